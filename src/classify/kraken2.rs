@@ -0,0 +1,330 @@
+use super::{Classifier, ClassifierOptions, ClassifyError, ClassifyJob, ClassifySummary};
+use crate::fastx::{compression_name, detect_compression};
+use crate::report::TaxonReport;
+use crate::{parse_kraken_stats, read_with_niffler, CommandRunner};
+use log::debug;
+use niffler::compression::Format;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Classifies reads by running the `kraken2` executable
+pub struct Kraken2Classifier {
+    pub(crate) runner: CommandRunner,
+    database: PathBuf,
+    options: ClassifierOptions,
+    memory_mapping: bool,
+}
+
+impl Kraken2Classifier {
+    pub fn new(database: &Path, options: ClassifierOptions) -> Self {
+        Self {
+            runner: CommandRunner::new("kraken2"),
+            database: database.to_path_buf(),
+            options,
+            memory_mapping: false,
+        }
+    }
+
+    /// Pass `--memory-mapping` so the database is read through the page cache rather than loaded
+    /// into memory on every run
+    pub fn memory_mapping(mut self, memory_mapping: bool) -> Self {
+        self.memory_mapping = memory_mapping;
+        self
+    }
+
+    /// The arguments to run kraken2 with, given the files it should read and write
+    fn args(&self, job: &ClassifyJob, inputs: &[PathBuf], outputs: &KrakenOutputs) -> Vec<String> {
+        let mut args = vec![
+            "--threads".to_string(),
+            job.threads.to_string(),
+            "--db".to_string(),
+            self.database.to_string_lossy().to_string(),
+            "--confidence".to_string(),
+            self.options.confidence.to_string(),
+            "--minimum-hit-groups".to_string(),
+            self.options.minimum_hit_groups.to_string(),
+            "--output".to_string(),
+            outputs.per_read.to_string_lossy().to_string(),
+        ];
+        if let Some(report) = &outputs.report {
+            args.extend(["--report".to_string(), report.to_string_lossy().to_string()]);
+            if job.report_minimizer_data {
+                args.push("--report-minimizer-data".to_string());
+            }
+        }
+        if self.memory_mapping {
+            args.push("--memory-mapping".to_string());
+        }
+        if job.paired() {
+            args.push("--paired".to_string());
+        }
+        if let Some(unclassified) = &outputs.unclassified {
+            args.extend(["--unclassified-out".to_string(), unclassified.clone()]);
+        }
+        if let Some(classified) = &outputs.classified {
+            args.extend(["--classified-out".to_string(), classified.clone()]);
+        }
+        args.extend(inputs.iter().map(|p| p.to_string_lossy().to_string()));
+        args
+    }
+}
+
+/// Where kraken2 writes its outputs
+struct KrakenOutputs {
+    unclassified: Option<String>,
+    classified: Option<String>,
+    per_read: PathBuf,
+    report: Option<PathBuf>,
+}
+
+impl Classifier for Kraken2Classifier {
+    fn name(&self) -> &str {
+        "kraken2"
+    }
+
+    fn version(&self) -> Option<String> {
+        let output = Command::new(&self.runner.command)
+            .args(["--version"])
+            .stdout(Stdio::piped())
+            .output();
+        let stdout = match output {
+            Ok(output) => String::from_utf8_lossy(&output.stdout).to_string(),
+            Err(_) => String::new(),
+        };
+
+        // Extract the version number
+        let version = stdout
+            .lines()
+            .find(|line| line.contains("version"))
+            .and_then(|line| line.split_whitespace().nth(2)) // Get the third word (the version number)
+            .unwrap_or("Unknown version");
+        Some(version.to_string())
+    }
+
+    fn writes_log(&self) -> bool {
+        true
+    }
+
+    fn classify(&self, job: &ClassifyJob) -> Result<ClassifySummary, ClassifyError> {
+        if job.inputs.is_empty() || job.inputs.len() > 2 {
+            return Err(ClassifyError::InputCount(job.inputs.len()));
+        }
+        let tmpdir = tempfile::Builder::new().prefix("nohuman").tempdir()?;
+
+        // kraken2 reads gzip and bzip2 itself, but lzma and zstd must be decompressed first. The
+        // format is detected from each file's content, as kraken2 does, rather than its name.
+        let (mut files_to_decompress, mut decompressed_paths) = (Vec::new(), Vec::new());
+        let mut inputs = Vec::with_capacity(job.inputs.len());
+        for (i, input_file) in job.inputs.iter().enumerate() {
+            // Pipes (e.g., reads fed from stdin) can only be read once, and are not compressed
+            let format = if input_file.is_file() {
+                detect_compression(input_file)?
+            } else {
+                Format::No
+            };
+            let input_label = format!("Input {}", i + 1);
+            debug!(
+                "{}: Detected format: {}",
+                input_label,
+                compression_name(format)
+            );
+            match format {
+                Format::Lzma | Format::Zstd => {
+                    debug!(
+                        "{}: Decompressing for kraken2 compatibility...",
+                        input_label
+                    );
+                    let decompressed_path = tmpdir.path().join(format!("input_{}.fq", i + 1));
+                    files_to_decompress.push(input_file.clone());
+                    decompressed_paths.push(decompressed_path.clone());
+                    inputs.push(decompressed_path);
+                }
+                Format::Gzip | Format::Bzip | Format::No => inputs.push(input_file.clone()),
+            }
+        }
+        let mut decompression_time = Duration::ZERO;
+        if !files_to_decompress.is_empty() {
+            let started = Instant::now();
+            read_with_niffler(files_to_decompress, decompressed_paths, job.threads)?;
+            decompression_time = started.elapsed();
+        }
+
+        let outputs = KrakenOutputs {
+            unclassified: output_template(tmpdir.path(), "unclassified", job.unclassified)?,
+            classified: output_template(tmpdir.path(), "classified", job.classified)?,
+            per_read: job
+                .per_read
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| tmpdir.path().join("kraken_output.txt")),
+            report: job.report.then(|| tmpdir.path().join("report.txt")),
+        };
+        let args = self.args(job, &inputs, &outputs);
+        debug!("Running kraken2 with arguments: {:?}", &args);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let (output, peak_memory_mb) = self.runner.run_with_peak_memory(&args)?;
+
+        let stats = parse_kraken_stats(&String::from_utf8_lossy(&output.stderr))?;
+        debug!(
+            "kraken2 classified {:.2} Mbp in {:.3}s",
+            stats.megabases, stats.seconds
+        );
+        let report = outputs
+            .report
+            .as_deref()
+            .map(TaxonReport::from_path)
+            .transpose()?;
+        Ok(ClassifySummary {
+            total_sequences: stats.total_sequences,
+            classified_sequences: stats.classified_sequences,
+            unclassified_sequences: stats.unclassified_sequences,
+            log: output.stderr,
+            report,
+            decompression_time,
+            peak_memory_mb: Some(peak_memory_mb),
+        })
+    }
+}
+
+/// The filename kraken2 should write a set of reads to.
+///
+/// For paired reads kraken2 needs a template in which `#` is replaced with `_1` and `_2`, so
+/// the template points at links in `tmpdir` to the actual outputs.
+fn output_template(
+    tmpdir: &Path,
+    prefix: &str,
+    outputs: &[PathBuf],
+) -> std::io::Result<Option<String>> {
+    match outputs {
+        [] => Ok(None),
+        [output] => Ok(Some(output.to_string_lossy().to_string())),
+        _ => {
+            for (i, output) in outputs.iter().enumerate() {
+                // Relative links would be resolved against `tmpdir`
+                let target = if output.is_absolute() {
+                    output.clone()
+                } else {
+                    std::env::current_dir()?.join(output)
+                };
+                std::os::unix::fs::symlink(
+                    target,
+                    tmpdir.join(format!("{}_{}.fq", prefix, i + 1)),
+                )?;
+            }
+            Ok(Some(
+                tmpdir
+                    .join(format!("{}#.fq", prefix))
+                    .to_string_lossy()
+                    .to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let tmp = tempfile::tempdir().unwrap();
+        let classifier = Kraken2Classifier::new(
+            Path::new("db"),
+            ClassifierOptions {
+                confidence: 0.1,
+                minimum_hit_groups: 3,
+            },
+        );
+        let inputs = [PathBuf::from("in_1.fq.gz"), PathBuf::from("in_2.fq.gz")];
+        let unclassified = [tmp.path().join("out_1.fq"), tmp.path().join("out_2.fq")];
+        let job = ClassifyJob {
+            inputs: &inputs,
+            unclassified: &unclassified,
+            classified: &[],
+            per_read: None,
+            report: false,
+            report_minimizer_data: false,
+            threads: 4,
+        };
+        let outputs = KrakenOutputs {
+            unclassified: output_template(tmp.path(), "unclassified", &unclassified).unwrap(),
+            classified: output_template(tmp.path(), "classified", &[]).unwrap(),
+            per_read: PathBuf::from("per_read.txt"),
+            report: None,
+        };
+        let template = tmp
+            .path()
+            .join("unclassified#.fq")
+            .to_string_lossy()
+            .to_string();
+        assert_eq!(
+            classifier.args(&job, &inputs, &outputs),
+            vec![
+                "--threads",
+                "4",
+                "--db",
+                "db",
+                "--confidence",
+                "0.1",
+                "--minimum-hit-groups",
+                "3",
+                "--output",
+                "per_read.txt",
+                "--paired",
+                "--unclassified-out",
+                &template,
+                "in_1.fq.gz",
+                "in_2.fq.gz",
+            ]
+        );
+        // kraken2 expands the template to the links, which point at the real outputs
+        assert_eq!(
+            std::fs::read_link(tmp.path().join("unclassified_2.fq")).unwrap(),
+            unclassified[1]
+        );
+
+        let job = ClassifyJob {
+            report: true,
+            report_minimizer_data: true,
+            ..job
+        };
+        let outputs = KrakenOutputs {
+            report: Some(PathBuf::from("report.txt")),
+            ..outputs
+        };
+        let args = classifier.args(&job, &inputs, &outputs);
+        assert_eq!(
+            args[10..13],
+            ["--report", "report.txt", "--report-minimizer-data"]
+        );
+    }
+
+    #[test]
+    fn test_classify_missing_kraken2() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = tmp.path().join("in.fq");
+        std::fs::write(&input, "@r1\nACGT\n+\nIIII\n").unwrap();
+        let mut classifier = Kraken2Classifier::new(
+            tmp.path(),
+            ClassifierOptions {
+                confidence: 0.0,
+                minimum_hit_groups: 2,
+            },
+        );
+        classifier.runner = CommandRunner::new("nohuman-test-nonexistent-kraken2");
+        let job = ClassifyJob {
+            inputs: &[input],
+            unclassified: &[],
+            classified: &[],
+            per_read: None,
+            report: false,
+            report_minimizer_data: false,
+            threads: 1,
+        };
+        let err = classifier.classify(&job).unwrap_err();
+        assert!(matches!(
+            err,
+            ClassifyError::CommandError(crate::CommandError::SpawnFailed { .. })
+        ));
+    }
+}
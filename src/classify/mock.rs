@@ -0,0 +1,92 @@
+use super::{
+    classify_records, Classifier, ClassifyError, ClassifyJob, ClassifySummary, ReadDecision,
+};
+use crate::report::{ReportRow, TaxonReport};
+use std::collections::HashSet;
+
+/// The taxonomy ID reported for reads the mock classifies as human (Homo sapiens)
+pub const HUMAN_TAXID: u64 = 9606;
+
+/// A deterministic classifier for tests, which calls a read human if its ID is in a given set.
+///
+/// For pairs, the ID of the first mate is used. Every k-mer of a read is reported as hitting the
+/// read's taxon, so the per-read output has the same shape as kraken2's.
+pub struct MockClassifier {
+    human_ids: HashSet<Vec<u8>>,
+}
+
+impl MockClassifier {
+    pub fn new<I, S>(human_ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        Self {
+            human_ids: human_ids
+                .into_iter()
+                .map(|id| id.as_ref().to_vec())
+                .collect(),
+        }
+    }
+}
+
+impl Classifier for MockClassifier {
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    fn classify(&self, job: &ClassifyJob) -> Result<ClassifySummary, ClassifyError> {
+        let (mut summary, _) = classify_records(job, |mates| {
+            let taxid = if self.human_ids.contains(mates[0].id()) {
+                HUMAN_TAXID
+            } else {
+                0
+            };
+            let hitlist = mates
+                .iter()
+                .map(|mate| format!("{}:{}", taxid, mate.seq.len()))
+                .collect::<Vec<_>>()
+                .join(" |:| ");
+            Ok(ReadDecision { taxid, hitlist })
+        })?;
+        if job.report {
+            summary.report = Some(mock_report(&summary));
+        }
+        Ok(summary)
+    }
+}
+
+/// A report of the unclassified reads and those assigned to Homo sapiens, directly under the root
+fn mock_report(summary: &ClassifySummary) -> TaxonReport {
+    let classified = summary.classified_sequences as u64;
+    let row = |clade_reads, direct_reads, rank: &str, taxid, name: &str, depth| ReportRow {
+        clade_reads,
+        direct_reads,
+        minimizers: None,
+        distinct_minimizers: None,
+        rank: rank.to_string(),
+        taxid,
+        name: name.to_string(),
+        depth,
+    };
+    let mut rows = vec![row(
+        summary.unclassified_sequences as u64,
+        summary.unclassified_sequences as u64,
+        "U",
+        0,
+        "unclassified",
+        0,
+    )];
+    if classified > 0 {
+        rows.push(row(classified, 0, "R", 1, "root", 0));
+        rows.push(row(
+            classified,
+            classified,
+            "S",
+            HUMAN_TAXID,
+            "Homo sapiens",
+            1,
+        ));
+    }
+    TaxonReport { rows }
+}
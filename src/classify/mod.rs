@@ -0,0 +1,437 @@
+pub mod kraken2;
+pub mod mock;
+pub mod native;
+pub mod per_read;
+
+use crate::db::DbError;
+use crate::fastx::{FastxError, Reader, Record};
+use crate::report::{ReportError, TaxonReport};
+use crate::{CommandError, KrakenStatsError};
+use niffler::error::Error as NifflerError;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Number of reads (or pairs) classified in parallel before being written out in order
+const BATCH_SIZE: usize = 10_000;
+
+#[derive(Error, Debug)]
+pub enum ClassifyError {
+    #[error("Only nucleotide kraken2 databases are supported")]
+    ProteinDatabase,
+
+    #[error(
+        "Databases with reverse complement version {0} (built by old versions of kraken2) \
+         are not supported by the native backend. Use --backend kraken2 or rebuild the database"
+    )]
+    UnsupportedRevcomVersion(i32),
+
+    #[error(
+        "The database maps a minimizer to taxon {taxon}, but its taxonomy has {node_count} nodes. \
+         The hash table may be corrupt"
+    )]
+    InvalidTaxon { taxon: u64, node_count: usize },
+
+    #[error("Paired input files have a different number of reads")]
+    UnequalPairs,
+
+    #[error("Expected 1 or 2 input files, got {0}")]
+    InputCount(usize),
+
+    #[error("Failed to parse the classification summary: {0}")]
+    InvalidSummary(#[from] KrakenStatsError),
+
+    #[error(transparent)]
+    CommandError(#[from] CommandError),
+
+    #[error(transparent)]
+    DbError(#[from] DbError),
+
+    #[error(transparent)]
+    ReportError(#[from] ReportError),
+
+    #[error(transparent)]
+    FastxError(#[from] FastxError),
+
+    #[error(transparent)]
+    NifflerError(#[from] NifflerError),
+
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// The thresholds used to call a read, matching kraken2's options of the same name
+#[derive(Debug, Clone, Copy)]
+pub struct ClassifierOptions {
+    pub confidence: f64,
+    pub minimum_hit_groups: u32,
+}
+
+/// The reads to classify and where to write them
+#[derive(Debug, Clone, Copy)]
+pub struct ClassifyJob<'a> {
+    /// One (single-end) or two (paired-end) FASTA/FASTQ files, possibly compressed
+    pub inputs: &'a [PathBuf],
+    /// Where to write the non-human reads, one path per input, or empty if they are not wanted
+    pub unclassified: &'a [PathBuf],
+    /// Where to write the human reads, one path per input, or empty if they are not wanted
+    pub classified: &'a [PathBuf],
+    /// Where to write the decision for each read, in kraken2's per-read output format
+    pub per_read: Option<&'a Path>,
+    /// Whether to produce a taxonomic report of the classification
+    pub report: bool,
+    /// Whether the report should include kraken2's minimizer counts for each taxon
+    pub report_minimizer_data: bool,
+    pub threads: usize,
+}
+
+impl ClassifyJob<'_> {
+    pub fn paired(&self) -> bool {
+        self.inputs.len() == 2
+    }
+}
+
+/// Counts of the reads (or pairs) seen by a classifier
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClassifySummary {
+    pub total_sequences: usize,
+    /// Reads classified as human
+    pub classified_sequences: usize,
+    /// Reads not classified as human
+    pub unclassified_sequences: usize,
+    /// Diagnostic output from the classifier (e.g., kraken2's stderr)
+    pub log: Vec<u8>,
+    /// The taxonomic report, if the job asked for one
+    pub report: Option<TaxonReport>,
+    /// Time spent decompressing inputs before classifying them
+    pub decompression_time: Duration,
+    /// Peak resident memory, in megabytes, of the classifier's own process, if it runs as one.
+    /// See [`crate::CommandRunner::run_with_peak_memory`].
+    pub peak_memory_mb: Option<f64>,
+}
+
+/// An engine that decides which reads are human.
+///
+/// nohuman gives the engine named pipes as outputs and compresses what is written to them as it
+/// is produced, so an engine only needs to write uncompressed FASTA/FASTQ to the job's paths.
+pub trait Classifier: Sync {
+    /// A short name for the engine, recorded in the run's stats
+    fn name(&self) -> &str;
+
+    /// The version of the engine's executable, if it runs one
+    fn version(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether the engine writes a log (its [`ClassifySummary::log`], or a failed command's
+    /// stderr) that should be kept in the kraken2 log file
+    fn writes_log(&self) -> bool {
+        false
+    }
+
+    /// Classify the job's reads, writing the human and non-human reads to its outputs.
+    ///
+    /// Human reads have their headers annotated with `kraken:taxid|<taxid>`, as kraken2 does.
+    fn classify(&self, job: &ClassifyJob) -> Result<ClassifySummary, ClassifyError>;
+}
+
+/// The decision made for a read or read pair
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadDecision {
+    /// The (external) taxonomy ID the read was assigned to, or 0 if unclassified
+    pub taxid: u64,
+    /// The taxon assigned to each k-mer, formatted as in kraken2's per-read output
+    pub hitlist: String,
+}
+
+impl ReadDecision {
+    pub fn is_human(&self) -> bool {
+        self.taxid != 0
+    }
+}
+
+/// Classify the reads of `job` one record (or pair of records) at a time with `decide`, routing
+/// them to the job's outputs and writing kraken2-style per-read output. Also returns the number of
+/// reads (or pairs) assigned to each taxonomy ID, for the report.
+///
+/// This does the reading and writing for classifiers that run in-process.
+pub(crate) fn classify_records<F>(
+    job: &ClassifyJob,
+    decide: F,
+) -> Result<(ClassifySummary, HashMap<u64, u64>), ClassifyError>
+where
+    F: Fn(&[Record]) -> Result<ReadDecision, ClassifyError> + Sync,
+{
+    if job.inputs.is_empty() || job.inputs.len() > 2 {
+        return Err(ClassifyError::InputCount(job.inputs.len()));
+    }
+    let mut readers = job
+        .inputs
+        .iter()
+        .map(|path| Reader::from_path(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let create = |paths: &[PathBuf]| -> io::Result<Vec<BufWriter<File>>> {
+        paths
+            .iter()
+            .map(|path| File::create(path).map(BufWriter::new))
+            .collect()
+    };
+    let mut unclassified_writers = create(job.unclassified)?;
+    let mut classified_writers = create(job.classified)?;
+    let mut per_read_writer = job
+        .per_read
+        .map(File::create)
+        .transpose()?
+        .map(BufWriter::new);
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(job.threads)
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let mut summary = ClassifySummary::default();
+    let mut taxon_counts = HashMap::new();
+    loop {
+        let batch = read_batch(&mut readers)?;
+        if batch.is_empty() {
+            break;
+        }
+        let decisions: Vec<ReadDecision> = pool.install(|| {
+            batch
+                .par_iter()
+                .map(|mates| decide(mates))
+                .collect::<Result<_, _>>()
+        })?;
+
+        for (mut mates, decision) in batch.into_iter().zip(decisions) {
+            summary.total_sequences += 1;
+            *taxon_counts.entry(decision.taxid).or_insert(0) += 1;
+            if let Some(writer) = per_read_writer.as_mut() {
+                writer.write_all(&format_kraken_line(&mates, &decision))?;
+            }
+            let writers = if decision.is_human() {
+                summary.classified_sequences += 1;
+                let tag = format!(" kraken:taxid|{}", decision.taxid);
+                for mate in mates.iter_mut() {
+                    mate.header.extend_from_slice(tag.as_bytes());
+                }
+                &mut classified_writers
+            } else {
+                summary.unclassified_sequences += 1;
+                &mut unclassified_writers
+            };
+            for (mate, writer) in mates.iter().zip(writers.iter_mut()) {
+                mate.write(writer)?;
+            }
+        }
+    }
+
+    for writer in unclassified_writers
+        .iter_mut()
+        .chain(classified_writers.iter_mut())
+    {
+        writer.flush()?;
+    }
+    if let Some(writer) = per_read_writer.as_mut() {
+        writer.flush()?;
+    }
+    Ok((summary, taxon_counts))
+}
+
+/// Read up to `BATCH_SIZE` reads (or pairs, one record from each reader)
+fn read_batch<R: BufRead>(readers: &mut [Reader<R>]) -> Result<Vec<Vec<Record>>, ClassifyError> {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while batch.len() < BATCH_SIZE {
+        let mates: Vec<Option<Record>> = readers
+            .iter_mut()
+            .map(|r| r.next().transpose())
+            .collect::<Result<_, _>>()?;
+        match (
+            mates.iter().all(Option::is_some),
+            mates.iter().all(Option::is_none),
+        ) {
+            (true, _) => batch.push(mates.into_iter().flatten().collect()),
+            (_, true) => break,
+            _ => return Err(ClassifyError::UnequalPairs),
+        }
+    }
+    Ok(batch)
+}
+
+/// Format a decision as a line of kraken2's per-read output
+fn format_kraken_line(mates: &[Record], decision: &ReadDecision) -> Vec<u8> {
+    let mut line = Vec::new();
+    line.extend_from_slice(if decision.is_human() { b"C\t" } else { b"U\t" });
+    line.extend_from_slice(&pair_id(mates));
+    let lengths: Vec<String> = mates
+        .iter()
+        .map(|mate| mate.seq.len().to_string())
+        .collect();
+    line.extend_from_slice(
+        format!(
+            "\t{}\t{}\t{}\n",
+            decision.taxid,
+            lengths.join("|"),
+            decision.hitlist
+        )
+        .as_bytes(),
+    );
+    line
+}
+
+/// The read ID kraken2 reports for a read or pair, dropping any `/1` mate suffix from pairs
+fn pair_id(mates: &[Record]) -> Vec<u8> {
+    let id = mates[0].id();
+    if mates.len() > 1 && id.ends_with(b"/1") {
+        id[..id.len() - 2].to_vec()
+    } else {
+        id.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockClassifier;
+    use super::*;
+
+    fn write_reads(dir: &Path, name: &str, reads: &[(&str, &str)]) -> PathBuf {
+        let path = dir.join(name);
+        let contents: String = reads
+            .iter()
+            .map(|(id, seq)| format!("@{}\n{}\n+\n{}\n", id, seq, "I".repeat(seq.len())))
+            .collect();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_classify_routes_reads() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = write_reads(
+            tmp.path(),
+            "in.fq",
+            &[("human", "ACGT"), ("microbe", "GGGGGG")],
+        );
+        let unclassified = tmp.path().join("unclassified.fq");
+        let classified = tmp.path().join("classified.fq");
+        let per_read = tmp.path().join("per_read.txt");
+        let job = ClassifyJob {
+            inputs: &[input],
+            unclassified: std::slice::from_ref(&unclassified),
+            classified: std::slice::from_ref(&classified),
+            per_read: Some(&per_read),
+            report: false,
+            report_minimizer_data: false,
+            threads: 2,
+        };
+
+        let summary = MockClassifier::new(["human"]).classify(&job).unwrap();
+        assert_eq!(
+            (
+                summary.total_sequences,
+                summary.classified_sequences,
+                summary.unclassified_sequences
+            ),
+            (2, 1, 1)
+        );
+        assert_eq!(
+            std::fs::read_to_string(&unclassified).unwrap(),
+            "@microbe\nGGGGGG\n+\nIIIIII\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&classified).unwrap(),
+            "@human kraken:taxid|9606\nACGT\n+\nIIII\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&per_read).unwrap(),
+            "C\thuman\t9606\t4\t9606:4\nU\tmicrobe\t0\t6\t0:6\n"
+        );
+    }
+
+    #[test]
+    fn test_classify_paired() {
+        let tmp = tempfile::tempdir().unwrap();
+        let in1 = write_reads(tmp.path(), "in_1.fq", &[("r1/1", "ACGT"), ("r2/1", "ACGT")]);
+        let in2 = write_reads(tmp.path(), "in_2.fq", &[("r1/2", "GG"), ("r2/2", "GG")]);
+        let unclassified = [tmp.path().join("u_1.fq"), tmp.path().join("u_2.fq")];
+        let per_read = tmp.path().join("per_read.txt");
+        let job = ClassifyJob {
+            inputs: &[in1, in2],
+            unclassified: &unclassified,
+            classified: &[],
+            per_read: Some(&per_read),
+            report: false,
+            report_minimizer_data: false,
+            threads: 1,
+        };
+        assert!(job.paired());
+
+        let summary = MockClassifier::new(["r2/1"]).classify(&job).unwrap();
+        assert_eq!(summary.classified_sequences, 1);
+        assert_eq!(
+            std::fs::read_to_string(&unclassified[0]).unwrap(),
+            "@r1/1\nACGT\n+\nIIII\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&unclassified[1]).unwrap(),
+            "@r1/2\nGG\n+\nII\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&per_read).unwrap(),
+            "U\tr1\t0\t4|2\t0:4 |:| 0:2\nC\tr2\t9606\t4|2\t9606:4 |:| 9606:2\n"
+        );
+        let decisions: Vec<_> = per_read::PerReadReader::from_path(&per_read)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(decisions[1].read_id, "r2");
+        assert_eq!(
+            decisions[1].hits[1],
+            vec![per_read::Hit {
+                taxid: Some(9606),
+                kmers: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn test_classify_unequal_pairs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let in1 = write_reads(tmp.path(), "in_1.fq", &[("r1", "ACGT"), ("r2", "ACGT")]);
+        let in2 = write_reads(tmp.path(), "in_2.fq", &[("r1", "ACGT")]);
+        let job = ClassifyJob {
+            inputs: &[in1, in2],
+            unclassified: &[],
+            classified: &[],
+            per_read: None,
+            report: false,
+            report_minimizer_data: false,
+            threads: 1,
+        };
+        let err = MockClassifier::new(Vec::<String>::new())
+            .classify(&job)
+            .unwrap_err();
+        assert!(matches!(err, ClassifyError::UnequalPairs));
+    }
+
+    #[test]
+    fn test_classify_too_many_inputs() {
+        let paths = [PathBuf::from("a"), PathBuf::from("b"), PathBuf::from("c")];
+        let job = ClassifyJob {
+            inputs: &paths,
+            unclassified: &[],
+            classified: &[],
+            per_read: None,
+            report: false,
+            report_minimizer_data: false,
+            threads: 1,
+        };
+        let err = MockClassifier::new(Vec::<String>::new())
+            .classify(&job)
+            .unwrap_err();
+        assert!(matches!(err, ClassifyError::InputCount(3)));
+    }
+}
@@ -0,0 +1,738 @@
+use super::{
+    classify_records, Classifier, ClassifierOptions, ClassifyError, ClassifyJob, ClassifySummary,
+    ReadDecision,
+};
+use crate::db::{
+    murmur_hash3, CompactHashTable, IndexOptions, Taxonomy, HASH_FILE, OPTIONS_FILE, TAXONOMY_FILE,
+};
+use crate::report::TaxonReport;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+
+/// The taxon assigned to each k-mer of a read, as reported in kraken2's per-read output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KmerCall {
+    /// The k-mer contains a base other than A, C, G or T
+    Ambiguous,
+    /// The k-mer's minimizer maps to this taxon (internal ID, 0 if not in the database)
+    Taxon(u64),
+}
+
+/// The result of classifying a read or read pair
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Classification {
+    /// The taxon the read is assigned to (internal ID), or 0 if unclassified
+    pub call: u64,
+    /// The k-mer calls for each mate
+    pub kmers: Vec<Vec<KmerCall>>,
+}
+
+/// Extracts kraken2 minimizers from a DNA sequence
+///
+/// Every k-mer is reduced to the minimum over its l-mers of the canonical l-mer, with the
+/// spaced seed mask applied and ordered after XORing with the toggle mask, as kraken2
+/// does when building and searching its database.
+pub struct MinimizerScanner {
+    k: usize,
+    l: usize,
+    lmer_mask: u64,
+    spaced_seed_mask: u64,
+    toggle_mask: u64,
+}
+
+impl MinimizerScanner {
+    pub fn new(options: &IndexOptions) -> Self {
+        let l = options.l as usize;
+        let lmer_mask = (1u64 << (2 * l)) - 1;
+        Self {
+            k: options.k as usize,
+            l,
+            lmer_mask,
+            spaced_seed_mask: options.spaced_seed_mask,
+            toggle_mask: options.toggle_mask & lmer_mask,
+        }
+    }
+
+    /// The minimizer of each k-mer in `seq`, or `None` if the k-mer is ambiguous
+    pub fn minimizers(&self, seq: &[u8]) -> Vec<Option<u64>> {
+        if seq.len() < self.k {
+            return Vec::new();
+        }
+        let mut minimizers = Vec::with_capacity(seq.len() - self.k + 1);
+        // Candidate l-mers in the current window, increasing in both position and value
+        let mut queue: VecDeque<(u64, usize)> = VecDeque::new();
+        let mut lmer = 0u64;
+        let mut valid_run = 0;
+        let mut last_ambiguous: Option<usize> = None;
+
+        for (pos, &base) in seq.iter().enumerate() {
+            match encode_base(base) {
+                Some(code) => {
+                    lmer = ((lmer << 2) | code) & self.lmer_mask;
+                    valid_run += 1;
+                }
+                None => {
+                    last_ambiguous = Some(pos);
+                    valid_run = 0;
+                    queue.clear();
+                }
+            }
+
+            if valid_run >= self.l {
+                let mut candidate = canonical(lmer, self.l);
+                if self.spaced_seed_mask != 0 {
+                    candidate &= self.spaced_seed_mask;
+                }
+                candidate ^= self.toggle_mask;
+                while queue.back().is_some_and(|&(value, _)| value > candidate) {
+                    queue.pop_back();
+                }
+                queue.push_back((candidate, pos + 1 - self.l));
+            }
+
+            if pos + 1 < self.k {
+                continue;
+            }
+            let kmer_start = pos + 1 - self.k;
+            if last_ambiguous.is_some_and(|ambiguous| ambiguous >= kmer_start) {
+                minimizers.push(None);
+                continue;
+            }
+            while queue.front().is_some_and(|&(_, start)| start < kmer_start) {
+                queue.pop_front();
+            }
+            let (minimum, _) = queue
+                .front()
+                .expect("window without ambiguous bases has l-mers");
+            minimizers.push(Some(minimum ^ self.toggle_mask));
+        }
+        minimizers
+    }
+}
+
+fn encode_base(base: u8) -> Option<u64> {
+    match base {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+/// Reverse complement of an `n`-mer packed 2 bits per base
+fn reverse_complement(kmer: u64, n: usize) -> u64 {
+    // Reverse the order of the bit pairs
+    let mut k = kmer;
+    k = ((k & 0xCCCCCCCCCCCCCCCC) >> 2) | ((k & 0x3333333333333333) << 2);
+    k = ((k & 0xF0F0F0F0F0F0F0F0) >> 4) | ((k & 0x0F0F0F0F0F0F0F0F) << 4);
+    k = ((k & 0xFF00FF00FF00FF00) >> 8) | ((k & 0x00FF00FF00FF00FF) << 8);
+    k = ((k & 0xFFFF0000FFFF0000) >> 16) | ((k & 0x0000FFFF0000FFFF) << 16);
+    k = k.rotate_left(32);
+    // Complement, then shift so the n-mer is aligned to the right
+    (!k) >> (64 - 2 * n)
+}
+
+fn canonical(lmer: u64, l: usize) -> u64 {
+    lmer.min(reverse_complement(lmer, l))
+}
+
+/// A pure-Rust implementation of kraken2's classification of reads against a kraken2 database
+pub struct NativeClassifier {
+    options: IndexOptions,
+    hash: CompactHashTable,
+    taxonomy: Taxonomy,
+    scanner: MinimizerScanner,
+    thresholds: ClassifierOptions,
+}
+
+impl NativeClassifier {
+    /// Load a kraken2 database. The hash table is read fully into memory.
+    pub fn load(db_dir: &Path, thresholds: ClassifierOptions) -> Result<Self, ClassifyError> {
+        let options = IndexOptions::from_path(&db_dir.join(OPTIONS_FILE))?;
+        if !options.dna_db {
+            return Err(ClassifyError::ProteinDatabase);
+        }
+        // Version 0 computed reverse complements differently, giving different minimizers
+        if options.revcom_version == 0 {
+            return Err(ClassifyError::UnsupportedRevcomVersion(
+                options.revcom_version,
+            ));
+        }
+        let taxonomy = Taxonomy::from_path(&db_dir.join(TAXONOMY_FILE))?;
+        let hash = CompactHashTable::from_path(&db_dir.join(HASH_FILE))?;
+        Ok(Self::new(options, hash, taxonomy, thresholds))
+    }
+
+    pub fn new(
+        options: IndexOptions,
+        hash: CompactHashTable,
+        taxonomy: Taxonomy,
+        thresholds: ClassifierOptions,
+    ) -> Self {
+        let scanner = MinimizerScanner::new(&options);
+        Self {
+            options,
+            hash,
+            taxonomy,
+            scanner,
+            thresholds,
+        }
+    }
+
+    pub fn taxonomy(&self) -> &Taxonomy {
+        &self.taxonomy
+    }
+
+    /// Classify a read, or read pair if more than one mate is given, as kraken2 would. Fails if
+    /// the hash table maps one of the read's minimizers to a taxon that isn't in the taxonomy.
+    pub fn classify_read(&self, mates: &[&[u8]]) -> Result<Classification, ClassifyError> {
+        let mut hit_counts: HashMap<u64, u32> = HashMap::new();
+        let mut hit_groups = 0;
+        let mut total_kmers = 0;
+        let mut last_minimizer = None;
+        let mut last_taxon = 0;
+        let mut kmers = Vec::with_capacity(mates.len());
+
+        for seq in mates {
+            let minimizers = self.scanner.minimizers(seq);
+            total_kmers += minimizers.len();
+            let mut calls = Vec::with_capacity(minimizers.len());
+            for minimizer in minimizers {
+                let Some(minimizer) = minimizer else {
+                    calls.push(KmerCall::Ambiguous);
+                    continue;
+                };
+                // Consecutive k-mers sharing a minimizer form a single hit group
+                if last_minimizer != Some(minimizer) {
+                    let skip_lookup = self.options.minimum_acceptable_hash_value != 0
+                        && murmur_hash3(minimizer) < self.options.minimum_acceptable_hash_value;
+                    last_taxon = if skip_lookup {
+                        0
+                    } else {
+                        self.hash.get(minimizer)
+                    };
+                    if last_taxon as usize >= self.taxonomy.node_count() {
+                        return Err(ClassifyError::InvalidTaxon {
+                            taxon: last_taxon,
+                            node_count: self.taxonomy.node_count(),
+                        });
+                    }
+                    last_minimizer = Some(minimizer);
+                    if last_taxon != 0 {
+                        hit_groups += 1;
+                    }
+                }
+                if last_taxon != 0 {
+                    *hit_counts.entry(last_taxon).or_insert(0) += 1;
+                }
+                calls.push(KmerCall::Taxon(last_taxon));
+            }
+            kmers.push(calls);
+        }
+
+        let mut call = self.resolve_tree(&hit_counts, total_kmers);
+        if call != 0 && hit_groups < self.thresholds.minimum_hit_groups {
+            call = 0;
+        }
+        Ok(Classification { call, kmers })
+    }
+
+    /// Find the taxon with the most k-mers hitting its root-to-leaf path, then walk up the tree
+    /// until its clade has enough support to pass the confidence threshold
+    fn resolve_tree(&self, hit_counts: &HashMap<u64, u32>, total_kmers: usize) -> u64 {
+        let required_score = (self.thresholds.confidence * total_kmers as f64).ceil() as u32;
+        let mut max_taxon = 0;
+        let mut max_score = 0;
+        // Iterate in a fixed order so ties are broken deterministically
+        let mut taxa: Vec<u64> = hit_counts.keys().copied().collect();
+        taxa.sort_unstable();
+
+        for &taxon in &taxa {
+            let score: u32 = taxa
+                .iter()
+                .filter(|&&other| self.taxonomy.is_ancestor_of(other, taxon))
+                .map(|other| hit_counts[other])
+                .sum();
+            if score > max_score {
+                max_score = score;
+                max_taxon = taxon;
+            } else if score == max_score {
+                max_taxon = self.taxonomy.lowest_common_ancestor(max_taxon, taxon);
+            }
+        }
+
+        max_score = hit_counts.get(&max_taxon).copied().unwrap_or(0);
+        while max_taxon != 0 && max_score < required_score {
+            max_score = taxa
+                .iter()
+                .filter(|&&taxon| self.taxonomy.is_ancestor_of(max_taxon, taxon))
+                .map(|taxon| hit_counts[taxon])
+                .sum();
+            if max_score >= required_score {
+                return max_taxon;
+            }
+            max_taxon = self.taxonomy.nodes[max_taxon as usize].parent_id;
+        }
+        max_taxon
+    }
+
+    /// Run-length encode k-mer calls as `taxid:count` pairs, with `A` for ambiguous k-mers
+    fn format_hitlist(&self, calls: &[KmerCall]) -> String {
+        let mut runs: Vec<(KmerCall, usize)> = Vec::new();
+        for &call in calls {
+            match runs.last_mut() {
+                Some((last, count)) if *last == call => *count += 1,
+                _ => runs.push((call, 1)),
+            }
+        }
+        runs.iter()
+            .map(|(call, count)| match call {
+                KmerCall::Ambiguous => format!("A:{}", count),
+                KmerCall::Taxon(taxon) => {
+                    format!("{}:{}", self.taxonomy.external_id(*taxon), count)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl Classifier for NativeClassifier {
+    fn name(&self) -> &str {
+        "native"
+    }
+
+    fn classify(&self, job: &ClassifyJob) -> Result<ClassifySummary, ClassifyError> {
+        let (mut summary, taxon_counts) = classify_records(job, |mates| {
+            let seqs: Vec<&[u8]> = mates.iter().map(|mate| mate.seq.as_slice()).collect();
+            let classification = self.classify_read(&seqs)?;
+            let hitlist = classification
+                .kmers
+                .iter()
+                .map(|calls| self.format_hitlist(calls))
+                .collect::<Vec<_>>()
+                .join(" |:| ");
+            Ok(ReadDecision {
+                taxid: self.taxonomy.external_id(classification.call),
+                hitlist,
+            })
+        })?;
+        // Minimizer counts aren't kept, so `report_minimizer_data` is not supported
+        if job.report {
+            summary.report = Some(TaxonReport::from_taxonomy(&self.taxonomy, &taxon_counts));
+        }
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classify::kraken2::Kraken2Classifier;
+    use crate::db::test_utils::*;
+
+    const K: u64 = 5;
+    const L: u64 = 3;
+    const THRESHOLDS: ClassifierOptions = ClassifierOptions {
+        confidence: 0.0,
+        minimum_hit_groups: 1,
+    };
+
+    fn options() -> IndexOptions {
+        IndexOptions {
+            k: K,
+            l: L,
+            spaced_seed_mask: 0,
+            toggle_mask: 0,
+            dna_db: true,
+            minimum_acceptable_hash_value: 0,
+            revcom_version: 1,
+            db_version: 0,
+            db_type: 0,
+        }
+    }
+
+    fn encode(seq: &[u8]) -> u64 {
+        seq.iter()
+            .fold(0, |acc, &b| (acc << 2) | encode_base(b).unwrap())
+    }
+
+    /// A database with a root -> Homo -> Homo sapiens taxonomy where `human` minimizers map to
+    /// Homo sapiens (internal ID 3)
+    fn classifier(human: &[&[u8]], thresholds: ClassifierOptions) -> NativeClassifier {
+        let tmp = tempfile::tempdir().unwrap();
+        let entries: Vec<(u64, u64)> = human.iter().map(|lmer| (encode(lmer), 3)).collect();
+        write_database(
+            tmp.path(),
+            &options_bytes(K, L),
+            &hash_bytes(&hash_cells(64, 22, &entries), entries.len() as u64, 22),
+            &human_taxonomy_bytes(),
+        );
+        NativeClassifier::load(tmp.path(), thresholds).unwrap()
+    }
+
+    #[test]
+    fn test_reverse_complement() {
+        // ACG -> CGT
+        assert_eq!(reverse_complement(encode(b"ACG"), 3), encode(b"CGT"));
+        assert_eq!(reverse_complement(encode(b"AAAA"), 4), encode(b"TTTT"));
+        assert_eq!(canonical(encode(b"TTT"), 3), encode(b"AAA"));
+    }
+
+    #[test]
+    fn test_minimizers() {
+        let scanner = MinimizerScanner::new(&options());
+        // k-mers: ACGTT (l-mers ACG, CGT, GTT -> canonical ACG, ACG, AAC) and CGTTA
+        let minimizers = scanner.minimizers(b"ACGTTA");
+        assert_eq!(minimizers, vec![Some(encode(b"AAC")), Some(encode(b"AAC"))]);
+    }
+
+    #[test]
+    fn test_minimizers_ambiguous_bases() {
+        let scanner = MinimizerScanner::new(&options());
+        let minimizers = scanner.minimizers(b"ACGNTTTTTA");
+        // Every k-mer overlapping the N at position 3 is ambiguous
+        assert_eq!(&minimizers[..4], &[None, None, None, None]);
+        assert_eq!(minimizers[4], Some(encode(b"AAA")));
+        assert_eq!(minimizers.len(), 6);
+    }
+
+    #[test]
+    fn test_minimizers_short_sequence() {
+        let scanner = MinimizerScanner::new(&options());
+        assert!(scanner.minimizers(b"ACGT").is_empty());
+    }
+
+    #[test]
+    fn test_minimizers_toggle_mask_changes_ordering() {
+        let mut opts = options();
+        opts.toggle_mask = encode(b"TTT");
+        let scanner = MinimizerScanner::new(&opts);
+        // With every bit toggled, the largest canonical l-mer becomes the minimizer
+        assert_eq!(scanner.minimizers(b"ACGTT"), vec![Some(encode(b"ACG"))]);
+    }
+
+    #[test]
+    fn test_minimizers_spaced_seed() {
+        let mut opts = options();
+        // Ignore the middle base of each l-mer
+        opts.spaced_seed_mask = encode(b"TAT");
+        let spaced = MinimizerScanner::new(&opts);
+        let contiguous = MinimizerScanner::new(&options());
+        // The minimizers of the two k-mers differ only in their middle base
+        assert_eq!(contiguous.minimizers(b"CCCAA"), vec![Some(encode(b"CAA"))]);
+        assert_eq!(contiguous.minimizers(b"CCCAC"), vec![Some(encode(b"CAC"))]);
+        assert_eq!(spaced.minimizers(b"CCCAA"), vec![Some(encode(b"CAA"))]);
+        assert_eq!(spaced.minimizers(b"CCCAC"), vec![Some(encode(b"CAA"))]);
+    }
+
+    #[test]
+    fn test_classify_spaced_seed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut options = options_bytes(K, L);
+        options[16..24].copy_from_slice(&encode(b"TAT").to_le_bytes());
+        let entries = [(encode(b"CAA"), 3)];
+        write_database(
+            tmp.path(),
+            &options,
+            &hash_bytes(&hash_cells(64, 22, &entries), 1, 22),
+            &human_taxonomy_bytes(),
+        );
+        let spaced = NativeClassifier::load(tmp.path(), THRESHOLDS).unwrap();
+        let classification = spaced.classify_read(&[b"CCCAC"]).unwrap();
+        assert_eq!(classification.call, 3);
+        assert_eq!(classification.kmers, vec![vec![KmerCall::Taxon(3)]]);
+        // Without the spaced seed, the k-mer's minimizer isn't in the database
+        assert_eq!(
+            classifier(&[b"CAA"], THRESHOLDS)
+                .classify_read(&[b"CCCAC"])
+                .unwrap()
+                .call,
+            0
+        );
+    }
+
+    #[test]
+    fn test_classify_human_read() {
+        let classifier = classifier(&[b"AAC"], THRESHOLDS);
+        let classification = classifier.classify_read(&[b"ACGTTA"]).unwrap();
+        assert_eq!(classification.call, 3);
+        assert_eq!(
+            classification.kmers,
+            vec![vec![KmerCall::Taxon(3), KmerCall::Taxon(3)]]
+        );
+    }
+
+    #[test]
+    fn test_classify_non_human_read() {
+        let classifier = classifier(&[b"AAC"], THRESHOLDS);
+        let classification = classifier.classify_read(&[b"GGGGGG"]).unwrap();
+        assert_eq!(classification.call, 0);
+        assert_eq!(
+            classification.kmers,
+            vec![vec![KmerCall::Taxon(0), KmerCall::Taxon(0)]]
+        );
+    }
+
+    #[test]
+    fn test_classify_minimum_hit_groups() {
+        let thresholds = ClassifierOptions {
+            confidence: 0.0,
+            minimum_hit_groups: 2,
+        };
+        let classifier = classifier(&[b"AAC"], thresholds);
+        // Both k-mers share one minimizer, so there is only a single hit group
+        assert_eq!(classifier.classify_read(&[b"ACGTTA"]).unwrap().call, 0);
+    }
+
+    #[test]
+    fn test_classify_confidence() {
+        let thresholds = ClassifierOptions {
+            confidence: 0.5,
+            minimum_hit_groups: 1,
+        };
+        let classifier = classifier(&[b"AAC"], thresholds);
+        // 2 of 2 k-mers hit human
+        assert_eq!(classifier.classify_read(&[b"ACGTTA"]).unwrap().call, 3);
+        // 2 of the 5 k-mers across the pair hit human
+        assert_eq!(
+            classifier
+                .classify_read(&[b"ACGTTA", b"GGGGGGG"])
+                .unwrap()
+                .call,
+            0
+        );
+    }
+
+    #[test]
+    fn test_load_rejects_revcom_version_0() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut options = options_bytes(K, L);
+        options[48..52].copy_from_slice(&0i32.to_le_bytes());
+        write_database(
+            tmp.path(),
+            &options,
+            &hash_bytes(&hash_cells(64, 22, &[]), 0, 22),
+            &human_taxonomy_bytes(),
+        );
+        let err = NativeClassifier::load(tmp.path(), THRESHOLDS)
+            .err()
+            .unwrap();
+        assert!(matches!(err, ClassifyError::UnsupportedRevcomVersion(0)));
+    }
+
+    #[test]
+    fn test_classify_taxon_outside_taxonomy() {
+        let tmp = tempfile::tempdir().unwrap();
+        // The taxonomy has nodes 0 to 3
+        let entries = [(encode(b"AAC"), 4)];
+        write_database(
+            tmp.path(),
+            &options_bytes(K, L),
+            &hash_bytes(&hash_cells(64, 22, &entries), 1, 22),
+            &human_taxonomy_bytes(),
+        );
+        let classifier = NativeClassifier::load(tmp.path(), THRESHOLDS).unwrap();
+        let err = classifier.classify_read(&[b"ACGTTA"]).unwrap_err();
+        assert!(matches!(
+            err,
+            ClassifyError::InvalidTaxon {
+                taxon: 4,
+                node_count: 4
+            }
+        ));
+    }
+
+    #[test]
+    fn test_classify_files() {
+        let classifier = classifier(&[b"AAC"], THRESHOLDS);
+        let tmp = tempfile::tempdir().unwrap();
+        let in1 = tmp.path().join("in_1.fq");
+        let in2 = tmp.path().join("in_2.fq");
+        std::fs::write(
+            &in1,
+            "@human/1\nACGTTA\n+\nIIIIII\n@microbe/1\nGGGGGG\n+\nIIIIII\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &in2,
+            "@human/2\nGGGNGGG\n+\nIIIIIII\n@microbe/2\nGGGGGG\n+\nIIIIII\n",
+        )
+        .unwrap();
+        let unclassified = [tmp.path().join("u_1.fq"), tmp.path().join("u_2.fq")];
+        let classified = [tmp.path().join("c_1.fq"), tmp.path().join("c_2.fq")];
+        let per_read = tmp.path().join("per_read.txt");
+        let job = ClassifyJob {
+            inputs: &[in1, in2],
+            unclassified: &unclassified,
+            classified: &classified,
+            per_read: Some(&per_read),
+            report: false,
+            report_minimizer_data: false,
+            threads: 2,
+        };
+
+        let summary = classifier.classify(&job).unwrap();
+        assert_eq!(
+            (
+                summary.total_sequences,
+                summary.classified_sequences,
+                summary.unclassified_sequences
+            ),
+            (2, 1, 1)
+        );
+        assert_eq!(
+            std::fs::read_to_string(&classified[1]).unwrap(),
+            "@human/2 kraken:taxid|9606\nGGGNGGG\n+\nIIIIIII\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&unclassified[0]).unwrap(),
+            "@microbe/1\nGGGGGG\n+\nIIIIII\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&per_read).unwrap(),
+            "C\thuman\t9606\t6|7\t9606:2 |:| A:3\nU\tmicrobe\t0\t6|6\t0:2 |:| 0:2\n"
+        );
+    }
+
+    /// Run `program`, returning its stderr
+    fn run(program: &str, args: &[&std::ffi::OsStr]) -> String {
+        let output = std::process::Command::new(program)
+            .args(args)
+            .output()
+            .unwrap_or_else(|e| panic!("Failed to run {}: {}", program, e));
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        assert!(
+            output.status.success(),
+            "{} failed: {}\n{}",
+            program,
+            output.status,
+            stderr
+        );
+        stderr
+    }
+
+    /// The numbers of reads (or pairs), classified and unclassified reads in per-read output
+    fn per_read_counts(per_read: &str) -> (usize, usize, usize) {
+        let count = |status: &str| {
+            per_read
+                .lines()
+                .filter(|line| line.starts_with(status))
+                .count()
+        };
+        (per_read.lines().count(), count("C\t"), count("U\t"))
+    }
+
+    /// Compare the per-read output with kraken2's on a small database built by `kraken2-build`
+    /// with its default k-mer length, minimizer length and spaced seed, from the random sequences
+    /// in `tests/data/kraken2`. Needs `kraken2` and `kraken2-build` on the PATH, so it only runs
+    /// when asked for: `cargo test -- --ignored test_matches_kraken2`.
+    ///
+    /// The summary kraken2 writes to stderr is also checked against its per-read output. With
+    /// `NOHUMAN_KRAKEN2_STDERR_DIR` set, the stderr of the single-end run with the default
+    /// thresholds is saved there as `kraken2-<version>.stderr`, for
+    /// `test_parse_kraken_stats_captures`.
+    #[test]
+    #[ignore]
+    fn test_matches_kraken2() {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/kraken2");
+        let tmp = tempfile::tempdir().unwrap();
+        let db = tmp.path().join("db");
+        std::fs::create_dir_all(db.join("taxonomy")).unwrap();
+        for file in ["nodes.dmp", "names.dmp"] {
+            std::fs::copy(data.join(file), db.join("taxonomy").join(file)).unwrap();
+        }
+        let library = data.join("library.fa");
+        run(
+            "kraken2-build",
+            &[
+                "--add-to-library".as_ref(),
+                library.as_os_str(),
+                "--no-masking".as_ref(),
+                "--db".as_ref(),
+                db.as_os_str(),
+            ],
+        );
+        run(
+            "kraken2-build",
+            &["--build".as_ref(), "--db".as_ref(), db.as_os_str()],
+        );
+
+        let reads = [data.join("reads_1.fq"), data.join("reads_2.fq")];
+        let expected = tmp.path().join("kraken2.out");
+        let actual = tmp.path().join("native.out");
+        for inputs in [&reads[..1], &reads[..]] {
+            for (confidence, minimum_hit_groups) in [(0.0, 2), (0.0, 1), (0.2, 2)] {
+                let confidence_arg = confidence.to_string();
+                let hit_groups_arg = minimum_hit_groups.to_string();
+                let mut args: Vec<&std::ffi::OsStr> = vec![
+                    "--db".as_ref(),
+                    db.as_os_str(),
+                    "--output".as_ref(),
+                    expected.as_os_str(),
+                    "--confidence".as_ref(),
+                    confidence_arg.as_ref(),
+                    "--minimum-hit-groups".as_ref(),
+                    hit_groups_arg.as_ref(),
+                ];
+                if inputs.len() == 2 {
+                    args.push("--paired".as_ref());
+                }
+                args.extend(inputs.iter().map(|input| input.as_os_str()));
+                let stderr = run("kraken2", &args);
+
+                let thresholds = ClassifierOptions {
+                    confidence,
+                    minimum_hit_groups,
+                };
+                let job = ClassifyJob {
+                    inputs,
+                    unclassified: &[],
+                    classified: &[],
+                    per_read: Some(&actual),
+                    report: false,
+                    report_minimizer_data: false,
+                    threads: 1,
+                };
+                NativeClassifier::load(&db, thresholds)
+                    .unwrap()
+                    .classify(&job)
+                    .unwrap();
+
+                let expected = std::fs::read_to_string(&expected).unwrap();
+                let actual = std::fs::read_to_string(&actual).unwrap();
+                assert_eq!(expected.lines().count(), 16);
+                let stats = crate::parse_kraken_stats(&stderr).unwrap();
+                assert_eq!(
+                    (
+                        stats.total_sequences,
+                        stats.classified_sequences,
+                        stats.unclassified_sequences
+                    ),
+                    per_read_counts(&expected),
+                    "kraken2 stderr:\n{}",
+                    stderr
+                );
+                let capture_dir = std::env::var_os("NOHUMAN_KRAKEN2_STDERR_DIR");
+                let default_run = inputs.len() == 1 && confidence == 0.0 && minimum_hit_groups == 2;
+                if let Some(dir) = capture_dir.filter(|_| default_run) {
+                    let version = Kraken2Classifier::new(&db, thresholds).version().unwrap();
+                    let path = Path::new(&dir).join(format!("kraken2-{}.stderr", version));
+                    std::fs::write(path, &stderr).unwrap();
+                }
+                for (expected, actual) in expected.lines().zip(actual.lines()) {
+                    assert_eq!(
+                        actual,
+                        expected,
+                        "{} input(s), --confidence {}, --minimum-hit-groups {}",
+                        inputs.len(),
+                        confidence,
+                        minimum_hit_groups
+                    );
+                }
+                assert_eq!(actual.lines().count(), expected.lines().count());
+            }
+        }
+    }
+}
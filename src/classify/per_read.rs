@@ -0,0 +1,267 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use thiserror::Error;
+
+/// The separator kraken2 puts between the k-mer hits of the two mates of a pair
+const MATE_SEPARATOR: &str = "|:|";
+
+#[derive(Error, Debug)]
+pub enum PerReadError {
+    #[error("Invalid line in the per-read classifier output (line {line}): {reason}")]
+    InvalidLine { line: usize, reason: String },
+
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// One line of kraken2's per-read (`--output`) output: the decision for a read or read pair
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrakenRecord {
+    /// Whether the read was classified (as human, with a nohuman database)
+    pub classified: bool,
+    /// The read ID, without the `/1` mate suffix for pairs
+    pub read_id: String,
+    /// The (external) taxonomy ID the read was assigned to, or 0 if unclassified
+    pub taxid: u64,
+    /// The length of each mate
+    pub lengths: Vec<usize>,
+    /// The k-mer hits of each mate, in order along the read
+    pub hits: Vec<Vec<Hit>>,
+}
+
+impl KrakenRecord {
+    /// Whether the record is for a read pair
+    pub fn paired(&self) -> bool {
+        self.lengths.len() == 2
+    }
+
+    /// The total length of the read (or both mates of a pair)
+    pub fn length(&self) -> usize {
+        self.lengths.iter().sum()
+    }
+}
+
+/// A run of consecutive k-mers (minimizers) of a read that map to the same taxon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hit {
+    /// The taxon the k-mers map to: 0 if they aren't in the database, or `None` if they contain
+    /// an ambiguous base
+    pub taxid: Option<u64>,
+    pub kmers: usize,
+}
+
+/// A streaming reader of kraken2's per-read output, yielding a [`KrakenRecord`] per line.
+///
+/// Taxa given as names (kraken2's `--use-names`, e.g., `Homo sapiens (taxid 9606)`) are
+/// accepted as well as bare taxonomy IDs.
+pub struct PerReadReader<R: BufRead> {
+    inner: R,
+    line: String,
+    line_number: usize,
+}
+
+impl PerReadReader<BufReader<File>> {
+    pub fn from_path(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: BufRead> PerReadReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            line: String::new(),
+            line_number: 0,
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<KrakenRecord>, PerReadError> {
+        loop {
+            self.line.clear();
+            if self.inner.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            self.line_number += 1;
+            let line = self.line.trim_end_matches(['\n', '\r']);
+            if !line.is_empty() {
+                return parse_line(line)
+                    .map(Some)
+                    .map_err(|reason| PerReadError::InvalidLine {
+                        line: self.line_number,
+                        reason,
+                    });
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for PerReadReader<R> {
+    type Item = Result<KrakenRecord, PerReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// Parse a line of the form `C/U <read ID> <taxid> <length(s)> <hits>`, separated by tabs
+fn parse_line(line: &str) -> Result<KrakenRecord, String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [flag, read_id, taxon, lengths, rest @ ..] = fields.as_slice() else {
+        return Err(format!(
+            "expected at least 4 tab-separated fields, got {}",
+            fields.len()
+        ));
+    };
+    let classified = match *flag {
+        "C" => true,
+        "U" => false,
+        _ => return Err(format!("expected C or U, got '{}'", flag)),
+    };
+    let taxid = parse_taxon(taxon)?;
+    let lengths = lengths
+        .split('|')
+        .map(|length| {
+            length
+                .parse()
+                .map_err(|_| format!("invalid read length '{}'", length))
+        })
+        .collect::<Result<Vec<usize>, _>>()?;
+    if lengths.len() > 2 {
+        return Err(format!(
+            "expected 1 or 2 read lengths, got {}",
+            lengths.len()
+        ));
+    }
+    let hits = match rest.first().map(|hits| hits.trim()) {
+        None | Some("") => vec![Vec::new(); lengths.len()],
+        Some(hits) => hits
+            .split(MATE_SEPARATOR)
+            .map(parse_hits)
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    if hits.len() != lengths.len() {
+        return Err(format!(
+            "{} read lengths but hits for {} mates",
+            lengths.len(),
+            hits.len()
+        ));
+    }
+    Ok(KrakenRecord {
+        classified,
+        read_id: read_id.to_string(),
+        taxid,
+        lengths,
+        hits,
+    })
+}
+
+/// Parse a taxonomy ID, or a taxon name with the ID in brackets
+fn parse_taxon(taxon: &str) -> Result<u64, String> {
+    let id = match taxon.rfind("(taxid ") {
+        Some(start) => taxon[start + 7..].trim_end_matches(')'),
+        None => taxon,
+    };
+    id.trim()
+        .parse()
+        .map_err(|_| format!("invalid taxonomy ID '{}'", taxon))
+}
+
+/// Parse the space-separated `<taxid>:<k-mers>` hits of a mate, where the taxid may be `A`
+fn parse_hits(hits: &str) -> Result<Vec<Hit>, String> {
+    hits.split_whitespace()
+        .map(|hit| {
+            let invalid = || format!("invalid k-mer hit '{}'", hit);
+            let (taxid, kmers) = hit.rsplit_once(':').ok_or_else(invalid)?;
+            let taxid = match taxid {
+                "A" => None,
+                _ => Some(taxid.parse().map_err(|_| invalid())?),
+            };
+            Ok(Hit {
+                taxid,
+                kmers: kmers.parse().map_err(|_| invalid())?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Vec<KrakenRecord>, PerReadError> {
+        PerReadReader::new(text.as_bytes()).collect()
+    }
+
+    #[test]
+    fn test_parse_single_and_paired() {
+        let records =
+            parse("U\tr1\t0\t150\t0:116\nC\tr2\t9606\t150|148\t9606:3 0:100 A:13 |:| 0:114\n")
+                .unwrap();
+        assert_eq!(
+            records[0],
+            KrakenRecord {
+                classified: false,
+                read_id: "r1".to_string(),
+                taxid: 0,
+                lengths: vec![150],
+                hits: vec![vec![Hit {
+                    taxid: Some(0),
+                    kmers: 116
+                }]],
+            }
+        );
+        let pair = &records[1];
+        assert!(pair.classified && pair.paired());
+        assert_eq!((pair.taxid, pair.length()), (9606, 298));
+        assert_eq!(
+            pair.hits[0][2],
+            Hit {
+                taxid: None,
+                kmers: 13
+            }
+        );
+        assert_eq!(
+            pair.hits[1],
+            vec![Hit {
+                taxid: Some(0),
+                kmers: 114
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_names_and_empty_hits() {
+        let records =
+            parse("C\tr1\tHomo sapiens (taxid 9606)\t4\t\n\nU\tr2\tunclassified (taxid 0)\t2|2\n")
+                .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].taxid, 9606);
+        assert_eq!(records[0].hits, vec![Vec::new()]);
+        assert_eq!(records[1].hits, vec![Vec::new(), Vec::new()]);
+    }
+
+    #[test]
+    fn test_parse_invalid_lines() {
+        for (text, reason) in [
+            ("X\tr1\t0\t4\t\n", "expected C or U, got 'X'"),
+            ("U\tr1\n", "expected at least 4 tab-separated fields, got 2"),
+            ("U\tr1\thuman\t4\t\n", "invalid taxonomy ID 'human'"),
+            ("U\tr1\t0\t4|x\t\n", "invalid read length 'x'"),
+            (
+                "U\tr1\t0\t4\t0:1 |:| 0:1\n",
+                "1 read lengths but hits for 2 mates",
+            ),
+            ("U\tr1\t0\t4\t0-1\n", "invalid k-mer hit '0-1'"),
+        ] {
+            let err = parse(&format!("U\tr0\t0\t4\t\n{}", text)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "Invalid line in the per-read classifier output (line 2): {}",
+                    reason
+                )
+            );
+        }
+    }
+}
@@ -0,0 +1,812 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+// The files in a kraken2 database directory. Each is a raw little-endian dump of one of
+// kraken2's in-memory structures.
+/// The parameters used to build the database (k, l, masks, ...)
+pub const OPTIONS_FILE: &str = "opts.k2d";
+/// The compact hash table mapping minimizers to taxa
+pub const HASH_FILE: &str = "hash.k2d";
+/// The taxonomy the hash table values refer to
+pub const TAXONOMY_FILE: &str = "taxo.k2d";
+
+/// The k-mer length nohuman's database is built with (kraken2's default)
+pub const EXPECTED_K: u64 = 35;
+/// The minimizer length nohuman's database is built with (kraken2's default)
+pub const EXPECTED_L: u64 = 31;
+
+/// Smallest `opts.k2d` we accept: k, l, the two masks and the DNA flag (padded to 8 bytes)
+const MIN_OPTIONS_SIZE: usize = 40;
+/// `sizeof(IndexOptions)` in current kraken2 versions
+const MAX_OPTIONS_SIZE: usize = 64;
+/// capacity, size, key_bits and value_bits, each a `size_t`
+const HASH_HEADER_SIZE: u64 = 32;
+/// Each hash table cell is a `uint32_t`
+const HASH_CELL_SIZE: u64 = 4;
+const TAXONOMY_MAGIC: &[u8] = b"K2TAXDAT";
+/// The magic bytes followed by node_count, name_data_len and rank_data_len
+const TAXONOMY_HEADER_SIZE: u64 = 8 + 3 * 8;
+/// Seven `uint64_t` fields per node
+const TAXONOMY_NODE_SIZE: u64 = 7 * 8;
+
+#[derive(Error, Debug)]
+pub enum DbError {
+    #[error("{} is truncated: expected {expected} bytes but found {actual}", .file.display())]
+    Truncated {
+        file: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error("{} is not a kraken2 taxonomy file", .0.display())]
+    BadMagic(PathBuf),
+
+    #[error("{} is invalid: {reason}", .file.display())]
+    Invalid { file: PathBuf, reason: String },
+
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// The parameters a kraken2 database was built with (`opts.k2d`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexOptions {
+    /// k-mer length
+    pub k: u64,
+    /// minimizer length
+    pub l: u64,
+    pub spaced_seed_mask: u64,
+    pub toggle_mask: u64,
+    /// Whether the database is nucleotide (true) or protein (false)
+    pub dna_db: bool,
+    pub minimum_acceptable_hash_value: u64,
+    pub revcom_version: i32,
+    pub db_version: i32,
+    pub db_type: i32,
+}
+
+impl IndexOptions {
+    /// Read the index options from an `opts.k2d` file.
+    ///
+    /// Older kraken2 versions wrote fewer fields; any that are missing are left as zero, as
+    /// kraken2 itself does.
+    pub fn from_path(path: &Path) -> Result<Self, DbError> {
+        let mut buf = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        Self::from_bytes(&buf).map_err(|reason| match reason {
+            OptionsError::Truncated => DbError::Truncated {
+                file: path.to_path_buf(),
+                expected: MIN_OPTIONS_SIZE as u64,
+                actual: buf.len() as u64,
+            },
+            OptionsError::Invalid(reason) => DbError::Invalid {
+                file: path.to_path_buf(),
+                reason,
+            },
+        })
+    }
+
+    fn from_bytes(buf: &[u8]) -> Result<Self, OptionsError> {
+        if buf.len() < MIN_OPTIONS_SIZE {
+            return Err(OptionsError::Truncated);
+        }
+        if buf.len() > MAX_OPTIONS_SIZE {
+            return Err(OptionsError::Invalid(format!(
+                "expected at most {} bytes but found {}",
+                MAX_OPTIONS_SIZE,
+                buf.len()
+            )));
+        }
+        let mut padded = [0u8; MAX_OPTIONS_SIZE];
+        padded[..buf.len()].copy_from_slice(buf);
+
+        let opts = Self {
+            k: read_u64(&padded, 0),
+            l: read_u64(&padded, 8),
+            spaced_seed_mask: read_u64(&padded, 16),
+            toggle_mask: read_u64(&padded, 24),
+            dna_db: padded[32] != 0,
+            minimum_acceptable_hash_value: read_u64(&padded, 40),
+            revcom_version: read_i32(&padded, 48),
+            db_version: read_i32(&padded, 52),
+            db_type: read_i32(&padded, 56),
+        };
+        opts.validate().map_err(OptionsError::Invalid)?;
+        Ok(opts)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        // l-mers are packed into a u64 with 2 bits per nucleotide or 5 per amino acid
+        let max_l = if self.dna_db { 31 } else { 12 };
+        if self.l == 0 || self.l > max_l {
+            return Err(format!(
+                "minimizer length l={} is outside 1..={}",
+                self.l, max_l
+            ));
+        }
+        if self.k < self.l {
+            return Err(format!(
+                "k-mer length k={} is smaller than l={}",
+                self.k, self.l
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether k and l match the parameters nohuman's database is built with
+    pub fn has_expected_parameters(&self) -> bool {
+        self.k == EXPECTED_K && self.l == EXPECTED_L
+    }
+}
+
+enum OptionsError {
+    Truncated,
+    Invalid(String),
+}
+
+/// The header of a kraken2 compact hash table (`hash.k2d`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashTableHeader {
+    /// Number of cells in the table
+    pub capacity: u64,
+    /// Number of occupied cells
+    pub size: u64,
+    /// Bits of each cell used to store (part of) the minimizer's hash
+    pub key_bits: u64,
+    /// Bits of each cell used to store the taxon ID
+    pub value_bits: u64,
+}
+
+impl HashTableHeader {
+    /// Read and validate the header of a `hash.k2d` file, without loading the table itself.
+    ///
+    /// The file size is checked against the capacity in the header, so a truncated download or
+    /// partially copied database is caught here.
+    pub fn from_path(path: &Path) -> Result<Self, DbError> {
+        let mut file = File::open(path)?;
+        let actual = file.metadata()?.len();
+        let mut buf = [0u8; HASH_HEADER_SIZE as usize];
+        if actual < HASH_HEADER_SIZE {
+            return Err(DbError::Truncated {
+                file: path.to_path_buf(),
+                expected: HASH_HEADER_SIZE,
+                actual,
+            });
+        }
+        file.read_exact(&mut buf)?;
+
+        let header = Self {
+            capacity: read_u64(&buf, 0),
+            size: read_u64(&buf, 8),
+            key_bits: read_u64(&buf, 16),
+            value_bits: read_u64(&buf, 24),
+        };
+        let invalid = |reason: String| DbError::Invalid {
+            file: path.to_path_buf(),
+            reason,
+        };
+        if header.key_bits.checked_add(header.value_bits) != Some(32)
+            || header.value_bits == 0
+            || header.key_bits == 0
+        {
+            return Err(invalid(format!(
+                "key bits ({}) and value bits ({}) must be non-zero and sum to 32",
+                header.key_bits, header.value_bits
+            )));
+        }
+        if header.size > header.capacity {
+            return Err(invalid(format!(
+                "size ({}) is larger than capacity ({})",
+                header.size, header.capacity
+            )));
+        }
+        let expected = header
+            .capacity
+            .checked_mul(HASH_CELL_SIZE)
+            .and_then(|n| n.checked_add(HASH_HEADER_SIZE))
+            .ok_or_else(|| invalid(format!("capacity ({}) is too large", header.capacity)))?;
+        if actual < expected {
+            return Err(DbError::Truncated {
+                file: path.to_path_buf(),
+                expected,
+                actual,
+            });
+        }
+        if actual > expected {
+            return Err(invalid(format!(
+                "expected {} bytes but found {}",
+                expected, actual
+            )));
+        }
+        Ok(header)
+    }
+
+    /// The largest taxon ID that can be stored in the table
+    pub fn max_value(&self) -> u64 {
+        (1u64 << self.value_bits) - 1
+    }
+}
+
+/// A node in a kraken2 taxonomy. Node IDs are kraken2's internal IDs, which are the hash table
+/// values; `external_id` is the NCBI taxonomy ID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaxonomyNode {
+    pub parent_id: u64,
+    pub first_child: u64,
+    pub child_count: u64,
+    pub name_offset: u64,
+    pub rank_offset: u64,
+    pub external_id: u64,
+    pub godparent_id: u64,
+}
+
+/// A kraken2 taxonomy (`taxo.k2d`)
+///
+/// Internal IDs are assigned breadth-first, so every node's parent has a smaller ID than the
+/// node itself. Node 0 is unused and node 1 is the root.
+#[derive(Debug, Clone)]
+pub struct Taxonomy {
+    pub nodes: Vec<TaxonomyNode>,
+    name_data: Vec<u8>,
+    rank_data: Vec<u8>,
+}
+
+impl Taxonomy {
+    pub fn from_path(path: &Path) -> Result<Self, DbError> {
+        let mut buf = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        let actual = buf.len() as u64;
+        let truncated = |expected: u64| DbError::Truncated {
+            file: path.to_path_buf(),
+            expected,
+            actual,
+        };
+        let invalid = |reason: String| DbError::Invalid {
+            file: path.to_path_buf(),
+            reason,
+        };
+
+        if !buf.starts_with(TAXONOMY_MAGIC) {
+            if TAXONOMY_MAGIC.starts_with(&buf) {
+                return Err(truncated(TAXONOMY_HEADER_SIZE));
+            }
+            return Err(DbError::BadMagic(path.to_path_buf()));
+        }
+        if actual < TAXONOMY_HEADER_SIZE {
+            return Err(truncated(TAXONOMY_HEADER_SIZE));
+        }
+        let node_count = read_u64(&buf, 8);
+        let name_data_len = read_u64(&buf, 16);
+        let rank_data_len = read_u64(&buf, 24);
+        let expected = node_count
+            .checked_mul(TAXONOMY_NODE_SIZE)
+            .and_then(|n| n.checked_add(TAXONOMY_HEADER_SIZE))
+            .and_then(|n| n.checked_add(name_data_len))
+            .and_then(|n| n.checked_add(rank_data_len))
+            .ok_or_else(|| invalid("header sizes overflow".to_string()))?;
+        if actual < expected {
+            return Err(truncated(expected));
+        }
+        if actual > expected {
+            return Err(invalid(format!(
+                "expected {} bytes but found {}",
+                expected, actual
+            )));
+        }
+
+        let nodes_start = TAXONOMY_HEADER_SIZE as usize;
+        let nodes: Vec<TaxonomyNode> = buf
+            [nodes_start..nodes_start + (node_count * TAXONOMY_NODE_SIZE) as usize]
+            .chunks_exact(TAXONOMY_NODE_SIZE as usize)
+            .map(|node| TaxonomyNode {
+                parent_id: read_u64(node, 0),
+                first_child: read_u64(node, 8),
+                child_count: read_u64(node, 16),
+                name_offset: read_u64(node, 24),
+                rank_offset: read_u64(node, 32),
+                external_id: read_u64(node, 40),
+                godparent_id: read_u64(node, 48),
+            })
+            .collect();
+        let names_start = nodes_start + (node_count * TAXONOMY_NODE_SIZE) as usize;
+        let ranks_start = names_start + name_data_len as usize;
+        let taxonomy = Self {
+            nodes,
+            name_data: buf[names_start..ranks_start].to_vec(),
+            rank_data: buf[ranks_start..].to_vec(),
+        };
+        taxonomy.validate().map_err(invalid)?;
+        Ok(taxonomy)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.nodes.len() < 2 {
+            return Err("taxonomy has no root node".to_string());
+        }
+        for (id, node) in self.nodes.iter().enumerate().skip(2) {
+            if node.parent_id == 0 || node.parent_id >= id as u64 {
+                return Err(format!("node {} has invalid parent {}", id, node.parent_id));
+            }
+        }
+        for (id, node) in self.nodes.iter().enumerate().skip(1) {
+            if node.name_offset >= self.name_data.len() as u64
+                || node.rank_offset >= self.rank_data.len() as u64
+            {
+                return Err(format!(
+                    "node {} has a name or rank outside the string data",
+                    id
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of nodes, including the unused node 0
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The scientific name of the node with internal ID `id`
+    pub fn name(&self, id: u64) -> Option<&str> {
+        let node = self.nodes.get(id as usize)?;
+        c_str_at(&self.name_data, node.name_offset)
+    }
+
+    /// The rank (e.g., "species") of the node with internal ID `id`
+    pub fn rank(&self, id: u64) -> Option<&str> {
+        let node = self.nodes.get(id as usize)?;
+        c_str_at(&self.rank_data, node.rank_offset)
+    }
+
+    /// The NCBI taxonomy ID of the node with internal ID `id` (0 for unclassified)
+    pub fn external_id(&self, id: u64) -> u64 {
+        self.nodes
+            .get(id as usize)
+            .map_or(0, |node| node.external_id)
+    }
+
+    /// Whether `a` is `b` or one of its ancestors
+    pub fn is_ancestor_of(&self, a: u64, mut b: u64) -> bool {
+        if a == 0 || b == 0 {
+            return false;
+        }
+        while b > a {
+            b = self.nodes[b as usize].parent_id;
+        }
+        a == b
+    }
+
+    /// The lowest common ancestor of `a` and `b`, where 0 means "no taxon"
+    pub fn lowest_common_ancestor(&self, mut a: u64, mut b: u64) -> u64 {
+        if a == 0 || b == 0 {
+            return a.max(b);
+        }
+        while a != b {
+            if a > b {
+                a = self.nodes[a as usize].parent_id;
+            } else {
+                b = self.nodes[b as usize].parent_id;
+            }
+        }
+        a
+    }
+}
+
+/// A kraken2 compact hash table (`hash.k2d`) loaded into memory
+///
+/// Each cell packs the top `key_bits` of a minimizer's hash with a `value_bits` taxon ID.
+/// Collisions are resolved by linear probing and an empty cell ends the search.
+pub struct CompactHashTable {
+    pub header: HashTableHeader,
+    cells: Vec<u32>,
+}
+
+impl CompactHashTable {
+    /// Load the whole table into memory; for the HPRC database this is several gigabytes.
+    pub fn from_path(path: &Path) -> Result<Self, DbError> {
+        let header = HashTableHeader::from_path(path)?;
+        let mut reader = io::BufReader::new(File::open(path)?);
+        io::copy(&mut (&mut reader).take(HASH_HEADER_SIZE), &mut io::sink())?;
+
+        let mut cells = Vec::with_capacity(header.capacity as usize);
+        let mut buf = vec![0u8; 1 << 20];
+        while (cells.len() as u64) < header.capacity {
+            let remaining = (header.capacity - cells.len() as u64) * HASH_CELL_SIZE;
+            let chunk = &mut buf[..remaining.min(1 << 20) as usize];
+            reader.read_exact(chunk)?;
+            cells.extend(
+                chunk
+                    .chunks_exact(HASH_CELL_SIZE as usize)
+                    .map(|cell| u32::from_le_bytes(cell.try_into().unwrap())),
+            );
+        }
+        Ok(Self { header, cells })
+    }
+
+    /// Look up the taxon (internal ID) for a minimizer, returning 0 if it is not in the table
+    pub fn get(&self, minimizer: u64) -> u64 {
+        let capacity = self.cells.len() as u64;
+        if capacity == 0 {
+            return 0;
+        }
+        let value_bits = self.header.value_bits;
+        let value_mask = self.header.max_value();
+        let hash = murmur_hash3(minimizer);
+        let compacted_key = hash >> (32 + value_bits);
+        let first = hash % capacity;
+        let mut idx = first;
+        loop {
+            let cell = self.cells[idx as usize] as u64;
+            let value = cell & value_mask;
+            if value == 0 {
+                return 0;
+            }
+            if cell >> value_bits == compacted_key {
+                return value;
+            }
+            idx = (idx + 1) % capacity;
+            if idx == first {
+                return 0;
+            }
+        }
+    }
+}
+
+/// The 64-bit finalizer from MurmurHash3, which kraken2 uses to hash minimizers
+pub fn murmur_hash3(key: u64) -> u64 {
+    let mut k = key;
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xff51afd7ed558ccd);
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xc4ceb9fe1a85ec53);
+    k ^= k >> 33;
+    k
+}
+
+/// A summary of a kraken2 database, read without loading the (large) hash table
+#[derive(Debug, Clone)]
+pub struct KrakenDatabase {
+    pub path: PathBuf,
+    pub options: IndexOptions,
+    pub hash: HashTableHeader,
+    pub taxonomy: Taxonomy,
+}
+
+impl KrakenDatabase {
+    /// Parse and cross-check the files in a kraken2 database directory.
+    pub fn inspect(dir: &Path) -> Result<Self, DbError> {
+        let options = IndexOptions::from_path(&dir.join(OPTIONS_FILE))?;
+        let hash = HashTableHeader::from_path(&dir.join(HASH_FILE))?;
+        let taxonomy = Taxonomy::from_path(&dir.join(TAXONOMY_FILE))?;
+
+        let max_taxon = taxonomy.node_count() as u64 - 1;
+        if max_taxon > hash.max_value() {
+            return Err(DbError::Invalid {
+                file: dir.join(HASH_FILE),
+                reason: format!(
+                    "{} value bits cannot hold the {} taxa in {}",
+                    hash.value_bits, max_taxon, TAXONOMY_FILE
+                ),
+            });
+        }
+
+        Ok(Self {
+            path: dir.to_path_buf(),
+            options,
+            hash,
+            taxonomy,
+        })
+    }
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_i32(buf: &[u8], offset: usize) -> i32 {
+    i32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Read the NUL-terminated string starting at `offset`
+fn c_str_at(data: &[u8], offset: u64) -> Option<&str> {
+    let data = data.get(offset as usize..)?;
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    std::str::from_utf8(&data[..end]).ok()
+}
+
+/// Builders for small, valid kraken2 database files, shared by the tests of modules that read them
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use super::*;
+
+    pub fn options_bytes(k: u64, l: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(k.to_le_bytes());
+        buf.extend(l.to_le_bytes());
+        buf.extend(0u64.to_le_bytes()); // spaced seed mask
+        buf.extend(0u64.to_le_bytes()); // toggle mask
+        buf.extend([1, 0, 0, 0, 0, 0, 0, 0]); // dna_db + padding
+        buf.extend(0u64.to_le_bytes()); // minimum acceptable hash value
+        buf.extend(1i32.to_le_bytes()); // revcom version
+        buf.extend(0i32.to_le_bytes()); // db version
+        buf.extend(0i32.to_le_bytes()); // db type
+        buf.extend([0, 0, 0, 0]); // padding
+        buf
+    }
+
+    pub fn hash_bytes(cells: &[u32], size: u64, value_bits: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend((cells.len() as u64).to_le_bytes());
+        buf.extend(size.to_le_bytes());
+        buf.extend((32 - value_bits).to_le_bytes());
+        buf.extend(value_bits.to_le_bytes());
+        for cell in cells {
+            buf.extend(cell.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Cells of a hash table of `capacity` holding `entries` of (minimizer, taxon), inserted the
+    /// same way kraken2 does
+    pub fn hash_cells(capacity: u64, value_bits: u64, entries: &[(u64, u64)]) -> Vec<u32> {
+        let mut cells = vec![0u32; capacity as usize];
+        for (minimizer, taxon) in entries {
+            let hash = murmur_hash3(*minimizer);
+            let compacted_key = hash >> (32 + value_bits);
+            let mut idx = (hash % capacity) as usize;
+            while cells[idx] != 0 {
+                idx = (idx + 1) % capacity as usize;
+            }
+            cells[idx] = ((compacted_key << value_bits) | taxon) as u32;
+        }
+        cells
+    }
+
+    /// A taxonomy from `(parent_id, external_id, name, rank)` for nodes 1.. (node 0 is added)
+    pub fn taxonomy_bytes(nodes: &[(u64, u64, &str, &str)]) -> Vec<u8> {
+        let mut names = vec![0u8];
+        let mut ranks = vec![0u8];
+        let mut node_data = vec![0u8; TAXONOMY_NODE_SIZE as usize];
+        for (parent_id, external_id, name, rank) in nodes {
+            for field in [
+                *parent_id,
+                0,
+                0,
+                names.len() as u64,
+                ranks.len() as u64,
+                *external_id,
+                0,
+            ] {
+                node_data.extend(field.to_le_bytes());
+            }
+            names.extend(name.as_bytes());
+            names.push(0);
+            ranks.extend(rank.as_bytes());
+            ranks.push(0);
+        }
+        let mut buf = TAXONOMY_MAGIC.to_vec();
+        buf.extend((nodes.len() as u64 + 1).to_le_bytes());
+        buf.extend((names.len() as u64).to_le_bytes());
+        buf.extend((ranks.len() as u64).to_le_bytes());
+        buf.extend(node_data);
+        buf.extend(names);
+        buf.extend(ranks);
+        buf
+    }
+
+    /// A root -> Homo -> Homo sapiens taxonomy
+    pub fn human_taxonomy_bytes() -> Vec<u8> {
+        taxonomy_bytes(&[
+            (0, 1, "root", "R"),
+            (1, 9605, "Homo", "G"),
+            (2, 9606, "Homo sapiens", "S"),
+        ])
+    }
+
+    pub fn write_database(dir: &Path, options: &[u8], hash: &[u8], taxonomy: &[u8]) {
+        std::fs::write(dir.join(OPTIONS_FILE), options).unwrap();
+        std::fs::write(dir.join(HASH_FILE), hash).unwrap();
+        std::fs::write(dir.join(TAXONOMY_FILE), taxonomy).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_utils::*;
+    use super::*;
+
+    #[test]
+    fn test_inspect_valid_database() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_database(
+            tmp.path(),
+            &options_bytes(35, 31),
+            &hash_bytes(&[0; 8], 0, 22),
+            &human_taxonomy_bytes(),
+        );
+
+        let db = KrakenDatabase::inspect(tmp.path()).unwrap();
+        assert_eq!(db.options.k, 35);
+        assert_eq!(db.options.l, 31);
+        assert!(db.options.dna_db);
+        assert_eq!(db.options.revcom_version, 1);
+        assert!(db.options.has_expected_parameters());
+        assert_eq!(db.hash.capacity, 8);
+        assert_eq!(db.hash.key_bits, 10);
+        assert_eq!(db.taxonomy.node_count(), 4);
+        assert_eq!(db.taxonomy.name(3), Some("Homo sapiens"));
+        assert_eq!(db.taxonomy.rank(3), Some("S"));
+        assert_eq!(db.taxonomy.nodes[3].external_id, 9606);
+        assert_eq!(db.taxonomy.nodes[3].parent_id, 2);
+    }
+
+    #[test]
+    fn test_options_from_older_kraken2() {
+        let opts = IndexOptions::from_bytes(&options_bytes(31, 15)[..48])
+            .ok()
+            .unwrap();
+        assert_eq!(opts.k, 31);
+        assert_eq!(opts.l, 15);
+        assert_eq!(opts.revcom_version, 0);
+        assert!(!opts.has_expected_parameters());
+    }
+
+    #[test]
+    fn test_options_truncated() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(OPTIONS_FILE);
+        std::fs::write(&path, &options_bytes(35, 31)[..20]).unwrap();
+        let err = IndexOptions::from_path(&path).unwrap_err();
+        assert!(matches!(
+            err,
+            DbError::Truncated {
+                expected: 40,
+                actual: 20,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_options_invalid_lengths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(OPTIONS_FILE);
+        std::fs::write(&path, options_bytes(21, 31)).unwrap();
+        let err = IndexOptions::from_path(&path).unwrap_err();
+        assert!(matches!(err, DbError::Invalid { .. }));
+        assert!(err
+            .to_string()
+            .contains("k-mer length k=21 is smaller than l=31"));
+    }
+
+    #[test]
+    fn test_hash_truncated() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(HASH_FILE);
+        let hash = hash_bytes(&[0; 8], 0, 22);
+        std::fs::write(&path, &hash[..hash.len() - 4]).unwrap();
+        let err = HashTableHeader::from_path(&path).unwrap_err();
+        assert!(matches!(
+            err,
+            DbError::Truncated {
+                expected: 64,
+                actual: 60,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_hash_bad_bit_split() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(HASH_FILE);
+        let mut hash = hash_bytes(&[0; 8], 0, 22);
+        hash[16..24].copy_from_slice(&11u64.to_le_bytes());
+        std::fs::write(&path, hash).unwrap();
+        assert!(matches!(
+            HashTableHeader::from_path(&path),
+            Err(DbError::Invalid { .. })
+        ));
+    }
+
+    #[test]
+    fn test_taxonomy_bad_magic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(TAXONOMY_FILE);
+        std::fs::write(&path, b"NOTATAXONOMYFILE").unwrap();
+        assert!(matches!(
+            Taxonomy::from_path(&path),
+            Err(DbError::BadMagic(_))
+        ));
+    }
+
+    #[test]
+    fn test_taxonomy_truncated() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(TAXONOMY_FILE);
+        let taxonomy = human_taxonomy_bytes();
+        std::fs::write(&path, &taxonomy[..taxonomy.len() - 10]).unwrap();
+        assert!(matches!(
+            Taxonomy::from_path(&path),
+            Err(DbError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn test_taxonomy_invalid_parent() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(TAXONOMY_FILE);
+        std::fs::write(
+            &path,
+            taxonomy_bytes(&[(0, 1, "root", "R"), (3, 9606, "Homo sapiens", "S")]),
+        )
+        .unwrap();
+        assert!(matches!(
+            Taxonomy::from_path(&path),
+            Err(DbError::Invalid { .. })
+        ));
+    }
+
+    #[test]
+    fn test_lowest_common_ancestor() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(TAXONOMY_FILE);
+        // root -> Homo -> {Homo sapiens, Homo neanderthalensis}
+        std::fs::write(
+            &path,
+            taxonomy_bytes(&[
+                (0, 1, "root", "R"),
+                (1, 9605, "Homo", "G"),
+                (2, 9606, "Homo sapiens", "S"),
+                (2, 63221, "Homo neanderthalensis", "S"),
+            ]),
+        )
+        .unwrap();
+        let taxonomy = Taxonomy::from_path(&path).unwrap();
+        assert_eq!(taxonomy.lowest_common_ancestor(3, 4), 2);
+        assert_eq!(taxonomy.lowest_common_ancestor(3, 3), 3);
+        assert_eq!(taxonomy.lowest_common_ancestor(0, 3), 3);
+        assert!(taxonomy.is_ancestor_of(1, 4));
+        assert!(taxonomy.is_ancestor_of(3, 3));
+        assert!(!taxonomy.is_ancestor_of(3, 4));
+        assert!(!taxonomy.is_ancestor_of(0, 4));
+        assert_eq!(taxonomy.external_id(3), 9606);
+    }
+
+    #[test]
+    fn test_hash_table_lookup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(HASH_FILE);
+        let entries = [(42, 3), (7, 2), (1 << 40, 3)];
+        std::fs::write(&path, hash_bytes(&hash_cells(16, 22, &entries), 3, 22)).unwrap();
+
+        let table = CompactHashTable::from_path(&path).unwrap();
+        for (minimizer, taxon) in entries {
+            assert_eq!(table.get(minimizer), taxon);
+        }
+        assert_eq!(table.get(43), 0);
+    }
+
+    #[test]
+    fn test_murmur_hash3() {
+        assert_eq!(murmur_hash3(0), 0);
+        assert_ne!(murmur_hash3(1), murmur_hash3(2));
+    }
+
+    #[test]
+    fn test_inspect_too_few_value_bits() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_database(
+            tmp.path(),
+            &options_bytes(35, 31),
+            &hash_bytes(&[0; 8], 0, 1),
+            &human_taxonomy_bytes(),
+        );
+        assert!(matches!(
+            KrakenDatabase::inspect(tmp.path()),
+            Err(DbError::Invalid { .. })
+        ));
+    }
+}
@@ -1,36 +1,58 @@
-use crate::Config;
+use crate::registry::{
+    md5_file, DatabaseStore, InstalledDatabase, Registry, RegistryError, METADATA_FILE,
+};
+use crate::sources::Sources;
+use crate::validate_db_directory;
 use async_std::task;
 use flate2::read::GzDecoder;
 use futures_util::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::blocking::get;
+use log::{info, warn};
+use reqwest::header::{CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::{StatusCode, Url};
 use std::fs;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tar::Archive;
 use thiserror::Error;
 
-// create a variable to store the url for the config file
-const CONFIG_URL: &str = "https://raw.githubusercontent.com/mbhall88/nohuman/main/config.toml";
-
 #[derive(Error, Debug)]
 pub enum DownloadError {
     #[error("Failed to download the tarball")]
     DownloadFailed,
 
+    #[error("The server responded with {0}")]
+    HttpStatus(StatusCode),
+
+    #[error("The download ended after {received} of {expected} bytes")]
+    Incomplete { received: u64, expected: u64 },
+
+    #[error("Couldn't confirm the download is complete, as the server didn't give its length")]
+    Unverified,
+
+    #[error("Unexpected response to a resumed download: {0}")]
+    InvalidRange(String),
+
     #[error("Tarball MD5 hash does not match the expected value")]
     Md5Mismatch,
 
     #[error("Failed to extract the tarball")]
     ExtractionFailed,
 
+    #[error("{0}")]
+    NotADatabase(String),
+
+    #[error("Database {0} is already installed. Remove it first with `nohuman db remove`")]
+    AlreadyInstalled(String),
+
     #[error("Failed to download the config file")]
     ConfigDownloadFailed,
 
-    #[error("Failed to parse the config file")]
-    ConfigParseFailed,
+    #[error(transparent)]
+    RegistryError(#[from] RegistryError),
 
     #[error("Failed to compute MD5 hash")]
     Md5Error,
@@ -44,104 +66,603 @@ pub enum DownloadError {
 
 /// function to compute md5 without reading whole file into memory
 fn compute_md5(path: &Path) -> Result<String, DownloadError> {
-    let mut file = fs::File::open(path).map_err(DownloadError::IoError)?;
-    let mut hasher = md5::Context::new();
-    let mut buffer = [0; 1024];
-    loop {
-        let n = file.read(&mut buffer).map_err(DownloadError::IoError)?;
-        if n == 0 {
-            break;
+    md5_file(path).map_err(DownloadError::IoError)
+}
+
+/// How many times a download is attempted, and how long to wait between attempts. The wait
+/// doubles after each failed attempt, up to `max_backoff`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
         }
-        hasher.consume(&buffer[..n]);
     }
-    let result = hasher.compute();
-    Ok(format!("{:x}", result))
 }
 
-async fn download_from_url(url: &str, dest: &Path) -> Result<(), DownloadError> {
-    let response = reqwest::get(url)
-        .await
-        .map_err(DownloadError::ReqwestError)?;
+/// The suffix of a download's partial file, which is kept if the download fails so it can be
+/// resumed
+const PARTIAL_SUFFIX: &str = ".part";
+
+/// The suffix of the file holding the URL of a partial download and its ETag (or Last-Modified
+/// date), to check that the download is resumed from the same URL and the file hasn't changed on
+/// the server
+const VALIDATOR_SUFFIX: &str = ".part.validator";
+
+/// Read the URL of a partial download, and its ETag if the server gave one, from its validator file
+fn read_validator(path: &Path) -> Option<(String, Option<String>)> {
+    let validator = fs::read_to_string(path).ok()?;
+    let mut lines = validator.lines();
+    let url = lines.next()?.to_string();
+    let etag = lines
+        .next()
+        .filter(|etag| !etag.is_empty())
+        .map(String::from);
+    Some((url, etag))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// How a download attempt that didn't fail ended
+enum Attempt {
+    Complete,
+    /// The body of a response without a `Content-Length` ended after this many bytes, which may
+    /// only be because the connection was closed early
+    Unverified(u64),
+}
 
-    if response.status() != reqwest::StatusCode::OK {
-        return Err(DownloadError::DownloadFailed);
+/// Whether a failed attempt at a download is worth retrying
+fn is_transient(error: &DownloadError) -> bool {
+    match error {
+        DownloadError::ReqwestError(e) => {
+            // Errors reading the body of a download are reported as decoding errors
+            e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() || e.is_decode()
+        }
+        DownloadError::HttpStatus(status) => {
+            status.is_server_error()
+                || *status == StatusCode::REQUEST_TIMEOUT
+                || *status == StatusCode::TOO_MANY_REQUESTS
+        }
+        DownloadError::Incomplete { .. } => true,
+        _ => false,
     }
+}
 
-    let content_length = response.content_length().unwrap_or(0);
-    let progress_bar = ProgressBar::new(content_length);
-    progress_bar.set_style(
-        ProgressStyle::default_bar()
-            .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .progress_chars("#>-"),
-    );
+/// Download `url` to `dest`, resuming from the partial file of an earlier attempt if there is one,
+/// and retrying transient failures according to `policy`. The partial file is kept if the
+/// download fails.
+///
+/// If the server doesn't give the length of the file, the end of the download is confirmed by
+/// asking for the rest of the file. A server that can't send the rest of a file is trusted once
+/// two whole downloads have the same length.
+async fn download_from_url(
+    url: &str,
+    dest: &Path,
+    policy: RetryPolicy,
+) -> Result<(), DownloadError> {
+    let partial = with_suffix(dest, PARTIAL_SUFFIX);
+    let validator = with_suffix(dest, VALIDATOR_SUFFIX);
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(30))
+        .read_timeout(Duration::from_secs(60))
+        .build()?;
+
+    let mut backoff = policy.initial_backoff;
+    let mut attempt = 1;
+    let mut checks = 0;
+    let mut unverified = None;
+    loop {
+        match download_attempt(&client, url, &partial, &validator).await {
+            Ok(Attempt::Complete) => break,
+            Ok(Attempt::Unverified(length)) if unverified == Some(length) => break,
+            Ok(Attempt::Unverified(_)) if checks + 1 >= policy.attempts => {
+                return Err(DownloadError::Unverified)
+            }
+            Ok(Attempt::Unverified(length)) => {
+                info!("Checking the download is complete after {} bytes", length);
+                checks += 1;
+                unverified = Some(length);
+            }
+            Err(e) if attempt < policy.attempts && is_transient(&e) => {
+                warn!(
+                    "Download attempt {} of {} failed ({}); retrying in {:?}",
+                    attempt, policy.attempts, e, backoff
+                );
+                attempt += 1;
+                task::sleep(backoff).await;
+                backoff = (backoff * 2).min(policy.max_backoff);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    fs::rename(&partial, dest)?;
+    let _ = fs::remove_file(&validator);
+    Ok(())
+}
 
-    let mut file = File::create(dest).map_err(DownloadError::IoError)?;
+/// Download `url` into `partial`, continuing from its end if it was downloaded from `url`, the
+/// server supports range requests and the file is unchanged
+async fn download_attempt(
+    client: &reqwest::Client,
+    url: &str,
+    partial: &Path,
+    validator: &Path,
+) -> Result<Attempt, DownloadError> {
+    let mut offset = fs::metadata(partial).map_or(0, |metadata| metadata.len());
+    let mut request = client.get(url);
+    if offset > 0 {
+        match read_validator(validator) {
+            Some((source, etag)) if source == url => {
+                request = request.header(RANGE, format!("bytes={}-", offset));
+                // The server sends the whole file rather than the range if it has changed
+                if let Some(etag) = etag {
+                    request = request.header(IF_RANGE, etag);
+                }
+            }
+            // Mirrors may not serve identical files, so a partial file is only resumed from the
+            // URL it came from
+            _ => {
+                info!("Discarding a partial download from another URL");
+                offset = 0;
+            }
+        }
+    }
+    let response = request.send().await?;
 
+    let (mut file, offset, total) = match response.status() {
+        StatusCode::OK => {
+            if offset > 0 {
+                info!("Restarting the download, as the server can't resume it");
+            }
+            (File::create(partial)?, 0, response.content_length())
+        }
+        StatusCode::PARTIAL_CONTENT => {
+            let range = response
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok());
+            let (start, total) = range.and_then(parse_content_range).ok_or_else(|| {
+                DownloadError::InvalidRange(format!(
+                    "invalid Content-Range {:?}",
+                    range.unwrap_or_default()
+                ))
+            })?;
+            if start != offset {
+                return Err(DownloadError::InvalidRange(format!(
+                    "asked for bytes from {} but got bytes from {}",
+                    offset, start
+                )));
+            }
+            info!("Resuming the download from {} bytes", offset);
+            let total = total.or_else(|| response.content_length().map(|length| offset + length));
+            (
+                OpenOptions::new().append(true).open(partial)?,
+                offset,
+                total,
+            )
+        }
+        // The partial file is already complete, or is longer than the file on the server
+        StatusCode::RANGE_NOT_SATISFIABLE => {
+            let range = response
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|value| value.to_str().ok());
+            let total = range.and_then(|range| range.strip_prefix("bytes */")?.parse::<u64>().ok());
+            if total == Some(offset) {
+                return Ok(Attempt::Complete);
+            }
+            fs::remove_file(partial)?;
+            return Err(DownloadError::Incomplete {
+                received: offset,
+                expected: total.unwrap_or(0),
+            });
+        }
+        status if is_transient(&DownloadError::HttpStatus(status)) => {
+            return Err(DownloadError::HttpStatus(status))
+        }
+        status => {
+            warn!("The server responded with {}", status);
+            return Err(DownloadError::DownloadFailed);
+        }
+    };
+
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .or_else(|| response.headers().get(LAST_MODIFIED));
+    let etag = etag
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    fs::write(validator, format!("{}\n{}\n", url, etag))?;
+
+    // Without a content length, the bytes received are shown instead of a progress bar
+    let progress_bar = match total {
+        Some(total) => ProgressBar::new(total).with_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                .unwrap()
+                .progress_chars("#>-"),
+        ),
+        None => ProgressBar::new_spinner().with_style(
+            ProgressStyle::default_spinner()
+                .template("[{elapsed_precise}] {spinner} {bytes} ({bytes_per_sec})")
+                .unwrap(),
+        ),
+    };
+    progress_bar.set_position(offset);
+
+    let mut received = offset;
     let mut stream = response.bytes_stream();
     while let Some(item) = stream.next().await {
-        let chunk = item?;
-        file.write_all(&chunk).map_err(DownloadError::IoError)?;
-        progress_bar.inc(chunk.len() as u64);
+        let chunk = match item {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                progress_bar.abandon();
+                return Err(e.into());
+            }
+        };
+        file.write_all(&chunk)?;
+        received += chunk.len() as u64;
+        progress_bar.set_position(received);
     }
-
+    file.flush()?;
     progress_bar.finish();
-    Ok(())
+
+    match total {
+        Some(expected) if received != expected => {
+            Err(DownloadError::Incomplete { received, expected })
+        }
+        Some(_) => Ok(Attempt::Complete),
+        None => Ok(Attempt::Unverified(received)),
+    }
+}
+
+/// Parse a `Content-Range` header of the form `bytes <start>-<end>/<total>` (the total may be `*`)
+/// into the start and total
+fn parse_content_range(range: &str) -> Option<(u64, Option<u64>)> {
+    let (range, total) = range.strip_prefix("bytes ")?.split_once('/')?;
+    let start = range.split_once('-')?.0.parse().ok()?;
+    let total = match total {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    Some((start, total))
 }
 
+/// Download the tarball from the first of `urls` that works and extract it into `output_path`.
+/// The tarball is downloaded to `<output_path>.tar.gz`, so an interrupted download is resumed by
+/// the next call, and deleted once it has been extracted. A mirror whose tarball doesn't match
+/// `md5` is skipped.
 fn download_and_extract_tarball(
-    url: &str,
+    urls: &[String],
+    output_path: &Path,
+    md5: &str,
+) -> Result<(), DownloadError> {
+    download_and_extract_tarball_with_retries(urls, output_path, md5, RetryPolicy::default())
+}
+
+fn download_and_extract_tarball_with_retries(
+    urls: &[String],
     output_path: &Path,
     md5: &str,
+    policy: RetryPolicy,
 ) -> Result<(), DownloadError> {
-    // Create a temporary file to store the downloaded tarball
-    let tarball_path = tempfile::NamedTempFile::new().map_err(DownloadError::IoError)?;
-    task::block_on(download_from_url(url, tarball_path.path()))?;
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tarball_path = with_suffix(output_path, ".tar.gz");
+    let mut error = DownloadError::DownloadFailed;
+    for url in urls {
+        if !tarball_path.exists() {
+            info!("Downloading {}", url);
+            if let Err(e) = task::block_on(download_from_url(url, &tarball_path, policy)) {
+                warn!("Failed to download {}: {}", url, e);
+                error = e;
+                continue;
+            }
+        }
 
-    // Check the MD5 hash of the tarball
-    let md5_hash = compute_md5(tarball_path.path())?;
+        match extract_tarball(&tarball_path, output_path, md5) {
+            // Start again from scratch next time
+            Err(DownloadError::Md5Mismatch) => {
+                warn!(
+                    "The tarball from {} does not match the expected MD5 hash",
+                    url
+                );
+                fs::remove_file(&tarball_path)?;
+                error = DownloadError::Md5Mismatch;
+            }
+            Err(e) => return Err(e),
+            // remove the downloaded tarball file
+            Ok(()) => return fs::remove_file(&tarball_path).map_err(DownloadError::IoError),
+        }
+    }
+    Err(error)
+}
+
+/// Check the MD5 hash of a tarball and extract it to `output_path`
+fn extract_tarball(
+    tarball_path: &Path,
+    output_path: &Path,
+    md5: &str,
+) -> Result<(), DownloadError> {
+    let md5_hash = compute_md5(tarball_path)?;
     if md5_hash != md5 {
         return Err(DownloadError::Md5Mismatch);
     }
 
-    // Extract the tarball to the output path
-    let tarball = File::open(tarball_path.path()).map_err(DownloadError::IoError)?;
+    let tarball = File::open(tarball_path).map_err(DownloadError::IoError)?;
     let tar = GzDecoder::new(&tarball);
     let mut archive = Archive::new(tar);
     archive
         .unpack(output_path)
-        .map_err(|_| DownloadError::ExtractionFailed)?;
+        .map_err(|_| DownloadError::ExtractionFailed)
+}
 
-    // remove the temporary tarball file
-    fs::remove_file(tarball_path.path()).map_err(DownloadError::IoError)?;
+/// Install the database named `name` (or the default database) at `version` (or its latest
+/// version) in `registry` into `store` from a local copy, without a network: either its tarball,
+/// which is checked against the registry's MD5 and extracted just as a download is, or a directory
+/// holding the database, which is copied. The same metadata is recorded as for a download.
+pub fn install_from_file(
+    store: &DatabaseStore,
+    source: &Path,
+    registry: &Registry,
+    name: Option<&str>,
+    version: Option<&str>,
+) -> Result<InstalledDatabase, DownloadError> {
+    let entry = registry.select(name, version)?;
+    let output_path = store.install_dir(entry);
+    if output_path.exists() {
+        return Err(DownloadError::AlreadyInstalled(entry.id()));
+    }
+    let result = if source.is_dir() {
+        validate_db_directory(source)
+            .map_err(DownloadError::NotADatabase)
+            .and_then(|_| Ok(copy_dir(source, &output_path)?))
+    } else {
+        fs::create_dir_all(&output_path)
+            .map_err(DownloadError::IoError)
+            .and_then(|_| extract_tarball(source, &output_path, &entry.md5))
+    };
+    if let Err(e) = result {
+        // Don't leave a partial database that would be taken as installed
+        let _ = fs::remove_dir_all(&output_path);
+        return Err(e);
+    }
+    Ok(store.record(entry)?)
+}
 
+/// Copy the files of a directory, and its subdirectories, into `dest`, except for any nohuman
+/// metadata
+fn copy_dir(source: &Path, dest: &Path) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else if entry.file_name() != METADATA_FILE {
+            fs::copy(entry.path(), target)?;
+        }
+    }
     Ok(())
 }
 
-pub fn download_database(database_path: &Path) -> Result<(), DownloadError> {
-    let config = download_config()?;
-    download_and_extract_tarball(&config.database_url, database_path, &config.database_md5)?;
-    Ok(())
+/// Download the database named `name` (or the default database) at `version` (or its latest
+/// version) from the registry, and install it into `store`, using the URLs in `sources`. A
+/// database that is already installed isn't downloaded again.
+pub fn download_database(
+    store: &DatabaseStore,
+    sources: &Sources,
+    name: Option<&str>,
+    version: Option<&str>,
+) -> Result<InstalledDatabase, DownloadError> {
+    let registry = download_registry(&sources.registry_urls)?;
+    let entry = registry.select(name, version)?;
+    if let Ok(installed) = InstalledDatabase::open(&store.install_dir(entry)) {
+        if installed.entry == *entry && installed.database_dir().is_ok() {
+            info!(
+                "Database {} is already installed in {:?}",
+                entry.id(),
+                installed.path
+            );
+            return Ok(installed);
+        }
+    }
+    download_and_extract_tarball(
+        &sources.db_urls_for(entry),
+        &store.install_dir(entry),
+        &entry.md5,
+    )?;
+    Ok(store.record(entry)?)
+}
+
+/// Download and parse the registry of databases from the first of `urls` that works. A `file://`
+/// URL is read from the local file.
+pub fn download_registry(urls: &[String]) -> Result<Registry, DownloadError> {
+    for url in urls {
+        match fetch_registry(url) {
+            Ok(registry) => return Ok(registry),
+            Err(e) => warn!(
+                "Failed to download the database registry from {}: {}",
+                url, e
+            ),
+        }
+    }
+    Err(DownloadError::ConfigDownloadFailed)
+}
+
+fn fetch_registry(url: &str) -> Result<Registry, DownloadError> {
+    if let Some(local) = Url::parse(url).ok().filter(|url| url.scheme() == "file") {
+        let path = local.to_file_path().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "not a path on this machine")
+        })?;
+        return Ok(Registry::parse(&fs::read_to_string(path)?)?);
+    }
+    let client = reqwest::blocking::Client::builder()
+        .connect_timeout(Duration::from_secs(30))
+        .timeout(Duration::from_secs(60))
+        .build()?;
+    let response = client.get(url).send()?;
+    if !response.status().is_success() {
+        return Err(DownloadError::HttpStatus(response.status()));
+    }
+    Ok(Registry::parse(&response.text()?)?)
 }
 
-fn download_config() -> Result<Config, DownloadError> {
-    // Download the config file
-    let mut response = get(CONFIG_URL).map_err(|_| DownloadError::ConfigDownloadFailed)?;
-    let mut config_content = String::new();
-    response
-        .read_to_string(&mut config_content)
-        .map_err(|_| DownloadError::ConfigDownloadFailed)?;
+/// A minimal HTTP server on localhost for testing downloads without a network
+#[cfg(test)]
+pub(crate) mod test_server {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    /// How the server responds. Every response closes the connection.
+    #[derive(Debug, Clone, Default)]
+    pub struct Behaviour {
+        /// The body served at every path
+        pub body: Vec<u8>,
+        pub etag: Option<String>,
+        /// Honour `Range` requests (and `If-Range`)
+        pub ranges: bool,
+        /// Leave out `Content-Length`, ending the body by closing the connection
+        pub no_content_length: bool,
+        /// Respond 503 to this many requests before serving the body
+        pub fail_first: usize,
+        /// Close the connection after sending this many bytes of the first body
+        pub drop_after: Option<usize>,
+        /// Respond 404 to every request
+        pub not_found: bool,
+    }
+
+    pub struct TestServer {
+        pub url: String,
+        /// The head of every request received, lowercased
+        pub requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TestServer {
+        pub fn start(behaviour: Behaviour) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/db.tar.gz", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let received = requests.clone();
+            std::thread::spawn(move || {
+                let mut behaviour = behaviour;
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else { continue };
+                    let request = read_request(&stream);
+                    received.lock().unwrap().push(request.clone());
+                    let _ = respond(stream, &request, &mut behaviour);
+                }
+            });
+            Self { url, requests }
+        }
+
+        pub fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    fn read_request(stream: &TcpStream) -> String {
+        let mut reader = BufReader::new(stream);
+        let mut request = String::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                return request;
+            }
+            request.push_str(&line.to_lowercase());
+        }
+    }
+
+    fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+        request
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .map(str::trim)
+    }
 
-    // Parse the TOML content into a config struct
-    let config: Config =
-        toml::from_str(&config_content).map_err(|_| DownloadError::ConfigParseFailed)?;
+    fn respond(
+        mut stream: TcpStream,
+        request: &str,
+        behaviour: &mut Behaviour,
+    ) -> std::io::Result<()> {
+        if behaviour.not_found {
+            return stream.write_all(
+                b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+        }
+        if behaviour.fail_first > 0 {
+            behaviour.fail_first -= 1;
+            return stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        }
+        let length = behaviour.body.len();
+        let etag_matches = match (header(request, "if-range"), &behaviour.etag) {
+            (Some(if_range), Some(etag)) => if_range == etag.to_lowercase(),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        let start = header(request, "range")
+            .filter(|_| behaviour.ranges && etag_matches)
+            .and_then(|range| {
+                range
+                    .strip_prefix("bytes=")?
+                    .strip_suffix('-')?
+                    .parse::<usize>()
+                    .ok()
+            });
 
-    Ok(config)
+        let mut head = match start {
+            Some(start) if start >= length => {
+                let head = format!(
+                    "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    length
+                );
+                return stream.write_all(head.as_bytes());
+            }
+            Some(start) => format!(
+                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+                start,
+                length - 1,
+                length
+            ),
+            None => "HTTP/1.1 200 OK\r\n".to_string(),
+        };
+        let body = &behaviour.body[start.unwrap_or(0)..];
+        if !behaviour.no_content_length {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        if let Some(etag) = &behaviour.etag {
+            head.push_str(&format!("ETag: {}\r\n", etag));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        stream.write_all(head.as_bytes())?;
+        match behaviour.drop_after.take() {
+            Some(sent) => stream.write_all(&body[..sent.min(body.len())]),
+            None => stream.write_all(body),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::test_server::{Behaviour, TestServer};
     use super::*;
+    use crate::registry::BUNDLED_REGISTRY;
     use std::path::PathBuf;
     use tempfile::TempDir;
 
@@ -165,7 +686,7 @@ mod tests {
         // Download and extract a sample tarball
         let url = "https://github.com/mbhall88/rasusa/releases/download/0.7.1/rasusa-0.7.1-x86_64-unknown-linux-gnu.tar.gz";
         let md5 = "6c60c417646084eac81fc23a85e9fbc2";
-        let result = download_and_extract_tarball(url, &output_path, md5);
+        let result = download_and_extract_tarball(&[url.to_string()], &output_path, md5);
 
         // Assert that the function executed successfully
         assert!(result.is_ok());
@@ -195,7 +716,7 @@ mod tests {
         // Download and extract a sample tarball
         let url = "https://github.com/mbhall88/rasusa/releases/download/0.7.1/rasusa-0.7.1-x86_64-unknown-linux-gnu.tar.gz";
         let md5 = "foo";
-        let result = download_and_extract_tarball(url, &output_path, md5);
+        let result = download_and_extract_tarball(&[url.to_string()], &output_path, md5);
 
         // Assert that the function executed successfully
         assert!(result.is_err());
@@ -221,7 +742,7 @@ mod tests {
         // Download and extract a non-existent tarball
         let url = "https://example.com/nonexistent.tar.gz";
         let md5 = "foo";
-        let result = download_and_extract_tarball(url, &output_path, md5);
+        let result = download_and_extract_tarball(&[url.to_string()], &output_path, md5);
 
         // Assert that the function returns a DownloadFailed error
         assert!(result.is_err());
@@ -247,7 +768,7 @@ mod tests {
         // Download and extract a tarball with invalid format
         let url = "https://raw.githubusercontent.com/mbhall88/rasusa/fa7e87b843419151cc4716c670adbb28544979b1/Cargo.toml";
         let md5 = "95143b02c21cc9ce1980645d2db69937";
-        let result = download_and_extract_tarball(url, &output_path, md5);
+        let result = download_and_extract_tarball(&[url.to_string()], &output_path, md5);
 
         // Assert that the function returns an ExtractionFailed error
         assert!(result.is_err());
@@ -260,6 +781,410 @@ mod tests {
         temp_dir.close().unwrap();
     }
 
+    /// A gzipped tarball holding `db/hash.k2d` of `size` bytes of noise, and its MD5
+    fn tarball(size: usize) -> (Vec<u8>, String) {
+        let mut state: u32 = 12345;
+        let noise: Vec<u8> = (0..size)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (state >> 24) as u8
+            })
+            .collect();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::fast(),
+        ));
+        builder
+            .append_data(&mut header, "db/hash.k2d", noise.as_slice())
+            .unwrap();
+        let gz = builder.into_inner().unwrap().finish().unwrap();
+        let md5 = format!("{:x}", md5::compute(&gz));
+        (gz, md5)
+    }
+
+    fn fast_retries() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+        }
+    }
+
+    /// Download and extract from `server` into `<tmp>/HPRC/r1`, checking nothing is left behind
+    /// but the extracted database
+    fn download_from(server: &TestServer, tmp: &TempDir, md5: &str) -> Result<(), DownloadError> {
+        let output = tmp.path().join("HPRC").join("r1");
+        download_and_extract_tarball_with_retries(
+            std::slice::from_ref(&server.url),
+            &output,
+            md5,
+            fast_retries(),
+        )?;
+        assert_eq!(
+            fs::metadata(output.join("db").join("hash.k2d"))
+                .unwrap()
+                .len(),
+            200_000
+        );
+        assert_eq!(fs::read_dir(tmp.path().join("HPRC")).unwrap().count(), 1);
+        Ok(())
+    }
+
+    /// Leave a partial download from `url` of the first `length` bytes of `body` in
+    /// `<tmp>/HPRC/r1`
+    fn write_partial(tmp: &TempDir, body: &[u8], length: usize, url: &str, etag: &str) {
+        fs::create_dir_all(tmp.path().join("HPRC")).unwrap();
+        fs::write(tmp.path().join("HPRC/r1.tar.gz.part"), &body[..length]).unwrap();
+        fs::write(
+            tmp.path().join("HPRC/r1.tar.gz.part.validator"),
+            format!("{}\n{}\n", url, etag),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_download_resumes_after_dropped_connection() {
+        let (body, md5) = tarball(200_000);
+        let server = TestServer::start(Behaviour {
+            body,
+            etag: Some("\"v1\"".to_string()),
+            ranges: true,
+            drop_after: Some(50_000),
+            ..Default::default()
+        });
+        let tmp = TempDir::new().unwrap();
+        download_from(&server, &tmp, &md5).unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[0].contains("range:"));
+        assert!(requests[1].contains("\nrange: bytes=") && !requests[1].contains("bytes=0-"));
+        assert!(requests[1].contains("\nif-range: \"v1\""));
+    }
+
+    #[test]
+    fn test_download_resumes_partial_file() {
+        let (body, md5) = tarball(200_000);
+        let server = TestServer::start(Behaviour {
+            body: body.clone(),
+            etag: Some("\"v1\"".to_string()),
+            ranges: true,
+            ..Default::default()
+        });
+        let tmp = TempDir::new().unwrap();
+        write_partial(&tmp, &body, 1000, &server.url, "\"v1\"");
+        download_from(&server, &tmp, &md5).unwrap();
+        assert!(server.requests()[0].contains("\nrange: bytes=1000-"));
+    }
+
+    #[test]
+    fn test_download_complete_partial_file() {
+        let (body, md5) = tarball(200_000);
+        let server = TestServer::start(Behaviour {
+            body: body.clone(),
+            etag: Some("\"v1\"".to_string()),
+            ranges: true,
+            ..Default::default()
+        });
+        let tmp = TempDir::new().unwrap();
+        write_partial(&tmp, &body, body.len(), &server.url, "\"v1\"");
+        download_from(&server, &tmp, &md5).unwrap();
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_download_restarts_if_file_changed() {
+        let (old_body, _) = tarball(100_000);
+        let (body, md5) = tarball(200_000);
+        let server = TestServer::start(Behaviour {
+            body,
+            etag: Some("\"v2\"".to_string()),
+            ranges: true,
+            ..Default::default()
+        });
+        let tmp = TempDir::new().unwrap();
+        write_partial(&tmp, &old_body, 50_000, &server.url, "\"v1\"");
+        download_from(&server, &tmp, &md5).unwrap();
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_download_restarts_partial_file_from_another_url() {
+        let (old_body, _) = tarball(100_000);
+        let (body, md5) = tarball(200_000);
+        let server = TestServer::start(Behaviour {
+            body,
+            etag: Some("\"v1\"".to_string()),
+            ranges: true,
+            ..Default::default()
+        });
+        let tmp = TempDir::new().unwrap();
+        let mirror = "http://mirror.example.com/db.tar.gz";
+        write_partial(&tmp, &old_body, 50_000, mirror, "\"v1\"");
+        download_from(&server, &tmp, &md5).unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(!requests[0].contains("range:"));
+    }
+
+    #[test]
+    fn test_download_retries_server_errors() {
+        let (body, md5) = tarball(200_000);
+        let server = TestServer::start(Behaviour {
+            body,
+            fail_first: 2,
+            no_content_length: true,
+            ..Default::default()
+        });
+        let tmp = TempDir::new().unwrap();
+        download_from(&server, &tmp, &md5).unwrap();
+        // Without a content length, the download is only trusted once it is the same length twice
+        assert_eq!(server.requests().len(), 4);
+    }
+
+    #[test]
+    fn test_download_without_content_length_resumes_after_dropped_connection() {
+        let (body, md5) = tarball(200_000);
+        let server = TestServer::start(Behaviour {
+            body,
+            etag: Some("\"v1\"".to_string()),
+            ranges: true,
+            no_content_length: true,
+            drop_after: Some(50_000),
+            ..Default::default()
+        });
+        let tmp = TempDir::new().unwrap();
+        download_from(&server, &tmp, &md5).unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("\nrange: bytes=50000-"));
+    }
+
+    #[test]
+    fn test_download_without_content_length_or_ranges() {
+        let (body, md5) = tarball(200_000);
+        let server = TestServer::start(Behaviour {
+            body,
+            no_content_length: true,
+            drop_after: Some(50_000),
+            ..Default::default()
+        });
+        let tmp = TempDir::new().unwrap();
+        download_from(&server, &tmp, &md5).unwrap();
+        assert_eq!(server.requests().len(), 3);
+
+        let (body, _) = tarball(200_000);
+        let length = body.len() as u64;
+        let server = TestServer::start(Behaviour {
+            body,
+            no_content_length: true,
+            drop_after: Some(50_000),
+            ..Default::default()
+        });
+        let tmp = TempDir::new().unwrap();
+        let output = tmp.path().join("HPRC").join("r1");
+        let policy = RetryPolicy {
+            attempts: 2,
+            ..fast_retries()
+        };
+        let err = download_and_extract_tarball_with_retries(
+            std::slice::from_ref(&server.url),
+            &output,
+            "foo",
+            policy,
+        )
+        .unwrap_err();
+        assert!(matches!(err, DownloadError::Unverified));
+        // The partial download is kept to be checked next time
+        assert_eq!(
+            fs::metadata(tmp.path().join("HPRC/r1.tar.gz.part"))
+                .unwrap()
+                .len(),
+            length
+        );
+    }
+
+    #[test]
+    fn test_download_gives_up() {
+        let server = TestServer::start(Behaviour {
+            fail_first: 10,
+            ..Default::default()
+        });
+        let tmp = TempDir::new().unwrap();
+        let err = download_from(&server, &tmp, "foo").unwrap_err();
+        assert!(matches!(
+            err,
+            DownloadError::HttpStatus(StatusCode::SERVICE_UNAVAILABLE)
+        ));
+        assert_eq!(server.requests().len(), 3);
+
+        let server = TestServer::start(Behaviour {
+            not_found: true,
+            ..Default::default()
+        });
+        let err = download_from(&server, &tmp, "foo").unwrap_err();
+        assert!(matches!(err, DownloadError::DownloadFailed));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_download_md5_mismatch_starts_over() {
+        let (body, _) = tarball(200_000);
+        let server = TestServer::start(Behaviour {
+            body,
+            ..Default::default()
+        });
+        let tmp = TempDir::new().unwrap();
+        let err = download_from(&server, &tmp, "foo").unwrap_err();
+        assert!(matches!(err, DownloadError::Md5Mismatch));
+        assert_eq!(fs::read_dir(tmp.path().join("HPRC")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_download_falls_back_to_mirrors() {
+        let (body, md5) = tarball(200_000);
+        let (stale, _) = tarball(1000);
+        let missing = TestServer::start(Behaviour {
+            not_found: true,
+            ..Default::default()
+        });
+        let outdated = TestServer::start(Behaviour {
+            body: stale,
+            ..Default::default()
+        });
+        let server = TestServer::start(Behaviour {
+            body,
+            ..Default::default()
+        });
+        let tmp = TempDir::new().unwrap();
+        let output = tmp.path().join("HPRC").join("r1");
+        let urls = [
+            missing.url.clone(),
+            outdated.url.clone(),
+            server.url.clone(),
+        ];
+        download_and_extract_tarball_with_retries(&urls, &output, &md5, fast_retries()).unwrap();
+        assert_eq!(
+            fs::metadata(output.join("db").join("hash.k2d"))
+                .unwrap()
+                .len(),
+            200_000
+        );
+        for server in [&missing, &outdated, &server] {
+            assert_eq!(server.requests().len(), 1);
+        }
+
+        let err =
+            download_and_extract_tarball_with_retries(&urls[..2], &output, &md5, fast_retries())
+                .unwrap_err();
+        assert!(matches!(err, DownloadError::Md5Mismatch));
+    }
+
+    #[test]
+    fn test_download_registry_falls_back_to_mirrors() {
+        // Nothing listens on a port that has just been freed
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/config.toml", listener.local_addr().unwrap())
+        };
+        let missing = TestServer::start(Behaviour {
+            not_found: true,
+            ..Default::default()
+        });
+        let server = TestServer::start(Behaviour {
+            body: BUNDLED_REGISTRY.as_bytes().to_vec(),
+            ..Default::default()
+        });
+
+        let registry =
+            download_registry(&[closed.clone(), missing.url.clone(), server.url.clone()]).unwrap();
+        assert_eq!(registry, Registry::parse(BUNDLED_REGISTRY).unwrap());
+        assert_eq!(missing.requests().len(), 1);
+        assert!(server.requests()[0].starts_with("get /db.tar.gz "));
+
+        let err = download_registry(&[closed, missing.url.clone()]).unwrap_err();
+        assert!(matches!(err, DownloadError::ConfigDownloadFailed));
+    }
+
+    #[test]
+    fn test_download_registry_from_file() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("config.toml");
+        fs::write(&path, BUNDLED_REGISTRY).unwrap();
+        let missing = Url::from_file_path(tmp.path().join("missing.toml")).unwrap();
+        let local = Url::from_file_path(&path).unwrap();
+
+        let registry = download_registry(&[missing.to_string(), local.to_string()]).unwrap();
+        assert_eq!(registry, Registry::parse(BUNDLED_REGISTRY).unwrap());
+    }
+
+    fn registry_for(md5: &str) -> Registry {
+        Registry::parse(&format!(
+            "[[database]]\nname = \"HPRC\"\nversion = \"r1\"\nurl = \"https://example.com/r1.tar.gz\"\nmd5 = \"{}\"\n",
+            md5
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_install_from_tarball() {
+        let (body, md5) = tarball(1000);
+        let tmp = TempDir::new().unwrap();
+        let source = tmp.path().join("k2_HPRC.tar.gz");
+        fs::write(&source, body).unwrap();
+        let store = DatabaseStore::new(tmp.path().join("store"));
+
+        let err = install_from_file(&store, &source, &registry_for("foo"), None, None).unwrap_err();
+        assert!(matches!(err, DownloadError::Md5Mismatch));
+
+        let registry = registry_for(&md5);
+        let installed = install_from_file(&store, &source, &registry, None, Some("r1")).unwrap();
+        assert_eq!(installed.entry, registry.databases[0]);
+        assert_eq!(installed.path, store.root().join("HPRC").join("r1"));
+        assert_eq!(store.get("HPRC", "r1").unwrap().files.len(), 1);
+        assert!(source.exists());
+
+        let err = install_from_file(&store, &source, &registry, None, None).unwrap_err();
+        assert!(matches!(err, DownloadError::AlreadyInstalled(_)));
+    }
+
+    #[test]
+    fn test_install_from_directory() {
+        let tmp = TempDir::new().unwrap();
+        let source = tmp.path().join("k2_HPRC");
+        fs::create_dir_all(source.join("db")).unwrap();
+        let store = DatabaseStore::new(tmp.path().join("store"));
+        let registry = registry_for("foo");
+
+        let err = install_from_file(&store, &source, &registry, None, None).unwrap_err();
+        assert!(matches!(err, DownloadError::NotADatabase(_)));
+
+        for file in ["hash.k2d", "opts.k2d", "taxo.k2d"] {
+            fs::write(source.join("db").join(file), file).unwrap();
+        }
+        let installed = install_from_file(&store, &source, &registry, Some("HPRC"), None).unwrap();
+        assert_eq!(installed.database_dir().unwrap(), installed.path.join("db"));
+        assert_eq!(
+            fs::read_to_string(installed.path.join("db/taxo.k2d")).unwrap(),
+            "taxo.k2d"
+        );
+        assert_eq!(store.find(None, None).unwrap(), installed);
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 100-199/200"),
+            Some((100, Some(200)))
+        );
+        assert_eq!(parse_content_range("bytes 0-99/*"), Some((0, None)));
+        assert_eq!(parse_content_range("bytes */200"), None);
+        assert_eq!(parse_content_range("100-199/200"), None);
+    }
+
     #[test]
     fn test_compute_md5() {
         // path to the repository's LICENSE file
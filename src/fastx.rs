@@ -0,0 +1,365 @@
+use niffler::compression::Format;
+use niffler::error::Error as NifflerError;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum FastxError {
+    #[error("Invalid FASTA/FASTQ record (line {line}): {reason}")]
+    InvalidRecord { line: usize, reason: String },
+
+    #[error("{} has the extension .{extension} but is {detected}", path.display())]
+    CompressionMismatch {
+        path: PathBuf,
+        extension: String,
+        detected: &'static str,
+    },
+
+    #[error("{} is not a FASTA/FASTQ file, or is compressed in an unsupported format", path.display())]
+    NotFastx { path: PathBuf },
+
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+
+    #[error(transparent)]
+    NifflerError(#[from] NifflerError),
+}
+
+/// Detect the compression of a FASTA/FASTQ file from its first bytes (its "magic number").
+///
+/// The file's extension is only used as a check: it is an error for it to name a different
+/// compression format, or for the (decompressed) content not to look like FASTA/FASTQ.
+pub fn detect_compression(path: &Path) -> Result<Format, FastxError> {
+    let mut magic = Vec::with_capacity(5);
+    File::open(path)?.take(5).read_to_end(&mut magic)?;
+    // A file too short to hold a magic number can't be compressed
+    let format = if magic.len() == 5 {
+        niffler::sniff(Box::new(io::Cursor::new(magic)))?.1
+    } else {
+        Format::No
+    };
+
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default();
+    let expected = match extension {
+        "gz" | "bgz" => Some(Format::Gzip),
+        "bz2" => Some(Format::Bzip),
+        "xz" | "lzma" => Some(Format::Lzma),
+        "zst" | "zstd" => Some(Format::Zstd),
+        _ => None,
+    };
+    if expected.is_some_and(|expected| expected != format) {
+        return Err(FastxError::CompressionMismatch {
+            path: path.to_path_buf(),
+            extension: extension.to_string(),
+            detected: compression_name(format),
+        });
+    }
+
+    let reader: Box<dyn Read> = match format {
+        Format::No => Box::new(File::open(path)?),
+        _ => niffler::from_path(path)?.0,
+    };
+    let mut start = Vec::new();
+    reader.take(1024).read_to_end(&mut start)?;
+    match start.iter().find(|byte| !byte.is_ascii_whitespace()) {
+        None | Some(b'@') | Some(b'>') => Ok(format),
+        Some(_) => Err(FastxError::NotFastx {
+            path: path.to_path_buf(),
+        }),
+    }
+}
+
+/// A description of a compression format for messages
+pub fn compression_name(format: Format) -> &'static str {
+    match format {
+        Format::Gzip => "gzip-compressed",
+        Format::Bzip => "bzip2-compressed",
+        Format::Lzma => "xz-compressed",
+        Format::Zstd => "zstd-compressed",
+        Format::No => "uncompressed",
+    }
+}
+
+/// A FASTA or FASTQ record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// The header line without the leading '>' or '@'
+    pub header: Vec<u8>,
+    pub seq: Vec<u8>,
+    /// Quality scores; `None` for FASTA records
+    pub qual: Option<Vec<u8>>,
+}
+
+impl Record {
+    /// The read ID, i.e., the header up to the first whitespace
+    pub fn id(&self) -> &[u8] {
+        let end = self
+            .header
+            .iter()
+            .position(u8::is_ascii_whitespace)
+            .unwrap_or(self.header.len());
+        &self.header[..end]
+    }
+
+    /// The read ID without a `/1` or `/2` mate suffix, which is the same for both mates of a pair
+    pub fn pair_name(&self) -> &[u8] {
+        let id = self.id();
+        match id {
+            [name @ .., b'/', b'1' | b'2'] => name,
+            _ => id,
+        }
+    }
+
+    /// Write the record in the format it was read in, with the sequence on a single line
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match &self.qual {
+            Some(qual) => {
+                writer.write_all(b"@")?;
+                writer.write_all(&self.header)?;
+                writer.write_all(b"\n")?;
+                writer.write_all(&self.seq)?;
+                writer.write_all(b"\n+\n")?;
+                writer.write_all(qual)?;
+                writer.write_all(b"\n")
+            }
+            None => {
+                writer.write_all(b">")?;
+                writer.write_all(&self.header)?;
+                writer.write_all(b"\n")?;
+                writer.write_all(&self.seq)?;
+                writer.write_all(b"\n")
+            }
+        }
+    }
+}
+
+/// A streaming reader of FASTA (possibly multi-line) or FASTQ records
+pub struct Reader<R: BufRead> {
+    inner: R,
+    line: Vec<u8>,
+    line_number: usize,
+}
+
+impl Reader<BufReader<Box<dyn Read + Send>>> {
+    /// Open a (possibly compressed) FASTA/FASTQ file, detecting the compression format
+    pub fn from_path(path: &Path) -> Result<Self, FastxError> {
+        let (reader, _format) = niffler::send::from_path(path)?;
+        Ok(Self::new(BufReader::new(reader)))
+    }
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            line: Vec::new(),
+            line_number: 0,
+        }
+    }
+
+    /// Read the next line into `self.line` without its line ending. Returns false at EOF.
+    fn read_line(&mut self) -> io::Result<bool> {
+        self.line.clear();
+        if self.inner.read_until(b'\n', &mut self.line)? == 0 {
+            return Ok(false);
+        }
+        self.line_number += 1;
+        while matches!(self.line.last(), Some(b'\n') | Some(b'\r')) {
+            self.line.pop();
+        }
+        Ok(true)
+    }
+
+    /// The first byte of the next line, without consuming it
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.inner.fill_buf()?.first().copied())
+    }
+
+    fn invalid(&self, reason: &str) -> FastxError {
+        FastxError::InvalidRecord {
+            line: self.line_number,
+            reason: reason.to_string(),
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<Record>, FastxError> {
+        // Skip blank lines between records
+        loop {
+            if !self.read_line()? {
+                return Ok(None);
+            }
+            if !self.line.is_empty() {
+                break;
+            }
+        }
+
+        match self.line[0] {
+            b'@' => {
+                let header = self.line[1..].to_vec();
+                if !self.read_line()? {
+                    return Err(self.invalid("missing sequence"));
+                }
+                let seq = self.line.clone();
+                if !self.read_line()? || !self.line.starts_with(b"+") {
+                    return Err(self.invalid("missing '+' separator"));
+                }
+                if !self.read_line()? {
+                    return Err(self.invalid("missing quality"));
+                }
+                if self.line.len() != seq.len() {
+                    return Err(self.invalid("sequence and quality lengths differ"));
+                }
+                Ok(Some(Record {
+                    header,
+                    seq,
+                    qual: Some(self.line.clone()),
+                }))
+            }
+            b'>' => {
+                let header = self.line[1..].to_vec();
+                let mut seq = Vec::new();
+                while !matches!(self.peek()?, None | Some(b'>')) {
+                    self.read_line()?;
+                    seq.extend_from_slice(&self.line);
+                }
+                Ok(Some(Record {
+                    header,
+                    seq,
+                    qual: None,
+                }))
+            }
+            _ => Err(self.invalid("expected a header starting with '>' or '@'")),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<Record, FastxError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(data: &[u8]) -> Result<Vec<Record>, FastxError> {
+        Reader::new(data).collect()
+    }
+
+    #[test]
+    fn test_read_fastq() {
+        let records = read_all(b"@r1 desc\nACGT\n+\nIIII\n@r2\r\nGG\r\n+r2\r\n!!\r\n").unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].header, b"r1 desc");
+        assert_eq!(records[0].id(), b"r1");
+        assert_eq!(records[0].seq, b"ACGT");
+        assert_eq!(records[0].qual.as_deref(), Some(&b"IIII"[..]));
+        assert_eq!(records[1].id(), b"r2");
+        assert_eq!(records[1].seq, b"GG");
+    }
+
+    #[test]
+    fn test_pair_name() {
+        let records =
+            read_all(b"@r1/1 desc\nA\n+\nI\n@r1/2\nA\n+\nI\n@r1 1:N:0\nA\n+\nI\n@r1/3\nA\n+\nI\n")
+                .unwrap();
+        let names: Vec<&[u8]> = records.iter().map(Record::pair_name).collect();
+        assert_eq!(names, vec![&b"r1"[..], b"r1", b"r1", b"r1/3"]);
+    }
+
+    #[test]
+    fn test_read_multiline_fasta() {
+        let records = read_all(b">r1\nACGT\nTTTT\n\n>r2 x\nGG").unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].seq, b"ACGTTTTT");
+        assert!(records[0].qual.is_none());
+        assert_eq!(records[1].id(), b"r2");
+        assert_eq!(records[1].seq, b"GG");
+    }
+
+    #[test]
+    fn test_read_empty() {
+        assert!(read_all(b"").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_truncated_fastq() {
+        let err = read_all(b"@r1\nACGT\n+\n").unwrap_err();
+        assert!(matches!(err, FastxError::InvalidRecord { line: 3, .. }));
+    }
+
+    #[test]
+    fn test_read_quality_length_mismatch() {
+        assert!(read_all(b"@r1\nACGT\n+\nIII\n").is_err());
+    }
+
+    #[test]
+    fn test_read_not_fastx() {
+        assert!(read_all(b"hello world\n").is_err());
+    }
+
+    #[test]
+    fn test_detect_compression_from_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let gzipped_fq = tmp.path().join("reads.fastq");
+        let mut writer = niffler::to_path(&gzipped_fq, Format::Gzip, niffler::Level::One).unwrap();
+        writer.write_all(b"@r1\nACGT\n+\nIIII\n").unwrap();
+        drop(writer);
+        assert_eq!(detect_compression(&gzipped_fq).unwrap(), Format::Gzip);
+
+        let plain = tmp.path().join("reads.fa");
+        std::fs::write(&plain, ">r1\nACGT\n").unwrap();
+        assert_eq!(detect_compression(&plain).unwrap(), Format::No);
+
+        let empty = tmp.path().join("empty.fq");
+        std::fs::write(&empty, "").unwrap();
+        assert_eq!(detect_compression(&empty).unwrap(), Format::No);
+    }
+
+    #[test]
+    fn test_detect_compression_mismatched_extension() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("reads.fq.gz");
+        std::fs::write(&path, "@r1\nACGT\n+\nIIII\n").unwrap();
+        let err = detect_compression(&path).unwrap_err();
+        assert!(matches!(
+            err,
+            FastxError::CompressionMismatch {
+                detected: "uncompressed",
+                ..
+            }
+        ));
+        assert!(err
+            .to_string()
+            .ends_with("has the extension .gz but is uncompressed"));
+    }
+
+    #[test]
+    fn test_detect_compression_not_fastx() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("reads.fq");
+        std::fs::write(&path, "PK\x03\x04 not reads").unwrap();
+        assert!(matches!(
+            detect_compression(&path).unwrap_err(),
+            FastxError::NotFastx { .. }
+        ));
+    }
+
+    #[test]
+    fn test_write_round_trip() {
+        let data = b"@r1 desc\nACGT\n+\nIIII\n>r2\nGG\n";
+        let mut out = Vec::new();
+        for record in read_all(data).unwrap() {
+            record.write(&mut out).unwrap();
+        }
+        assert_eq!(out, data);
+    }
+}
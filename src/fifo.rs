@@ -0,0 +1,332 @@
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// A named pipe (FIFO) that an external process writes into while nohuman reads from it.
+///
+/// Both ends are opened when the pipe is created: the read end is handed to whoever consumes
+/// the data, and nohuman holds a write end open until [`NamedPipe::close_writer`] is called. This
+/// means the reader never sees end-of-file before the external process has opened the pipe, and
+/// never blocks forever if the process exits (or crashes) without ever opening it.
+pub struct NamedPipe {
+    path: PathBuf,
+    reader: Option<File>,
+    keepalive: Option<File>,
+}
+
+impl NamedPipe {
+    /// Create a new FIFO at `path` and open both of its ends.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        make_fifo(path)?;
+
+        // Opening the read end without O_NONBLOCK would block until a writer appears
+        let reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+        // There is now a reader, so opening the write end does not block
+        let keepalive = OpenOptions::new().write(true).open(path)?;
+        set_blocking(&reader)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            reader: Some(reader),
+            keepalive: Some(keepalive),
+        })
+    }
+
+    /// The location of the FIFO on disk, to be given to the writing process.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Take the read end of the pipe. Returns `None` if it has already been taken.
+    pub fn take_reader(&mut self) -> Option<File> {
+        self.reader.take()
+    }
+
+    /// Close nohuman's write end, so the reader sees end-of-file once the external process has
+    /// also closed the pipe.
+    pub fn close_writer(&mut self) {
+        self.keepalive = None;
+    }
+}
+
+/// A named pipe (FIFO) that nohuman writes into while another process reads from it, e.g., to
+/// give a classifier reads that arrive on stdin.
+///
+/// Clones share the pipe, so one thread can wait in [`FeedPipe::open_writer`] while another calls
+/// [`FeedPipe::close`] to release it.
+#[derive(Clone)]
+pub struct FeedPipe {
+    path: PathBuf,
+    state: Arc<Mutex<FeedState>>,
+}
+
+#[derive(Default)]
+struct FeedState {
+    opening: bool,
+    closed: bool,
+    /// The read end opened by [`FeedPipe::close`] to release a writer waiting for a reader
+    release: Option<File>,
+}
+
+impl FeedPipe {
+    /// Create a new FIFO at `path`. Neither end is opened.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        make_fifo(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Arc::default(),
+        })
+    }
+
+    /// The location of the FIFO on disk, to be given to the reading process.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Open the write end. This waits until the pipe is opened for reading, and fails with a broken
+    /// pipe if the pipe is closed first.
+    pub fn open_writer(&self) -> io::Result<File> {
+        let mut state = self.lock();
+        if state.closed {
+            return Err(closed());
+        }
+        state.opening = true;
+        drop(state);
+
+        let file = OpenOptions::new().write(true).open(&self.path);
+        let mut state = self.lock();
+        state.opening = false;
+        if state.closed {
+            return Err(closed());
+        }
+        file
+    }
+
+    /// Stop the pipe from being opened for writing, e.g., once the reading process has failed. A
+    /// writer waiting in [`FeedPipe::open_writer`] for a reader that is never coming is released
+    /// and fails with a broken pipe.
+    pub fn close(&self) -> io::Result<()> {
+        let mut state = self.lock();
+        state.closed = true;
+        if state.opening && state.release.is_none() {
+            // The read end is kept open until the pipe is dropped, as the writer may not have
+            // reached its `open` yet
+            state.release = Some(
+                OpenOptions::new()
+                    .read(true)
+                    .custom_flags(libc::O_NONBLOCK)
+                    .open(&self.path)?,
+            );
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, FeedState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "The named pipe was closed")
+}
+
+/// A file whose reads can be interrupted from another thread with its [`Interrupter`], e.g., to
+/// stop waiting for reads on stdin once nothing more is wanted from them
+pub struct Interruptible {
+    inner: File,
+    cancel: File,
+}
+
+/// Interrupts the reads of an [`Interruptible`] file when it is dropped
+pub struct Interrupter {
+    _signal: File,
+}
+
+impl Interrupter {
+    /// Make every read of the [`Interruptible`] file, including one waiting for data, fail with a
+    /// broken pipe
+    pub fn interrupt(self) {}
+}
+
+/// Make the reads of `file` interruptible
+pub fn interruptible(file: File) -> io::Result<(Interruptible, Interrupter)> {
+    let (cancel, signal) = pipe()?;
+    Ok((
+        Interruptible {
+            inner: file,
+            cancel,
+        },
+        Interrupter { _signal: signal },
+    ))
+}
+
+impl Read for Interruptible {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // The cancel pipe hangs up when the interrupter is dropped
+        let mut fds = [self.inner.as_raw_fd(), self.cancel.as_raw_fd()].map(|fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        loop {
+            // SAFETY: `fds` holds two valid pollfd structs, for descriptors owned by `self`
+            if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if fds[1].revents != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "Reading was interrupted",
+                ));
+            }
+            if fds[0].revents != 0 {
+                return self.inner.read(buf);
+            }
+        }
+    }
+}
+
+/// Create an anonymous pipe, returning its read and write ends
+pub fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two file descriptors `pipe` writes
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `pipe` succeeded, so both descriptors are open and owned by nothing else
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+fn make_fifo(path: &Path) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `c_path` is a valid, NUL-terminated C string
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Clear `O_NONBLOCK` so reads wait for data rather than returning `WouldBlock`
+fn set_blocking(file: &File) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    // SAFETY: `fd` is an open file descriptor owned by `file`
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: as above
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_pipe_passes_data_through() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut pipe = NamedPipe::create(&tmp.path().join("reads.fq")).unwrap();
+        let mut reader = pipe.take_reader().unwrap();
+
+        let path = pipe.path().to_path_buf();
+        let writer = std::thread::spawn(move || {
+            let mut file = OpenOptions::new().write(true).open(path).unwrap();
+            file.write_all(b"@read1\nACGT\n+\nIIII\n").unwrap();
+        });
+        writer.join().unwrap();
+        pipe.close_writer();
+
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "@read1\nACGT\n+\nIIII\n");
+    }
+
+    #[test]
+    fn test_pipe_never_opened_by_writer_is_empty() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut pipe = NamedPipe::create(&tmp.path().join("reads.fq")).unwrap();
+        let mut reader = pipe.take_reader().unwrap();
+        assert!(pipe.take_reader().is_none());
+        pipe.close_writer();
+
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).unwrap();
+        assert!(contents.is_empty());
+    }
+
+    #[test]
+    fn test_feed_pipe_passes_data_through() {
+        let tmp = tempfile::tempdir().unwrap();
+        let pipe = FeedPipe::create(&tmp.path().join("input.fq")).unwrap();
+        let path = pipe.path().to_path_buf();
+        let reader = std::thread::spawn(move || std::fs::read_to_string(path).unwrap());
+
+        let mut writer = pipe.open_writer().unwrap();
+        writer.write_all(b"@read1\nACGT\n+\nIIII\n").unwrap();
+        drop(writer);
+        assert_eq!(reader.join().unwrap(), "@read1\nACGT\n+\nIIII\n");
+    }
+
+    #[test]
+    fn test_feed_pipe_close_releases_writer() {
+        let tmp = tempfile::tempdir().unwrap();
+        let pipe = FeedPipe::create(&tmp.path().join("input.fq")).unwrap();
+        let feed = pipe.clone();
+        let writer = std::thread::spawn(move || feed.open_writer());
+        pipe.close().unwrap();
+        let err = writer.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(pipe.open_writer().is_err());
+    }
+
+    #[test]
+    fn test_interruptible() {
+        let (reader, mut writer) = pipe().unwrap();
+        let (mut reader, interrupter) = interruptible(reader).unwrap();
+        writer.write_all(b"ACGT").unwrap();
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ACGT");
+
+        // The writer is still open, so the read waits until it is interrupted
+        let blocked = std::thread::spawn(move || reader.read(&mut buf));
+        interrupter.interrupt();
+        let err = blocked.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_anonymous_pipe() {
+        let (mut reader, mut writer) = pipe().unwrap();
+        writer.write_all(b"ACGT").unwrap();
+        drop(writer);
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "ACGT");
+    }
+
+    #[test]
+    fn test_create_fails_if_path_exists() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("reads.fq");
+        std::fs::write(&path, "").unwrap();
+        assert!(NamedPipe::create(&path).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+use crate::fastx::{FastxError, Reader, Record};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use thiserror::Error;
+
+/// Records are passed between threads in chunks of this many
+pub(crate) const CHUNK_SIZE: usize = 1024;
+
+#[derive(Error, Debug)]
+pub enum InterleaveError {
+    #[error("Interleaved input ends with an unpaired read: {0}")]
+    UnpairedRecord(String),
+
+    #[error("The two mate files have different numbers of reads")]
+    UnequalPairs,
+
+    #[error("The mates of pair {pair} have different names ({mate1} and {mate2}); is the input interleaved?")]
+    MateMismatch {
+        pair: usize,
+        mate1: String,
+        mate2: String,
+    },
+
+    #[error(transparent)]
+    FastxError(#[from] FastxError),
+
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// Split interleaved reads (mate 1, mate 2, mate 1, ...) into two outputs, one per mate, and
+/// return the number of pairs. The mates of each pair must have the same name, ignoring any `/1`
+/// and `/2` suffixes.
+///
+/// Each output is opened and written on its own thread, with the reads for it buffered in between.
+/// The outputs can therefore be FIFOs whose opening waits for a reader, read by a process that
+/// reads a large block from one before reading from the other (as kraken2 does).
+pub fn deinterleave<R, W, F>(reader: Reader<R>, outputs: [F; 2]) -> Result<usize, InterleaveError>
+where
+    R: BufRead,
+    W: Write,
+    F: FnOnce() -> io::Result<W> + Send,
+{
+    std::thread::scope(|scope| {
+        let mut senders = Vec::new();
+        let mut handles = Vec::new();
+        for open in outputs {
+            let (sender, receiver) = mpsc::channel();
+            senders.push(sender);
+            handles.push(scope.spawn(move || write_chunks(receiver, open)));
+        }
+        let result = split(reader, &senders);
+        drop(senders);
+        let written: Vec<io::Result<()>> = handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Deinterleave thread panicked"))
+            .collect();
+        let written: io::Result<()> = written.into_iter().collect();
+
+        // A writer that fails stops taking reads, so its error is the root cause of any failure to
+        // hand it more. Otherwise, input that can't be split is the root cause of any writer
+        // failing, e.g., because whoever reads the output gave up once it ended early.
+        match result {
+            Err(InterleaveError::IoError(e)) if e.kind() == io::ErrorKind::BrokenPipe => {
+                written?;
+                Err(e.into())
+            }
+            Err(e) => Err(e),
+            Ok(pairs) => written.map(|_| pairs).map_err(Into::into),
+        }
+    })
+}
+
+/// Merge two inputs of mates into one interleaved output, and return the number of pairs. As for
+/// [`deinterleave`], the mates of each pair must have the same name.
+///
+/// Each input is read on its own thread, so the inputs can be pipes from a process that writes a
+/// large block to one before writing to the other.
+pub fn interleave<R: Read + Send, W: Write>(
+    inputs: [R; 2],
+    output: W,
+) -> Result<usize, InterleaveError> {
+    std::thread::scope(|scope| {
+        let mut receivers = Vec::new();
+        let mut handles = Vec::new();
+        for input in inputs {
+            let (sender, receiver) = mpsc::channel();
+            receivers.push(receiver);
+            handles.push(scope.spawn(move || read_chunks(input, sender)));
+        }
+        let result = merge(&receivers, output);
+        drop(receivers);
+
+        // An input that can't be parsed ends early, which would otherwise be reported as unequal pairs
+        for handle in handles {
+            handle.join().expect("Interleave thread panicked")?;
+        }
+        result
+    })
+}
+
+fn split<R: BufRead>(
+    mut reader: Reader<R>,
+    senders: &[Sender<Vec<Record>>],
+) -> Result<usize, InterleaveError> {
+    let mut pairs = 0;
+    let mut chunks = [
+        Vec::with_capacity(CHUNK_SIZE),
+        Vec::with_capacity(CHUNK_SIZE),
+    ];
+    while let Some(mate1) = reader.next().transpose()? {
+        let Some(mate2) = reader.next().transpose()? else {
+            return Err(InterleaveError::UnpairedRecord(
+                String::from_utf8_lossy(mate1.id()).to_string(),
+            ));
+        };
+        pairs += 1;
+        check_mates(pairs, &mate1, &mate2)?;
+        chunks[0].push(mate1);
+        chunks[1].push(mate2);
+        if chunks[0].len() == CHUNK_SIZE {
+            send_chunks(&mut chunks, senders)?;
+        }
+    }
+    send_chunks(&mut chunks, senders)?;
+    Ok(pairs)
+}
+
+fn send_chunks(chunks: &mut [Vec<Record>; 2], senders: &[Sender<Vec<Record>>]) -> io::Result<()> {
+    for (chunk, sender) in chunks.iter_mut().zip(senders) {
+        if !chunk.is_empty() {
+            sender
+                .send(std::mem::take(chunk))
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_chunks<W: Write, F: FnOnce() -> io::Result<W>>(
+    receiver: Receiver<Vec<Record>>,
+    open: F,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(open()?);
+    for chunk in receiver {
+        for record in chunk {
+            record.write(&mut writer)?;
+        }
+    }
+    writer.flush()
+}
+
+/// Parse the records of `input` and send them in chunks, stopping early if the receiver hangs up
+pub(crate) fn read_chunks<R: Read>(
+    input: R,
+    sender: Sender<Vec<Record>>,
+) -> Result<(), FastxError> {
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    for record in Reader::new(BufReader::new(input)) {
+        chunk.push(record?);
+        if chunk.len() == CHUNK_SIZE && sender.send(std::mem::take(&mut chunk)).is_err() {
+            // The receiver has stopped and will report why
+            return Ok(());
+        }
+    }
+    if !chunk.is_empty() {
+        let _ = sender.send(chunk);
+    }
+    Ok(())
+}
+
+fn merge<W: Write>(
+    receivers: &[Receiver<Vec<Record>>],
+    output: W,
+) -> Result<usize, InterleaveError> {
+    let mut writer = BufWriter::new(output);
+    let mut buffers: [std::vec::IntoIter<Record>; 2] = Default::default();
+    let mut pairs = 0;
+    loop {
+        let mut mates = buffers
+            .iter_mut()
+            .zip(receivers)
+            .map(|(buffer, receiver)| next_record(buffer, receiver));
+        match (mates.next().flatten(), mates.next().flatten()) {
+            (Some(mate1), Some(mate2)) => {
+                pairs += 1;
+                check_mates(pairs, &mate1, &mate2)?;
+                mate1.write(&mut writer)?;
+                mate2.write(&mut writer)?;
+            }
+            (None, None) => break,
+            _ => return Err(InterleaveError::UnequalPairs),
+        }
+    }
+    writer.flush()?;
+    Ok(pairs)
+}
+
+/// Check that the mates of the `pair`th pair belong together
+fn check_mates(pair: usize, mate1: &Record, mate2: &Record) -> Result<(), InterleaveError> {
+    if mate1.pair_name() == mate2.pair_name() {
+        Ok(())
+    } else {
+        Err(InterleaveError::MateMismatch {
+            pair,
+            mate1: String::from_utf8_lossy(mate1.id()).to_string(),
+            mate2: String::from_utf8_lossy(mate2.id()).to_string(),
+        })
+    }
+}
+
+/// The next record from an input, receiving another chunk once `buffer` is used up
+pub(crate) fn next_record(
+    buffer: &mut std::vec::IntoIter<Record>,
+    receiver: &Receiver<Vec<Record>>,
+) -> Option<Record> {
+    loop {
+        if let Some(record) = buffer.next() {
+            return Some(record);
+        }
+        *buffer = receiver.recv().ok()?.into_iter();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fastq(ids: &[&str]) -> String {
+        ids.iter()
+            .map(|id| format!("@{}\nACGT\n+\nIIII\n", id))
+            .collect()
+    }
+
+    #[test]
+    fn test_deinterleave_and_interleave_round_trip() {
+        let input = fastq(&["r1/1", "r1/2", "r2/1", "r2/2"]);
+        let (mut mate1, mut mate2) = (Vec::new(), Vec::new());
+        let pairs = deinterleave(
+            Reader::new(input.as_bytes()),
+            [&mut mate1, &mut mate2].map(|out| move || Ok(out)),
+        )
+        .unwrap();
+        assert_eq!(pairs, 2);
+        assert_eq!(String::from_utf8_lossy(&mate1), fastq(&["r1/1", "r2/1"]));
+        assert_eq!(String::from_utf8_lossy(&mate2), fastq(&["r1/2", "r2/2"]));
+
+        let mut merged = Vec::new();
+        assert_eq!(
+            interleave([&mate1[..], &mate2[..]], &mut merged).unwrap(),
+            2
+        );
+        assert_eq!(String::from_utf8_lossy(&merged), input);
+    }
+
+    #[test]
+    fn test_deinterleave_many_chunks() {
+        let ids: Vec<String> = (0..CHUNK_SIZE * 3)
+            .map(|i| format!("r{}/{}", i / 2, i % 2 + 1))
+            .collect();
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let (mut mate1, mut mate2) = (Vec::new(), Vec::new());
+        let pairs = deinterleave(
+            Reader::new(fastq(&ids).as_bytes()),
+            [&mut mate1, &mut mate2].map(|out| move || Ok(out)),
+        )
+        .unwrap();
+        assert_eq!(pairs, CHUNK_SIZE * 3 / 2);
+        assert_eq!(Reader::new(&mate2[..]).count(), pairs);
+    }
+
+    #[test]
+    fn test_deinterleave_odd_number_of_reads() {
+        let input = fastq(&["r1/1", "r1/2", "r2/1"]);
+        let err = deinterleave(
+            Reader::new(input.as_bytes()),
+            [0, 1].map(|_| || Ok(io::sink())),
+        )
+        .unwrap_err();
+        assert!(matches!(err, InterleaveError::UnpairedRecord(id) if id == "r2/1"));
+    }
+
+    #[test]
+    fn test_deinterleave_mismatched_mates() {
+        let input = fastq(&["r1/1", "r1/2", "r2/1", "r3/1", "r2/2", "r3/2"]);
+        let err = deinterleave(
+            Reader::new(input.as_bytes()),
+            [0, 1].map(|_| || Ok(io::sink())),
+        )
+        .unwrap_err();
+        assert!(matches!(err, InterleaveError::MateMismatch { pair: 2, .. }));
+        assert!(err
+            .to_string()
+            .starts_with("The mates of pair 2 have different names (r2/1 and r3/1)"));
+    }
+
+    #[test]
+    fn test_interleave_mismatched_mates() {
+        let (mate1, mate2) = (fastq(&["r1/1", "r2/1"]), fastq(&["r1/2", "r3/2"]));
+        let err = interleave([mate1.as_bytes(), mate2.as_bytes()], io::sink()).unwrap_err();
+        assert!(matches!(err, InterleaveError::MateMismatch { pair: 2, .. }));
+    }
+
+    #[test]
+    fn test_interleave_unequal_inputs() {
+        let (mate1, mate2) = (fastq(&["r1/1", "r2/1"]), fastq(&["r1/2"]));
+        let err = interleave([mate1.as_bytes(), mate2.as_bytes()], io::sink()).unwrap_err();
+        assert!(matches!(err, InterleaveError::UnequalPairs));
+    }
+
+    #[test]
+    fn test_interleave_reports_invalid_input() {
+        let (mate1, mate2) = (fastq(&["r1/1"]), "not a fastq file\n".to_string());
+        let err = interleave([mate1.as_bytes(), mate2.as_bytes()], io::sink()).unwrap_err();
+        assert!(matches!(err, InterleaveError::FastxError(_)));
+    }
+}
@@ -1,15 +1,35 @@
+pub mod classify;
+pub mod db;
 pub mod download;
+pub mod fastx;
+pub mod fifo;
+pub mod interleave;
+pub mod mask;
+pub mod metrics;
+pub mod multiqc;
+pub mod pipeline;
+pub mod registry;
+pub mod removed;
+pub mod report;
+pub mod samplesheet;
+pub mod sources;
+
+pub use pipeline::{Pipeline, RunReport};
 
-use serde::Deserialize;
 use std::ffi::OsStr;
-use std::io::{self, Write, BufReader, BufWriter};
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Output, Stdio};
 use std::fs::File;
 use serde::Serialize;
 use anyhow::{Context, Result};
-use serde_json;
+use log::warn;
+use thiserror::Error;
 use rayon::prelude::*;
+use metrics::{InputCounts, LengthSummary, PairCounts, ReadMetrics, Timings};
+use report::ReportSummary;
 
 use niffler::{get_writer, compression, from_path, error::Error as NifflerError};
 use gzp::{deflate::Gzip, ZBuilder};
@@ -17,59 +37,282 @@ use gzp::deflate::Bgzf;
 use zstd::stream::Encoder as ZstdEncoder;
 use liblzma::write::XzEncoder;
 
-/// Wrapper function to simplify running of different compression functions
-pub fn write_output(
-    tmpout1: &PathBuf,
-    tmpout2: Option<&PathBuf>, // Optional second output file
-    out1: &PathBuf,
-    out2: Option<&PathBuf>,    // Optional second output file
-    compression_threads: usize // The number of threads for compression
-) -> Result<(), anyhow::Error> {
-    let mut niffler_pairs = Vec::new(); // Store niffler compression pairs
-
-    // Handle out1 based on its compression type
-    let compression_type1 = determine_compression_type(out1);
-    match compression_type1.as_str() {
-        "gz" | "bgz" => write_with_gzp(tmpout1, out1, compression_threads)?,
-        "zst" | "zstd" => write_with_zstd(tmpout1, out1, compression_threads)?,
-        "xz" | "lzma" => write_with_liblzma(tmpout1, out1, compression_threads, 6)?,
-        "no" | "bz2" => {
-            // Collect niffler pairs for out1
-            niffler_pairs.push((vec![tmpout1.clone()], vec![out1.clone()]));
+/// The engine used to classify reads as human or non-human
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// Run the `kraken2` executable
+    Kraken2,
+    /// Use nohuman's built-in classifier, an implementation of kraken2's classification that doesn't need it installed
+    Native,
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backend::Kraken2 => write!(f, "kraken2"),
+            Backend::Native => write!(f, "native"),
+        }
+    }
+}
+
+/// Which reads to write out once they have been classified
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Keep {
+    /// Write only the non-human (unclassified) reads
+    Nonhuman,
+    /// Write only the human (classified) reads
+    Human,
+    /// Write the non-human and human reads to separate outputs
+    Both,
+}
+
+impl Keep {
+    /// Whether the non-human reads are written
+    pub fn nonhuman(&self) -> bool {
+        matches!(self, Keep::Nonhuman | Keep::Both)
+    }
+
+    /// Whether the human reads are written
+    pub fn human(&self) -> bool {
+        matches!(self, Keep::Human | Keep::Both)
+    }
+}
+
+/// How reads classified as human are masked when every read is written out
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum MaskMode {
+    /// Replace every base with N and every quality score with the lowest ('!')
+    N,
+    /// Reduce the read to a single N, keeping only its name
+    Stub,
+}
+
+/// The path that stands for stdin when given as an input, and stdout when given as an output
+pub const STDIO: &str = "-";
+
+/// Whether `path` stands for stdin/stdout rather than a file
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == STDIO
+}
+
+/// The compression format of an output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// gzip
+    Gz,
+    /// Blocked gzip (BGZF)
+    Bgz,
+    /// Zstandard
+    Zst,
+    /// xz
+    Xz,
+    /// bzip2
+    Bz2,
+    /// Uncompressed
+    None,
+}
+
+impl OutputFormat {
+    /// The format implied by an output file's extension
+    pub fn from_path(path: &Path) -> Self {
+        match determine_compression_type(path).as_str() {
+            "gz" => OutputFormat::Gz,
+            "bgz" => OutputFormat::Bgz,
+            "zst" => OutputFormat::Zst,
+            "xz" | "lzma" => OutputFormat::Xz,
+            "bz2" => OutputFormat::Bz2,
+            _ => OutputFormat::None,
+        }
+    }
+
+    /// The file extension for the format, if it is compressed
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            OutputFormat::Gz => Some("gz"),
+            OutputFormat::Bgz => Some("bgz"),
+            OutputFormat::Zst => Some("zst"),
+            OutputFormat::Xz => Some("xz"),
+            OutputFormat::Bz2 => Some("bz2"),
+            OutputFormat::None => None,
+        }
+    }
+
+    /// The compression levels the format's codec accepts, if it is compressed
+    pub fn levels(&self) -> Option<RangeInclusive<u32>> {
+        match self {
+            OutputFormat::Gz | OutputFormat::Bgz | OutputFormat::Bz2 => Some(1..=9),
+            OutputFormat::Zst => Some(1..=22),
+            OutputFormat::Xz => Some(0..=9),
+            OutputFormat::None => None,
         }
-        _ => return Err(anyhow::anyhow!("Unsupported compression type: {}", compression_type1)),
-    }
-
-    // Handle out2 if it exists and is different from out1
-    if let Some(tmp2) = tmpout2 {
-        if let Some(out2) = out2 {
-            let compression_type2 = determine_compression_type(out2);
-            match compression_type2.as_str() {
-                "gz" | "bgz" => write_with_gzp(tmp2, out2, compression_threads)?,
-                "zst" | "zstd" => write_with_zstd(tmp2, out2, compression_threads)?,
-                "xz" | "lzma" => write_with_liblzma(tmp2, out2, compression_threads, 6)?,
-                "no" | "bz2" => {
-                    // Collect niffler pairs for out2
-                    niffler_pairs.push((vec![tmp2.clone()], vec![out2.clone()]));
-                }
-                _ => return Err(anyhow::anyhow!("Unsupported compression type: {}", compression_type2)),
+    }
+
+    /// The level used when none is given, which favours speed
+    pub fn default_level(&self) -> u32 {
+        match self {
+            OutputFormat::Gz | OutputFormat::Bgz | OutputFormat::Zst => 3,
+            OutputFormat::Xz => 6,
+            OutputFormat::Bz2 => 1,
+            OutputFormat::None => 0,
+        }
+    }
+
+    /// Check that `level` is accepted by the format's codec
+    pub fn check_level(&self, level: u32) -> Result<(), String> {
+        match self.levels() {
+            Some(levels) if levels.contains(&level) => Ok(()),
+            Some(levels) => Err(format!(
+                "compression level for {} must be between {} and {}, got {}",
+                self,
+                levels.start(),
+                levels.end(),
+                level
+            )),
+            None => Err("uncompressed output has no compression level".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.extension().unwrap_or("none"))
+    }
+}
+
+/// How output files are compressed
+#[derive(Clone, Copy, Debug)]
+pub struct OutputSettings {
+    /// Number of threads used to compress each output
+    pub threads: usize,
+    /// Compress every output in this format, whatever its extension
+    pub format: Option<OutputFormat>,
+    /// Compression level (defaults to [`OutputFormat::default_level`])
+    pub level: Option<u32>,
+}
+
+impl OutputSettings {
+    /// The format `output` is written in: the one given, else the one implied by its extension
+    /// (stdout is uncompressed)
+    pub fn format_for(&self, output: &Path) -> OutputFormat {
+        self.format.unwrap_or_else(|| {
+            if is_stdio(output) {
+                OutputFormat::None
+            } else {
+                OutputFormat::from_path(output)
             }
+        })
+    }
+}
+
+/// Replace any compression extension on `path` with the one for `format`
+/// e.g., "in.nohuman.fq.gz" with zstd -> "in.nohuman.fq.zst"
+pub fn with_compression_extension(path: &Path, format: OutputFormat) -> PathBuf {
+    let base = match OutputFormat::from_path(path) {
+        OutputFormat::None => path.to_path_buf(),
+        _ => path.with_extension(""),
+    };
+    match format.extension() {
+        Some(ext) => {
+            let mut path = base.into_os_string();
+            path.push(".");
+            path.push(ext);
+            PathBuf::from(path)
         }
+        None => base,
     }
+}
 
-    // Process niffler pairs in parallel (for no or bgz compression)
-    // These formats are handled differently since parallel compression
-    // of a single file is not possible, so
-    if !niffler_pairs.is_empty() {
-        niffler_pairs.into_par_iter().try_for_each(|(tmpout, out)| {
-            write_with_niffler(tmpout, out, 1) // Always use 1 thread per file
-        })?;
+/// Create an output file, or return stdout if `path` is [`STDIO`]
+fn create_output(path: &Path) -> io::Result<Box<dyn Write + Send>> {
+    if is_stdio(path) {
+        Ok(Box::new(io::stdout()))
+    } else {
+        Ok(Box::new(File::create(path)?))
     }
+}
 
-    Ok(())
+/// Build the default output path for an input file by inserting `tag` before the extension.
+///
+/// Any compression extension on the input is kept, so the output is compressed the same way.
+/// e.g., "input_1.fastq.gz" with tag "nohuman" -> "input_1.nohuman.fq.gz"
+pub fn default_output_path(input: &Path, tag: &str) -> PathBuf {
+    let parent = input.parent().unwrap_or_else(|| Path::new(""));
+    let ext = input.extension().and_then(OsStr::to_str).unwrap_or_default();
+    let fname = match ext {
+        "gz" | "bgz" | "bz2" | "xz" | "lzma" | "zst" | "zstd" => {
+            let no_ext = input.with_extension(""); // Strip compression extension
+            let stem = no_ext.file_stem().unwrap_or_default();
+            format!("{}.{}.fq.{}", stem.to_string_lossy(), tag, ext)
+        }
+        _ => format!("{}.{}.fq", input.file_stem().unwrap_or_default().to_string_lossy(), tag), // Uncompressed file
+    };
+    parent.join(fname)
+}
+
+/// Wrapper function to simplify running of different compression functions
+///
+/// Each input is compressed into the output at the same position on its own thread, so the
+/// inputs can be pipes that are being written to concurrently (e.g., kraken2's paired outputs).
+/// An output of [`STDIO`] is written to stdout.
+pub fn write_output<R: Read + Send>(
+    inputs: Vec<R>,
+    outputs: &[PathBuf],
+    settings: OutputSettings,
+) -> Result<(), anyhow::Error> {
+    if inputs.len() != outputs.len() {
+        return Err(anyhow::anyhow!(
+            "Expected {} inputs to compress, got {}",
+            outputs.len(),
+            inputs.len()
+        ));
+    }
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = inputs
+            .into_iter()
+            .zip(outputs)
+            .map(|(input, output)| {
+                scope.spawn(move || compress_output(input, output, settings))
+            })
+            .collect();
+
+        // Wait for every writer, even if one fails, so no thread is left reading from a pipe
+        let results: Vec<Result<()>> = handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Compression thread panicked"))
+            .collect();
+        results.into_iter().collect::<Result<()>>()
+    })
+}
+
+/// Compress a single input into `output` based on the output's compression type
+fn compress_output<R: Read>(
+    input: R,
+    output: &Path,
+    settings: OutputSettings,
+) -> Result<()> {
+    let format = settings.format_for(output);
+    let level = settings.level.unwrap_or_else(|| format.default_level());
+    if format != OutputFormat::None {
+        format.check_level(level).map_err(|e| anyhow::anyhow!(e))?;
+    }
+    let threads = settings.threads;
+    let result = match format {
+        OutputFormat::Gz | OutputFormat::Bgz => write_with_gzp(input, output, threads, format, level),
+        OutputFormat::Zst => write_with_zstd(input, output, threads, level as i32),
+        OutputFormat::Xz => write_with_liblzma(input, output, threads, level),
+        // Parallel compression of a single file is not possible for these formats
+        OutputFormat::Bz2 => write_with_niffler(input, output, compression::Format::Bzip, niffler_level(level)),
+        OutputFormat::None => write_with_niffler(input, output, compression::Format::No, niffler::Level::One),
+    };
+    if is_stdio(output) {
+        result.context("Failed to write output to stdout")
+    } else {
+        result.with_context(|| format!("Failed to write output file {}", output.display()))
+    }
 }
 
-pub fn determine_compression_type(output_path: &PathBuf) -> String {
+pub fn determine_compression_type(output_path: &Path) -> String {
     match output_path.extension().unwrap_or_default().to_str().unwrap_or_default() {
         "gz" => "gz".to_string(),
         "xz" => "xz".to_string(),
@@ -82,13 +325,11 @@ pub fn determine_compression_type(output_path: &PathBuf) -> String {
 }
 
 /// Function to write and compress using XZ with configurable threads
-pub fn write_with_liblzma(input_path: &PathBuf, output_path: &PathBuf, threads: usize, level: u32) -> io::Result<()> {
-    // Open the input file
-    let input_file = File::open(input_path)?;
-    let mut reader = BufReader::new(input_file);
+pub fn write_with_liblzma<R: Read>(input: R, output_path: &Path, threads: usize, level: u32) -> io::Result<()> {
+    let mut reader = BufReader::new(input);
 
     // Create the output file with a `.xz` extension
-    let output_file = File::create(output_path)?;
+    let output_file = create_output(output_path)?;
     let writer = BufWriter::new(output_file);
 
     // Choose the encoder based on the number of threads
@@ -100,7 +341,7 @@ pub fn write_with_liblzma(input_path: &PathBuf, output_path: &PathBuf, threads:
         XzEncoder::new(writer, level)  // Single-thread compression with the specified level
     };
 
-    // Compress the input file data
+    // Compress the input data
     io::copy(&mut reader, &mut encoder)?;
 
     // Finalize the compression process
@@ -110,29 +351,33 @@ pub fn write_with_liblzma(input_path: &PathBuf, output_path: &PathBuf, threads:
 }
 
 /// Utility function to gzip or BGZF output files using the gzp crate
-pub fn write_with_gzp(input_path: &PathBuf, output_path: &PathBuf, threads: usize) -> io::Result<()> {
-    // Open the input file
-    let input_file = File::open(input_path)?;
-    let mut reader = BufReader::new(input_file);
+pub fn write_with_gzp<R: Read>(
+    input: R,
+    output_path: &Path,
+    threads: usize,
+    format: OutputFormat,
+    level: u32,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(input);
 
-    // Create the output file based on its extension
-    let extension = output_path.extension().unwrap_or_default().to_str().unwrap_or_default();
-    let output_file = File::create(output_path)?;
+    let output_file = create_output(output_path)?;
 
     let writer = BufWriter::new(output_file);
 
     // Configure the compressor with the specified number of threads and compression format
-    let mut compressor = match extension {
-        "gz" => ZBuilder::<Gzip, _>::new()
+    let mut compressor = match format {
+        OutputFormat::Gz => ZBuilder::<Gzip, _>::new()
             .num_threads(threads)
+            .compression_level(gzp::Compression::new(level))
             .from_writer(writer), // Use Gzip compression for .gz files
-        "bgz" => ZBuilder::<Bgzf, _>::new()
+        OutputFormat::Bgz => ZBuilder::<Bgzf, _>::new()
             .num_threads(threads)
+            .compression_level(gzp::Compression::new(level))
             .from_writer(writer), // Use BGZF compression for .bgz files
-        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unsupported file extension")),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unsupported format for gzp")),
     };
 
-    // Compress the input file data
+    // Compress the input data
     io::copy(&mut reader, &mut compressor)?;
 
     // Finalize the compression process and map the error to io::Error
@@ -142,25 +387,23 @@ pub fn write_with_gzp(input_path: &PathBuf, output_path: &PathBuf, threads: usiz
 }
 
 /// Utility function to compress files using the zstd crate with configurable multithreading
-pub fn write_with_zstd(input_path: &PathBuf, output_path: &PathBuf, threads: usize) -> io::Result<()> {
-    // Open the input file
-    let input_file = File::open(input_path)?;
-    let mut reader = BufReader::new(input_file);
+pub fn write_with_zstd<R: Read>(input: R, output_path: &Path, threads: usize, level: i32) -> io::Result<()> {
+    let mut reader = BufReader::new(input);
 
     // Create the output file with a `.zst` extension
-    let output_file = File::create(output_path)?;
+    let output_file = create_output(output_path)?;
     let writer = BufWriter::new(output_file);
 
     // Configure the compressor based on the number of threads
     let mut encoder = if threads > 1 {
-        let mut encoder = ZstdEncoder::new(writer, 0)?;
+        let mut encoder = ZstdEncoder::new(writer, level)?;
         encoder.multithread(threads as u32)?; // Enable multithreading
         encoder
     } else {
-        ZstdEncoder::new(writer, 0)? // Single-threaded mode
+        ZstdEncoder::new(writer, level)? // Single-threaded mode
     };
 
-    // Compress the input file data
+    // Compress the input data
     io::copy(&mut reader, &mut encoder)?;
 
     // Finalize the compression process
@@ -169,48 +412,48 @@ pub fn write_with_zstd(input_path: &PathBuf, output_path: &PathBuf, threads: usi
     Ok(())
 }
 
-/// Compress a file using niffler with dynamic format detection based on the file extension
-pub fn write_with_niffler(input_paths: Vec<PathBuf>, output_paths: Vec<PathBuf>, threads: usize) -> Result<(), NifflerError> {
-    // Set the number of threads for parallelism using a local thread pool
-    rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap().install(|| -> Result<(), NifflerError> {
-
-        // Parallelize the compression of multiple files
-        input_paths.into_par_iter().zip(output_paths.into_par_iter()).try_for_each(|(input_path, output_path)| -> Result<(), NifflerError> {
-            // Open the input file and detect its compression format
-            let input_file = File::open(&input_path).map_err(NifflerError::IOError)?;
-            let mut reader = BufReader::new(input_file);
-
-            // Create the output file with appropriate compression format based on the extension
-            // In this program we are only using niffler for bgz and no compression, but the others
-            // are included here for completeness.
-            let extension = output_path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
-            let format = match extension {
-                "gz" => compression::Format::Gzip,
-                "bz2" => compression::Format::Bzip,
-                "xz" => compression::Format::Lzma,
-                "lzma" => compression::Format::Lzma,
-                "zst" => compression::Format::Zstd,
-                "zstd" => compression::Format::Zstd,
-                _ => compression::Format::No,
-            };
-
-            // Use niffler to create the output file
-            let output_file = File::create(&output_path).map_err(NifflerError::IOError)?;
-            let writer = BufWriter::new(output_file);
-            let mut compressor = get_writer(Box::new(writer), format, niffler::Level::One)?;
+/// Compress using niffler with the given format
+///
+/// In this program we are only using niffler for bz2 and no compression, as the other formats
+/// have multithreaded writers.
+pub fn write_with_niffler<R: Read>(
+    input: R,
+    output_path: &Path,
+    format: compression::Format,
+    level: niffler::Level,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(input);
+
+    // Use niffler to create the output file
+    let output_file = create_output(output_path)?;
+    let writer = BufWriter::new(output_file);
+    let mut compressor = get_writer(Box::new(writer), format, level)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
-            // Compress the input file data
-            io::copy(&mut reader, &mut compressor).map_err(NifflerError::IOError)?;
+    // Compress the input data
+    io::copy(&mut reader, &mut compressor)?;
 
-            // Finalize the compression
-            compressor.flush().map_err(NifflerError::IOError)?;
-            Ok(())
-        })
-    })?;
-    
+    // Finalize the compression
+    compressor.flush()?;
     Ok(())
 }
 
+/// niffler's level for a bzip2 compression level (1-9)
+fn niffler_level(level: u32) -> niffler::Level {
+    match level {
+        0 => niffler::Level::Zero,
+        1 => niffler::Level::One,
+        2 => niffler::Level::Two,
+        3 => niffler::Level::Three,
+        4 => niffler::Level::Four,
+        5 => niffler::Level::Five,
+        6 => niffler::Level::Six,
+        7 => niffler::Level::Seven,
+        8 => niffler::Level::Eight,
+        _ => niffler::Level::Nine,
+    }
+}
+
 pub fn read_with_niffler(input_paths: Vec<PathBuf>, output_paths: Vec<PathBuf>, compression_threads: usize) -> Result<(), NifflerError> {
     if compression_threads > 1 {
         // Decompress both files in parallel, with each file using a single thread
@@ -233,7 +476,7 @@ pub fn read_with_niffler(input_paths: Vec<PathBuf>, output_paths: Vec<PathBuf>,
             })?;
     } else {
         // Sequential decompression without any thread pool
-        for (input_path, output_path) in input_paths.into_iter().zip(output_paths.into_iter()) {
+        for (input_path, output_path) in input_paths.into_iter().zip(output_paths) {
             let (mut reader, _format) = from_path(&input_path)?;
             let output_file = File::create(&output_path).map_err(NifflerError::IOError)?;
             let mut writer = BufWriter::new(output_file);
@@ -245,70 +488,182 @@ pub fn read_with_niffler(input_paths: Vec<PathBuf>, output_paths: Vec<PathBuf>,
     Ok(())
 }
 
-/// Parse Kraken2 stderr for stats
-pub fn parse_kraken_stats(kraken_stderr: &str) -> Result<Stats, anyhow::Error> {
-    let mut total_sequences: usize = 0;
-    let mut classified_sequences: usize = 0;
-    let mut unclassified_sequences: usize = 0;
-
-    // Parse Kraken2 stderr output line by line
-    for line in kraken_stderr.lines() {
-        if line.contains("processed") {
-            total_sequences = line.split_whitespace()
-                .nth(0)
-                .unwrap()
-                .replace(",", "") // Handle commas in large numbers
-                .parse::<usize>()
-                .expect("Failed to parse total sequences");
-        } else if line.contains("sequences classified") {
-            classified_sequences = line.split_whitespace()
-                .nth(0)
-                .unwrap()
-                .replace(",", "") // Handle commas in large numbers
-                .parse::<usize>()
-                .expect("Failed to parse classified sequences");
-        } else if line.contains("sequences unclassified") {
-            unclassified_sequences = line.split_whitespace()
-                .nth(0)
-                .unwrap()
-                .replace(",", "") // Handle commas in large numbers
-                .parse::<usize>()
-                .expect("Failed to parse unclassified sequences");
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum KrakenStatsError {
+    #[error("kraken2's output has no line reporting the {0}")]
+    MissingLine(&'static str),
+
+    #[error("Could not read the {field} from kraken2's output: '{line}'")]
+    MalformedLine { field: &'static str, line: String },
+
+    #[error("kraken2 reported {total} sequences, but {classified} classified and {unclassified} unclassified")]
+    Inconsistent {
+        total: usize,
+        classified: usize,
+        unclassified: usize,
+    },
+}
+
+/// The summary kraken2 writes to stderr at the end of a run, e.g.,
+///
+/// ```text
+/// 1000 sequences (0.15 Mbp) processed in 0.052s (1153.8 Kseq/m, 173.08 Mbp/m).
+///   12 sequences classified (1.20%)
+///   988 sequences unclassified (98.80%)
+/// ```
+///
+/// Paired reads are counted as one sequence per pair.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KrakenStats {
+    pub total_sequences: usize,
+    pub classified_sequences: usize,
+    pub unclassified_sequences: usize,
+    /// Megabases of sequence processed, as rounded by kraken2
+    pub megabases: f64,
+    /// Time spent classifying, not including loading the database
+    pub seconds: f64,
+}
+
+/// Parse the summary at the end of kraken2's stderr. Other lines (e.g., loading messages and
+/// warnings) are ignored.
+pub fn parse_kraken_stats(kraken_stderr: &str) -> Result<KrakenStats, KrakenStatsError> {
+    let mut processed = None;
+    let mut classified = None;
+    let mut unclassified = None;
+
+    // Progress updates are overwritten with carriage returns, so split on those too
+    for line in kraken_stderr.split(['\n', '\r']).map(str::trim) {
+        let malformed = |field| KrakenStatsError::MalformedLine {
+            field,
+            line: line.to_string(),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [count, "sequences", bases, "Mbp)", "processed", "in", time, ..] => {
+                let total = parse_count(count).ok_or_else(|| malformed("number of sequences processed"))?;
+                let megabases = bases
+                    .trim_start_matches('(')
+                    .parse()
+                    .map_err(|_| malformed("number of bases processed"))?;
+                let seconds = time
+                    .trim_end_matches('.')
+                    .trim_end_matches('s')
+                    .parse()
+                    .map_err(|_| malformed("processing time"))?;
+                processed = Some((total, megabases, seconds));
+            }
+            [count, "sequences", "classified", ..] => {
+                classified = Some(parse_count(count).ok_or_else(|| malformed("number of sequences classified"))?);
+            }
+            [count, "sequences", "unclassified", ..] => {
+                unclassified = Some(parse_count(count).ok_or_else(|| malformed("number of sequences unclassified"))?);
+            }
+            _ => {}
         }
     }
 
-    let sequences_removed = classified_sequences;
-    let sequences_remaining = unclassified_sequences;
-    let proportion_removed = sequences_removed as f64 / total_sequences as f64;
-
-    // Return stats
-    Ok(Stats {
-        nohuman_version: env!("CARGO_PKG_VERSION").to_string(),
-        kraken2_version: "".to_string(),  // Placeholder, to be filled later
-        input1: "".to_string(),  // Placeholder, to be filled later
-        input2: "".to_string(),  // Placeholder, to be filled later
-        output1: "".to_string(), // Placeholder, to be filled later
-        output2: "".to_string(), // Placeholder, to be filled later
+    let (total_sequences, megabases, seconds) =
+        processed.ok_or(KrakenStatsError::MissingLine("number of sequences processed"))?;
+    let classified_sequences = classified.ok_or(KrakenStatsError::MissingLine("number of sequences classified"))?;
+    let unclassified_sequences =
+        unclassified.ok_or(KrakenStatsError::MissingLine("number of sequences unclassified"))?;
+    if classified_sequences + unclassified_sequences != total_sequences {
+        return Err(KrakenStatsError::Inconsistent {
+            total: total_sequences,
+            classified: classified_sequences,
+            unclassified: unclassified_sequences,
+        });
+    }
+    Ok(KrakenStats {
         total_sequences,
-        sequences_removed,
-        sequences_remaining,
-        proportion_removed,
+        classified_sequences,
+        unclassified_sequences,
+        megabases,
+        seconds,
     })
 }
 
+/// Parse a count, which may have thousands separators
+fn parse_count(count: &str) -> Option<usize> {
+    count.replace(',', "").parse().ok()
+}
+
 /// Struct for JSON statistics output
-#[derive(Serialize)]
+#[derive(Debug, Default, Serialize)]
 pub struct Stats {
     pub nohuman_version: String,
     pub kraken2_version: String,
+    pub backend: String,
+    /// The sample name, as reported to MultiQC
+    pub sample: String,
+    pub database: String,
+    pub database_version: String,
     pub input1: String,
     pub input2: String,
     pub output1: String,
     pub output2: String,
+    pub human_output1: String,
+    pub human_output2: String,
+    pub confidence: f64,
+    pub minimum_hit_groups: u32,
     pub total_sequences: usize,
     pub sequences_remaining: usize,
     pub sequences_removed: usize,
     pub proportion_removed: f64,
+    pub nonhuman_sequences_written: usize,
+    pub human_sequences_written: usize,
+    pub sequences_masked: usize,
+    pub total_bases: u64,
+    pub bases_removed: u64,
+    pub bases_remaining: u64,
+    /// Counts for each input file (or each mate of interleaved reads)
+    pub inputs: Vec<InputCounts>,
+    /// Counts of read pairs by the mates with human k-mers, for paired reads
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pairs: Option<PairCounts>,
+    pub read_lengths_before: LengthSummary,
+    /// Lengths of the reads not classified as human
+    pub read_lengths_after: LengthSummary,
+    pub timings: Timings,
+    /// Peak memory use of the nohuman process so far. In a batch this includes the samples run
+    /// before this one.
+    pub process_peak_memory_mb: f64,
+    /// Peak memory use of the classifier in this run, if it runs as a separate process (kraken2)
+    pub classifier_peak_memory_mb: f64,
+    /// A summary of the classification report, if one was written
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report: Option<ReportSummary>,
+}
+
+impl Stats {
+    /// Stats for a run from the number of reads (or pairs) classified as human (removed) and
+    /// non-human (remaining), with the run information left to be filled in
+    pub fn from_counts(total_sequences: usize, sequences_removed: usize, sequences_remaining: usize) -> Self {
+        let proportion_removed = if total_sequences == 0 {
+            0.0
+        } else {
+            sequences_removed as f64 / total_sequences as f64
+        };
+        Stats {
+            nohuman_version: env!("CARGO_PKG_VERSION").to_string(),
+            total_sequences,
+            sequences_removed,
+            sequences_remaining,
+            proportion_removed,
+            ..Default::default()
+        }
+    }
+
+    /// Fill in the base counts, per-input counts and read lengths from the reads' statistics
+    pub fn add_read_metrics(&mut self, metrics: &ReadMetrics) {
+        self.total_bases = metrics.total_bases();
+        self.bases_removed = metrics.bases_removed();
+        self.bases_remaining = self.total_bases - self.bases_removed;
+        self.inputs = metrics.inputs.clone();
+        self.pairs = metrics.pairs.clone();
+        self.read_lengths_before = metrics.lengths_before.summary();
+        self.read_lengths_after = metrics.lengths_after.summary();
+    }
 }
 
 /// Write stats to a JSON file
@@ -319,18 +674,77 @@ pub fn write_stats(stats_file: &PathBuf, stats: &Stats) -> Result<(), anyhow::Er
     Ok(())
 }
 
-#[derive(Deserialize)]
-pub struct Config {
-    pub database_url: String,
-    pub database_md5: String,
+/// Number of lines of stderr kept when reporting a failed command
+const STDERR_TAIL_LINES: usize = 10;
+
+#[derive(Error, Debug)]
+pub enum CommandError {
+    #[error("Failed to run {command}: {source}")]
+    SpawnFailed {
+        command: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("{command} {}{}", describe_exit(.code, .signal), format_stderr_tail(.stderr))]
+    Failed {
+        command: String,
+        code: Option<i32>,
+        signal: Option<i32>,
+        stderr: String,
+    },
 }
 
+impl CommandError {
+    /// The last few lines of stderr from the failed command, if it got as far as running
+    pub fn stderr_tail(&self) -> Option<String> {
+        match self {
+            CommandError::Failed { stderr, .. } => Some(tail_lines(stderr, STDERR_TAIL_LINES)),
+            CommandError::SpawnFailed { .. } => None,
+        }
+    }
+}
 
-impl Config {
-    pub fn new(database_url: &str, database_md5: &str) -> Self {
-        Self {
-            database_url: database_url.to_string(),
-            database_md5: database_md5.to_string(),
+fn describe_exit(code: &Option<i32>, signal: &Option<i32>) -> String {
+    match (code, signal) {
+        (Some(code), _) => format!("exited with status {}", code),
+        (None, Some(signal)) => format!("was terminated by signal {}", signal),
+        (None, None) => "exited unsuccessfully".to_string(),
+    }
+}
+
+fn format_stderr_tail(stderr: &str) -> String {
+    let tail = tail_lines(stderr, STDERR_TAIL_LINES);
+    if tail.is_empty() {
+        String::new()
+    } else {
+        format!("\nLast lines of stderr:\n{}", tail)
+    }
+}
+
+fn tail_lines(text: &str, n: usize) -> String {
+    let lines: Vec<&str> = text.trim_end().lines().collect();
+    lines[lines.len().saturating_sub(n)..].join("\n")
+}
+
+/// Wait for the child process `pid` to exit, returning its exit status and peak resident memory
+/// in megabytes
+fn wait_with_peak_memory(pid: u32) -> io::Result<(ExitStatus, f64)> {
+    let mut status = 0;
+    // SAFETY: rusage is plain data, for which all zeroes is a valid value
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: `pid` is a child of this process that hasn't been reaped, as only this function
+        // waits for it, and `status` and `usage` are valid for `wait4` to write to
+        if unsafe { libc::wait4(pid as libc::pid_t, &mut status, 0, &mut usage) } != -1 {
+            return Ok((
+                ExitStatus::from_raw(status),
+                metrics::maxrss_mb(&usage),
+            ));
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
         }
     }
 }
@@ -346,15 +760,62 @@ impl CommandRunner {
         }
     }
 
-    pub fn run(&self, args: &[&str]) -> io::Result<()> {
-        let output = Command::new(&self.command).args(args).output()?;
+    /// Run the command to completion, capturing stdout and stderr.
+    ///
+    /// Returns an error carrying the exit code (or terminating signal) and stderr if the
+    /// command does not exit successfully.
+    pub fn run(&self, args: &[&str]) -> Result<Output, CommandError> {
+        self.run_with_peak_memory(args).map(|(output, _)| output)
+    }
+
+    /// Like [`CommandRunner::run`], but also returns the peak resident memory of the command's
+    /// process, in megabytes. On Linux this is never less than the peak of this process when the
+    /// command was started, as the kernel counts the memory of the process it was started from.
+    pub fn run_with_peak_memory(&self, args: &[&str]) -> Result<(Output, f64), CommandError> {
+        let spawn_failed = |source| CommandError::SpawnFailed {
+            command: self.command.clone(),
+            source,
+        };
+        let mut child = Command::new(&self.command)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(spawn_failed)?;
+
+        // Both pipes are drained at once so the command can't block on a full one
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let stdout = std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            stdout.read_to_end(&mut buf).map(|_| buf)
+        });
+        let mut stderr = Vec::new();
+        let read = child
+            .stderr
+            .take()
+            .expect("stderr is piped")
+            .read_to_end(&mut stderr);
+        let stdout = stdout.join().expect("stdout reader panicked");
+        // The child is reaped here, rather than by `Child::wait`, to get its resource usage
+        let (status, peak_memory_mb) = wait_with_peak_memory(child.id()).map_err(spawn_failed)?;
+        read.map_err(spawn_failed)?;
+        let output = Output {
+            status,
+            stdout: stdout.map_err(spawn_failed)?,
+            stderr,
+        };
 
         if !output.status.success() {
-            let error_message = String::from_utf8_lossy(&output.stderr);
-            writeln!(io::stderr(), "{}", error_message)?;
+            return Err(CommandError::Failed {
+                command: self.command.clone(),
+                code: output.status.code(),
+                signal: output.status.signal(),
+                stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            });
         }
 
-        Ok(())
+        Ok((output, peak_memory_mb))
     }
 
     pub fn is_executable(&self) -> bool {
@@ -367,16 +828,40 @@ impl CommandRunner {
     }
 }
 
-/// A utility function that allows the CLI to error if a path doesn't exist
+/// Remove any (partially written) output files, warning rather than failing if one can't be removed
+pub fn remove_partial_outputs(paths: &[&Path]) {
+    for path in paths.iter().filter(|path| !is_stdio(path)) {
+        match std::fs::remove_file(path) {
+            Ok(()) => warn!("Removed partial output file: {}", path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove partial output file {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// A utility function that allows the CLI to error if a path doesn't exist. [`STDIO`] (stdin) is
+/// always accepted.
 pub fn check_path_exists<S: AsRef<OsStr> + ?Sized>(s: &S) -> Result<PathBuf, String> {
     let path = PathBuf::from(s);
-    if path.exists() {
+    if path.exists() || is_stdio(&path) {
         Ok(path)
     } else {
         Err(format!("{:?} does not exist", path))
     }
 }
 
+/// A utility function that allows the CLI to error if a kraken2 confidence score is not in [0, 1]
+pub fn check_confidence(s: &str) -> Result<f64, String> {
+    let confidence: f64 = s
+        .parse()
+        .map_err(|_| format!("{:?} is not a valid number", s))?;
+    if (0.0..=1.0).contains(&confidence) {
+        Ok(confidence)
+    } else {
+        Err(format!("confidence must be between 0 and 1, got {}", confidence))
+    }
+}
+
 /// Checks if the specified path is a directory and contains the required kraken2 db files.
 /// If not found, checks inside a 'db' subdirectory.
 ///
@@ -412,6 +897,23 @@ pub fn validate_db_directory(path: &Path) -> Result<PathBuf, String> {
     ))
 }
 
+/// The version of the database in the directory `db`: the `name/version` it was installed as or,
+/// if it wasn't installed by nohuman, the directory's name. For the `db` subdirectory of a
+/// downloaded database, the directory holding it is used.
+pub fn database_version(db: &Path) -> String {
+    let install_dir = match db.file_name() {
+        Some(dir) if dir == "db" => db.parent().filter(|parent| parent.file_name().is_some()).unwrap_or(db),
+        _ => db,
+    };
+    if let Ok(installed) = registry::InstalledDatabase::open(install_dir) {
+        return installed.entry.id();
+    }
+    match install_dir.file_name() {
+        Some(dir) => dir.to_string_lossy().to_string(),
+        None => db.display().to_string(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -436,6 +938,185 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_run_with_failing_command() {
+        let command = CommandRunner::new("sh");
+        let err = command
+            .run(&["-c", "echo first >&2; echo boom >&2; exit 3"])
+            .unwrap_err();
+        match &err {
+            CommandError::Failed { code, signal, .. } => {
+                assert_eq!(*code, Some(3));
+                assert_eq!(*signal, None);
+            }
+            _ => panic!("Expected a failed command error"),
+        }
+        assert_eq!(err.stderr_tail().unwrap(), "first\nboom");
+        assert!(err.to_string().starts_with("sh exited with status 3"));
+        assert!(err.to_string().ends_with("boom"));
+    }
+
+    #[test]
+    fn test_run_with_peak_memory() {
+        // dd fills a buffer of one block
+        let command = CommandRunner::new("dd");
+        let run = |block_mb: u64| {
+            let bs = format!("bs={}M", block_mb);
+            let args = ["if=/dev/zero", "of=/dev/null", "count=1", &bs];
+            command.run_with_peak_memory(&args).unwrap().1
+        };
+        let small_peak = run(1);
+        // A command's peak is never less than this process's, so the big block is on top of that
+        let big_peak = run(metrics::process_peak_memory_mb() as u64 + 32);
+        // Each command's own peak is measured, not the largest of all commands run so far
+        let last_peak = run(1);
+        assert!(
+            small_peak > 0.0 && big_peak - small_peak > 16.0 && last_peak < big_peak,
+            "peaks of {}, {} and {} MB",
+            small_peak,
+            big_peak,
+            last_peak
+        );
+    }
+
+    #[test]
+    fn test_run_with_killed_command() {
+        let command = CommandRunner::new("sh");
+        let err = command.run(&["-c", "kill -9 $$"]).unwrap_err();
+        match &err {
+            CommandError::Failed { code, signal, .. } => {
+                assert_eq!(*code, None);
+                assert_eq!(*signal, Some(9));
+            }
+            _ => panic!("Expected a failed command error"),
+        }
+        assert_eq!(err.to_string(), "sh was terminated by signal 9");
+    }
+
+    #[test]
+    fn test_parse_kraken_stats_captures() {
+        // The stderr of kraken2 releases classifying `tests/data/kraken2/reads_1.fq`, saved by
+        // `just capture-kraken2-stderr`
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/kraken2");
+        let mut counts = Vec::new();
+        for entry in std::fs::read_dir(data).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if !(name.starts_with("kraken2-") && name.ends_with(".stderr")) {
+                continue;
+            }
+            let stderr = std::fs::read_to_string(&path).unwrap();
+            let stats = parse_kraken_stats(&stderr)
+                .unwrap_or_else(|e| panic!("Failed to parse {}: {}", name, e));
+            assert_eq!(stats.total_sequences, 16, "{}", name);
+            assert_eq!(
+                stats.classified_sequences + stats.unclassified_sequences,
+                16,
+                "{}",
+                name
+            );
+            counts.push((stats.classified_sequences, name));
+        }
+        // Every release classifies the reads the same way
+        counts.sort();
+        assert!(counts.windows(2).all(|pair| pair[0].0 == pair[1].0), "{:?}", counts);
+    }
+
+    #[test]
+    fn test_parse_kraken_stats_layouts() {
+        // Hand-written in the layout of kraken2's stderr, not captured from a run: the plain
+        // summary, paired reads (counted as pairs), a warning while loading the database, and the
+        // progress lines ending in `\r` that are written when stderr is a terminal
+        let outputs = [
+            (
+                "Loading database information... done.\n\
+                 10000 sequences (1.50 Mbp) processed in 0.336s (1785.1 Kseq/m, 267.76 Mbp/m).\n\
+                 \x20 123 sequences classified (1.23%)\n\
+                 \x20 9877 sequences unclassified (98.77%)\n",
+                (10000, 123, 9877, 1.5),
+            ),
+            (
+                "Loading database information... done.\n\
+                 5000 sequences (3.00 Mbp) processed in 0.612s (490.2 Kseq/m, 294.12 Mbp/m).\n\
+                 \x20 50 sequences classified (1.00%)\n\
+                 \x20 4950 sequences unclassified (99.00%)\n",
+                (5000, 50, 4950, 3.0),
+            ),
+            (
+                "Loading database information... done.\n\
+                 Processed 8000 sequences (1200000 bp) ...\rProcessed 12000 sequences (1800000 bp) ...\r\
+                 12000 sequences (1.80 Mbp) processed in 0.287s (2508.7 Kseq/m, 376.31 Mbp/m).\n\
+                 \x20 0 sequences classified (0.00%)\n\
+                 \x20 12000 sequences unclassified (100.00%)\n",
+                (12000, 0, 12000, 1.8),
+            ),
+            (
+                "Loading database information...Warning: unable to use memory mapping\n done.\n\
+                 3 sequences (0.00 Mbp) processed in 0.001s (180.0 Kseq/m, 0.27 Mbp/m).\n\
+                 \x20 3 sequences classified (100.00%)\n\
+                 \x20 0 sequences unclassified (0.00%)\n",
+                (3, 3, 0, 0.0),
+            ),
+        ];
+        for (output, (total, classified, unclassified, megabases)) in outputs {
+            let stats = parse_kraken_stats(output).unwrap();
+            assert_eq!(
+                (stats.total_sequences, stats.classified_sequences, stats.unclassified_sequences),
+                (total, classified, unclassified)
+            );
+            assert_eq!(stats.megabases, megabases);
+            assert!(stats.seconds > 0.0);
+        }
+    }
+
+    #[test]
+    fn test_parse_kraken_stats_no_reads() {
+        let output = "0 sequences (0.00 Mbp) processed in 0.001s (0.0 Kseq/m, 0.00 Mbp/m).\n\
+                      \x20 0 sequences classified (-nan%)\n\
+                      \x20 0 sequences unclassified (-nan%)\n";
+        let stats = parse_kraken_stats(output).unwrap();
+        assert_eq!(stats.total_sequences, 0);
+        let stats = Stats::from_counts(stats.total_sequences, stats.classified_sequences, stats.unclassified_sequences);
+        assert_eq!(stats.proportion_removed, 0.0);
+    }
+
+    #[test]
+    fn test_parse_kraken_stats_errors() {
+        assert_eq!(
+            parse_kraken_stats("Loading database information...").unwrap_err(),
+            KrakenStatsError::MissingLine("number of sequences processed")
+        );
+        assert_eq!(
+            parse_kraken_stats("10 sequences (0.00 Mbp) processed in 0.1s\n  1 sequences classified (10%)\n").unwrap_err(),
+            KrakenStatsError::MissingLine("number of sequences unclassified")
+        );
+        assert_eq!(
+            parse_kraken_stats("ten sequences (0.00 Mbp) processed in 0.1s\n").unwrap_err().to_string(),
+            "Could not read the number of sequences processed from kraken2's output: 'ten sequences (0.00 Mbp) processed in 0.1s'"
+        );
+        assert!(matches!(
+            parse_kraken_stats("10 sequences (0.00 Mbp) processed in 0.1s\n 1 sequences classified\n 8 sequences unclassified\n"),
+            Err(KrakenStatsError::Inconsistent { total: 10, .. })
+        ));
+    }
+
+    #[test]
+    fn test_tail_lines() {
+        assert_eq!(tail_lines("a\nb\nc\n", 2), "b\nc");
+        assert_eq!(tail_lines("a\n", 5), "a");
+        assert_eq!(tail_lines("", 5), "");
+    }
+
+    #[test]
+    fn test_remove_partial_outputs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let partial = tmp.path().join("partial.fq");
+        std::fs::write(&partial, "@read").unwrap();
+        let missing = tmp.path().join("missing.fq");
+        remove_partial_outputs(&[&partial, &missing]);
+        assert!(!partial.exists());
+    }
+
     #[test]
     fn test_is_executable() {
         let command = CommandRunner::new("ls");
@@ -448,6 +1129,13 @@ mod tests {
         assert!(!command.is_executable());
     }
 
+    #[test]
+    fn test_database_version() {
+        assert_eq!(database_version(Path::new("/data/HPRC.r1")), "HPRC.r1");
+        assert_eq!(database_version(Path::new("/data/HPRC.r1/db")), "HPRC.r1");
+        assert_eq!(database_version(Path::new("db")), "db");
+    }
+
     #[test]
     fn check_path_exists_it_doesnt() {
         let result = check_path_exists(OsStr::new("fake.path"));
@@ -461,6 +1149,144 @@ mod tests {
         assert_eq!(actual, expected)
     }
 
+    #[test]
+    fn check_path_accepts_stdin() {
+        assert_eq!(check_path_exists(OsStr::new("-")).unwrap(), PathBuf::from("-"));
+    }
+
+    #[test]
+    fn output_format_from_extension() {
+        assert_eq!(OutputFormat::from_path(Path::new("out.fq.gz")), OutputFormat::Gz);
+        assert_eq!(OutputFormat::from_path(Path::new("out.fq.zstd")), OutputFormat::Zst);
+        assert_eq!(OutputFormat::from_path(Path::new("out.fq.lzma")), OutputFormat::Xz);
+        assert_eq!(OutputFormat::from_path(Path::new("out.fq")), OutputFormat::None);
+    }
+
+    #[test]
+    fn default_output_path_uncompressed() {
+        let actual = default_output_path(Path::new("dir/in_1.fastq"), "nohuman");
+        assert_eq!(actual, PathBuf::from("dir/in_1.nohuman.fq"));
+    }
+
+    #[test]
+    fn default_output_path_keeps_compression_extension() {
+        let actual = default_output_path(Path::new("dir/in_2.fastq.gz"), "nohuman");
+        assert_eq!(actual, PathBuf::from("dir/in_2.nohuman.fq.gz"));
+
+        let actual = default_output_path(Path::new("in.fq.zst"), "human");
+        assert_eq!(actual, PathBuf::from("in.human.fq.zst"));
+    }
+
+    #[test]
+    fn keep_selects_outputs() {
+        assert!(Keep::Nonhuman.nonhuman() && !Keep::Nonhuman.human());
+        assert!(!Keep::Human.nonhuman() && Keep::Human.human());
+        assert!(Keep::Both.nonhuman() && Keep::Both.human());
+    }
+
+    #[test]
+    fn test_write_output_compresses_each_input() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outputs = vec![tmp.path().join("out_1.fq.gz"), tmp.path().join("out_2.fq")];
+        let inputs = vec![&b"@r1/1\nACGT\n+\nIIII\n"[..], &b"@r1/2\nTTTT\n+\nIIII\n"[..]];
+        let settings = OutputSettings {
+            threads: 2,
+            format: None,
+            level: None,
+        };
+        write_output(inputs, &outputs, settings).unwrap();
+
+        let (mut reader, format) = from_path(&outputs[0]).unwrap();
+        assert_eq!(format, compression::Format::Gzip);
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "@r1/1\nACGT\n+\nIIII\n");
+        assert_eq!(std::fs::read_to_string(&outputs[1]).unwrap(), "@r1/2\nTTTT\n+\nIIII\n");
+    }
+
+    #[test]
+    fn test_write_output_mismatched_lengths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outputs = vec![tmp.path().join("out_1.fq"), tmp.path().join("out_2.fq")];
+        let settings = OutputSettings {
+            threads: 1,
+            format: None,
+            level: None,
+        };
+        assert!(write_output(vec![&b""[..]], &outputs, settings).is_err());
+    }
+
+    #[test]
+    fn test_write_output_with_format_and_level() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outputs = vec![tmp.path().join("out_1.fq.gz"), tmp.path().join("out_2.fq")];
+        let inputs = vec![&b"@r1/1\nACGT\n+\nIIII\n"[..], &b"@r1/2\nTTTT\n+\nIIII\n"[..]];
+        let settings = OutputSettings {
+            threads: 1,
+            format: Some(OutputFormat::Bz2),
+            level: Some(9),
+        };
+        write_output(inputs, &outputs, settings).unwrap();
+
+        // The format applies whatever the extension
+        for output in &outputs {
+            let (mut reader, format) = from_path(output).unwrap();
+            assert_eq!(format, compression::Format::Bzip);
+            let mut contents = String::new();
+            reader.read_to_string(&mut contents).unwrap();
+            assert!(contents.starts_with("@r1/"));
+        }
+
+        let settings = OutputSettings {
+            threads: 1,
+            format: Some(OutputFormat::Gz),
+            level: Some(12),
+        };
+        let err = write_output(vec![&b""[..]], &outputs[..1], settings).unwrap_err();
+        assert_eq!(err.to_string(), "compression level for gz must be between 1 and 9, got 12");
+    }
+
+    #[test]
+    fn output_format_levels() {
+        assert!(OutputFormat::Zst.check_level(19).is_ok());
+        assert!(OutputFormat::Xz.check_level(0).is_ok());
+        assert!(OutputFormat::Bz2.check_level(0).is_err());
+        assert!(OutputFormat::None.check_level(1).is_err());
+        for format in [OutputFormat::Gz, OutputFormat::Bgz, OutputFormat::Zst, OutputFormat::Xz, OutputFormat::Bz2] {
+            assert!(format.check_level(format.default_level()).is_ok());
+        }
+    }
+
+    #[test]
+    fn replace_compression_extension() {
+        let path = Path::new("dir/in.nohuman.fq.gz");
+        assert_eq!(with_compression_extension(path, OutputFormat::Zst), PathBuf::from("dir/in.nohuman.fq.zst"));
+        assert_eq!(with_compression_extension(path, OutputFormat::None), PathBuf::from("dir/in.nohuman.fq"));
+        assert_eq!(
+            with_compression_extension(Path::new("in.nohuman.fq"), OutputFormat::Bgz),
+            PathBuf::from("in.nohuman.fq.bgz")
+        );
+    }
+
+    #[test]
+    fn check_confidence_in_range() {
+        assert_eq!(check_confidence("0").unwrap(), 0.0);
+        assert_eq!(check_confidence("0.25").unwrap(), 0.25);
+        assert_eq!(check_confidence("1.0").unwrap(), 1.0);
+    }
+
+    #[test]
+    fn check_confidence_out_of_range() {
+        assert!(check_confidence("-0.1").is_err());
+        assert!(check_confidence("1.01").is_err());
+        assert!(check_confidence("NaN").is_err());
+    }
+
+    #[test]
+    fn check_confidence_not_a_number() {
+        assert!(check_confidence("high").is_err());
+    }
+
     // /// Function to write and compress using parallel XZ with multiple threads
     // /// Note: removed as using liblzma instead
     // pub fn write_with_xz2(input_path: &PathBuf, output_path: &PathBuf, threads: usize) -> Result<()> {
@@ -1,22 +1,24 @@
 use std::path::PathBuf;
 use anyhow::{bail, Context, Result};
-use clap::Parser;
+use clap::{ArgGroup, Parser, Subcommand};
 use env_logger::Builder;
 use lazy_static::lazy_static;
 use log::{debug, error, info, warn, LevelFilter};
 use nohuman::{
+    check_confidence,
     check_path_exists, 
-    download::download_database, 
-    validate_db_directory, 
-    parse_kraken_stats, 
-    write_stats, 
-    write_output, 
-    read_with_niffler, 
-    CommandRunner
+    db::{KrakenDatabase, EXPECTED_K, EXPECTED_L},
+    download::{download_database, download_registry, install_from_file},
+    registry::{DatabaseStore, Registry, BUNDLED_REGISTRY},
+    samplesheet::{read_samplesheet, run_batch, write_summary, SUMMARY_FILE},
+    sources::Sources,
+    CommandRunner,
+    Backend,
+    Keep,
+    MaskMode,
+    OutputFormat,
+    Pipeline
 };
-use std::process::{Command, Stdio};
-use std::fs::File;
-use std::io::Write;
 
 lazy_static! {
     static ref DEFAULT_DB_LOCATION: String = {
@@ -31,25 +33,68 @@ lazy_static! {
 
 /// Struct representing the command-line arguments
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(group(ArgGroup::new("install").args(["download", "from_file"])))]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input file(s) to remove human reads from.
     ///
+    /// Use `-` to read from stdin. The compression of stdin is detected from its content.
     /// This is a required argument unless `--check` or `--download` is specified.
     #[arg(
         name = "INPUT",
-        required_unless_present_any = &["check", "download"],
+        required_unless_present_any = &["check", "download", "from_file", "samplesheet"],
         value_parser = check_path_exists,
         verbatim_doc_comment
     )]
     input: Option<Vec<PathBuf>>,
 
+    /// The input is a single file (or stdin) of interleaved paired reads.
+    ///
+    /// Mates are classified as pairs, and each set of reads is written interleaved to a
+    /// single output (`--out1`/`--human-out1`).
+    #[arg(
+        long,
+        verbatim_doc_comment
+    )]
+    interleaved: bool,
+
+    /// Sample sheet of samples to process in a single run, instead of INPUT.
+    ///
+    /// A CSV file (or tab-separated with a `.tsv` or `.txt` extension) with a header and
+    /// the columns `sample` and `r1`, and optionally `r2`, `out1` and `out2`, whose relative
+    /// paths are relative to the sheet's directory. The database is loaded once for all of
+    /// the samples. Outputs not named in the sheet, per-sample
+    /// stats (`<sample>.stats.json`) and MultiQC files (`<sample>_mqc.json`), and a summary
+    /// table (`nohuman_summary.tsv`) are written to `--outdir`.
+    #[arg(
+        long,
+        value_name = "PATH",
+        value_parser = check_path_exists,
+        conflicts_with_all = &["INPUT", "OUTPUT_1", "OUTPUT_2", "HUMAN_OUTPUT_1", "HUMAN_OUTPUT_2", "stats", "kraken2_log", "removed_ids", "report", "multiqc", "sample_name"],
+        verbatim_doc_comment
+    )]
+    samplesheet: Option<PathBuf>,
+
+    /// Output directory for `--samplesheet` runs.
+    #[arg(
+        long,
+        value_name = "DIR",
+        default_value = ".",
+        requires = "samplesheet",
+        verbatim_doc_comment
+    )]
+    outdir: PathBuf,
+
     /// First output file.
     ///
     /// Defaults to the name of the first input file with the suffix "nohuman" appended.
     /// e.g., "input_1.fastq.gz" -> "input_1.nohuman.fq.gz". 
     /// If the file stem is one of `.gz`, `.bgz`, `.xz`, `.zst`, the output will be
     /// compressed accordingly.    
+    /// Use `-` to write to stdout (the default when reading from stdin).
     #[arg(
         short,
         long,
@@ -72,6 +117,90 @@ struct Args {
     )]
     pub out2: Option<PathBuf>,
 
+    /// Which reads to write out after classification.
+    ///
+    /// "nonhuman" writes the reads with human reads removed (to `--out1`/`--out2`),
+    /// "human" writes only the human reads (to `--human-out1`/`--human-out2`), and
+    /// "both" writes the two sets to their respective outputs.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Keep::Nonhuman,
+        verbatim_doc_comment
+    )]
+    keep: Keep,
+
+    /// First output file for human reads (requires `--keep human` or `--keep both`).
+    ///
+    /// Defaults to the name of the first input file with the suffix "human" appended.
+    /// e.g., "input_1.fastq.gz" -> "input_1.human.fq.gz".
+    /// Compression is determined in the same way as for `--out1`.
+    #[arg(
+        long,
+        name = "HUMAN_OUTPUT_1",
+        verbatim_doc_comment
+    )]
+    pub human_out1: Option<PathBuf>,
+
+    /// Second output file for human reads (requires `--keep human` or `--keep both`).
+    ///
+    /// Defaults to the name of the second input file with the suffix "human" appended.
+    /// e.g., "input_2.fastq.gz" -> "input_2.human.fq.gz".
+    /// Compression is determined in the same way as for `--out2`.
+    #[arg(
+        long,
+        name = "HUMAN_OUTPUT_2",
+        verbatim_doc_comment
+    )]
+    pub human_out2: Option<PathBuf>,
+
+    /// Keep every read, masking the human reads instead of removing them.
+    ///
+    /// "n" replaces every base of a human read with N and its quality scores with the
+    /// lowest ('!'); "stub" reduces it to a single N. Reads stay in their input order and
+    /// are written to `--out1`/`--out2`, which default to the inputs with the suffix
+    /// "masked" appended. e.g., "input_1.fastq.gz" -> "input_1.masked.fq.gz".
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        conflicts_with_all = &["keep", "HUMAN_OUTPUT_1", "HUMAN_OUTPUT_2"],
+        verbatim_doc_comment
+    )]
+    mask: Option<MaskMode>,
+
+    /// Write a list of the reads classified as human to this file (tab-separated).
+    ///
+    /// Each mate gets a row with the read ID, the mate (1 or 2), the taxonomy ID it was
+    /// assigned, its length and the fraction of its k-mers that hit human taxa.
+    /// Compression is determined from the extension, as for `--out1`.
+    #[arg(
+        long,
+        value_name = "PATH",
+        verbatim_doc_comment
+    )]
+    removed_ids: Option<PathBuf>,
+
+    /// Write a taxonomic report of the classification to this file.
+    ///
+    /// The report is in kraken2's `--report` format, and a summary of it is added to
+    /// the stats file (`--stats`).
+    #[arg(
+        long,
+        value_name = "PATH",
+        verbatim_doc_comment
+    )]
+    report: Option<PathBuf>,
+
+    /// Include the number of minimizers (and distinct minimizers) for each taxon in
+    /// the report, as kraken2's `--report-minimizer-data` does.
+    #[arg(
+        long,
+        requires = "report",
+        verbatim_doc_comment
+    )]
+    report_minimizer_data: bool,
+
     /// Check that all required dependencies are available and exit.
     #[arg(
         short,
@@ -88,9 +217,55 @@ struct Args {
     )]
     download: bool,
 
+    /// Install the database from a local copy instead of downloading it.
+    ///
+    /// Either the database's tarball, which is checked against the MD5 in the registry
+    /// and extracted, or a directory holding the database, which is copied. Use
+    /// `--db-name` and `--db-version` to say which database it is. The registry is the
+    /// one built into nohuman, unless another is given by `--registry-url` (which can be a
+    /// `file://` URL of a local copy), `$NOHUMAN_REGISTRY_URL` or the config file.
+    #[arg(
+        long,
+        value_name = "PATH",
+        value_parser = check_path_exists,
+        conflicts_with = "download",
+        verbatim_doc_comment
+    )]
+    from_file: Option<PathBuf>,
+
+    /// URL of the registry of databases to download from.
+    ///
+    /// A `file://` URL is a local copy of the registry. Repeat to list mirrors, which are
+    /// tried in order. Overrides `$NOHUMAN_REGISTRY_URL` and `registry_urls` in the config
+    /// file (`$NOHUMAN_CONFIG`, by default `nohuman/config.toml` in the user's config
+    /// directory).
+    #[arg(
+        long,
+        value_name = "URL",
+        requires = "install",
+        verbatim_doc_comment
+    )]
+    registry_url: Vec<String>,
+
+    /// URL of the database tarball to download, instead of the one in the registry.
+    ///
+    /// A URL ending in `/` is a mirror of a directory, to which the name of the registry's
+    /// tarball is added. Repeat to list mirrors, which are tried in order. The tarball is
+    /// still checked against the registry's MD5. Overrides `$NOHUMAN_DB_URL` and `db_urls`
+    /// in the config file.
+    #[arg(
+        long,
+        value_name = "URL",
+        requires = "download",
+        verbatim_doc_comment
+    )]
+    db_url: Vec<String>,
+
     /// Path to the database.
     ///
-    /// Defaults to the database location specified in the home directory.
+    /// Either a database directory, or a directory of databases downloaded with
+    /// `--download`, laid out as `<name>/<version>`. Defaults to the database location
+    /// specified in the home directory.
     #[arg(
         short = 'D',
         long = "db",
@@ -100,6 +275,40 @@ struct Args {
     )]
     database: PathBuf,
 
+    /// Name of the database to download or use.
+    ///
+    /// Defaults to the first database in the registry when downloading, and to the only
+    /// database downloaded to `--db` otherwise.
+    #[arg(
+        long,
+        value_name = "NAME",
+        verbatim_doc_comment
+    )]
+    db_name: Option<String>,
+
+    /// Version of the database to download or use.
+    ///
+    /// Defaults to the latest version (the most recently built).
+    #[arg(
+        long,
+        value_name = "VERSION",
+        verbatim_doc_comment
+    )]
+    db_version: Option<String>,
+
+    /// The engine used to classify reads.
+    ///
+    /// "kraken2" runs the `kraken2` executable, which must be installed.
+    /// "native" uses nohuman's built-in classifier, which implements kraken2's
+    /// classification of reads against the same database without needing it installed.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Backend::Kraken2,
+        verbatim_doc_comment
+    )]
+    backend: Backend,
+
     /// Write `kraken2` logging information to filename specified here.
     ///
     /// If not specified, no `kraken2` log is saved.
@@ -121,6 +330,32 @@ struct Args {
     )]
     threads: usize,
 
+    /// Confidence score threshold passed to kraken2 (`--confidence`).
+    ///
+    /// Must be in the range [0, 1]. Higher values require more of a read's k-mers
+    /// to hit human taxa before it is removed, trading sensitivity for fewer
+    /// microbial reads being removed by mistake.
+    #[arg(
+        long,
+        value_name = "FLOAT",
+        default_value_t = 0.0,
+        value_parser = check_confidence,
+        verbatim_doc_comment
+    )]
+    confidence: f64,
+
+    /// Minimum number of hit groups needed to classify a read as human (`--minimum-hit-groups`).
+    ///
+    /// A hit group is an overlapping set of k-mers sharing a minimizer in the database.
+    #[arg(
+        long,
+        value_name = "INT",
+        default_value_t = 2,
+        value_parser = clap::value_parser!(u32).range(1..),
+        verbatim_doc_comment
+    )]
+    minimum_hit_groups: u32,
+
     /// Number of threads to use for compression.
     ///
     /// Defaults to the same value as `--threads` if not specified by the user.
@@ -131,6 +366,29 @@ struct Args {
     )]
     compression_threads: Option<usize>,
 
+    /// Compression format of every output, whatever its file extension.
+    ///
+    /// Outputs that aren't named explicitly get the format's extension. Defaults to the
+    /// format implied by each output's extension, with stdout uncompressed.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        verbatim_doc_comment
+    )]
+    output_format: Option<OutputFormat>,
+
+    /// Compression level of the outputs.
+    ///
+    /// Accepted levels are 1-9 for gz, bgz and bz2, 1-22 for zst, and 0-9 for xz.
+    /// Defaults to 3 for gz, bgz and zst, 6 for xz, and 1 for bz2.
+    #[arg(
+        long,
+        value_name = "INT",
+        verbatim_doc_comment
+    )]
+    compression_level: Option<u32>,
+
     /// Allow overwriting of existing output files.
     ///
     /// If not provided, the process will error out if the output file(s) already exist.
@@ -156,29 +414,99 @@ struct Args {
         verbatim_doc_comment
     )]
     pub stats: Option<PathBuf>,
-}
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+    /// Write a MultiQC custom content file with the sample's host depletion stats.
+    ///
+    /// The file is tab-separated if its extension is `.tsv`, and JSON otherwise. Name it
+    /// `<name>_mqc.json` or `<name>_mqc.tsv` for MultiQC to find it.
+    #[arg(
+        long,
+        value_name = "PATH",
+        verbatim_doc_comment
+    )]
+    multiqc: Option<PathBuf>,
 
-    // Early check: Output1 and Output2 must not be the same file.
-    if let (Some(out1), Some(out2)) = (&args.out1, &args.out2) {
-        if out1 == out2 {
-            bail!("Output1 and Output2 cannot be the same file. Please provide distinct output file names.");
-        }
-    }
+    /// Sample name for the stats and MultiQC files.
+    ///
+    /// Defaults to the name of the first input file without its extensions (and, for
+    /// paired inputs, without a trailing `_1` or `_R1`).
+    #[arg(
+        long,
+        value_name = "NAME",
+        verbatim_doc_comment
+    )]
+    sample_name: Option<String>,
+}
 
-    // Early check: Ensure that existing output files won't be overwritten unless `--overwrite` is provided
-    if let Some(ref out1) = args.out1 {
-        if out1.exists() && !args.overwrite {
-            bail!("Output file '{}' already exists. Use '--overwrite' to allow overwriting existing files.", out1.display());
-        }
+impl Args {
+    /// Whether the run only installs a database, with no reads to process afterwards
+    fn only_installs_database(&self) -> bool {
+        (self.download || self.from_file.is_some())
+            && self.input.is_none()
+            && self.samplesheet.is_none()
     }
+}
 
-    if let Some(ref out2) = args.out2 {
-        if out2.exists() && !args.overwrite {
-            bail!("Output file '{}' already exists. Use '--overwrite' to allow overwriting existing files.", out2.display());
-        }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage the databases downloaded with `--download`
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+
+        /// Directory of downloaded databases
+        #[arg(
+            short = 'D',
+            long = "db",
+            value_name = "PATH",
+            default_value = &**DEFAULT_DB_LOCATION,
+            global = true,
+            verbatim_doc_comment
+        )]
+        database: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// List the installed databases
+    List,
+    /// Show an installed database's metadata (by default, the one used when none is named)
+    Info {
+        name: Option<String>,
+        version: Option<String>,
+    },
+    /// Check the files of installed databases against the checksums recorded when they were
+    /// installed (by default, every database)
+    Verify {
+        name: Option<String>,
+        version: Option<String>,
+    },
+    /// Delete an installed database
+    Remove {
+        name: String,
+        /// The version to delete
+        #[arg(required_unless_present = "old")]
+        version: Option<String>,
+        /// Delete every version but the latest and the version pinned with `set-default`
+        #[arg(long, conflicts_with = "version")]
+        old: bool,
+    },
+    /// Use a database when none is named with `--db-name`
+    SetDefault {
+        name: String,
+        /// Pin this version, rather than using the latest
+        version: Option<String>,
+    },
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    // Early check: the outputs must be usable before anything is downloaded or run
+    let pipeline = args.input.clone().map(|input| build_pipeline(&args, input));
+    if let Some(pipeline) = &pipeline {
+        pipeline.validate()?;
     }
 
     // Initialize logger
@@ -195,24 +523,48 @@ fn main() -> Result<()> {
         .format_target(false)
         .init();
 
+    if let Some(Command::Db { command, database }) = &args.command {
+        return run_db_command(command, &DatabaseStore::new(database));
+    }
+
     // Check if the database exists
-    if !args.database.exists() && !args.download && !args.check {
+    if !args.database.exists() && !args.download && args.from_file.is_none() && !args.check {
         bail!("Database does not exist. Use --download to download the database");
     }
 
+    let store = DatabaseStore::new(&args.database);
+    let (db_name, db_version) = (args.db_name.as_deref(), args.db_version.as_deref());
     if args.download {
         info!("Downloading database...");
-        download_database(&args.database).context("Failed to download database")?;
-        info!("Database downloaded");
-        if args.input.is_none() {
-            info!("No input files provided. Exiting.");
-            return Ok(());
-        }
+        let sources = Sources::load(args.registry_url.clone(), args.db_url.clone())?;
+        let installed =
+            download_database(&store, &sources, db_name, db_version).context("Failed to download database")?;
+        info!("Database {} installed in {:?}", installed.entry.id(), installed.path);
+    } else if let Some(source) = &args.from_file {
+        info!("Installing database from {:?}...", source);
+        // The bundled registry needs no network, so it is used unless another is configured
+        let sources = Sources::load(args.registry_url.clone(), Vec::new())?;
+        let registry = if sources.registry_urls == Sources::default().registry_urls {
+            Registry::parse(BUNDLED_REGISTRY)?
+        } else {
+            download_registry(&sources.registry_urls).context("Failed to read the registry")?
+        };
+        let installed = install_from_file(&store, source, &registry, db_name, db_version)
+            .context("Failed to install database")?;
+        info!("Database {} installed in {:?}", installed.entry.id(), installed.path);
+    }
+    if args.only_installs_database() {
+        info!("No input files provided. Exiting.");
+        return Ok(());
     }
 
     let kraken = CommandRunner::new("kraken2");
 
-    let external_commands = vec![&kraken];
+    // The native backend has no external dependencies
+    let external_commands = match args.backend {
+        Backend::Kraken2 => vec![&kraken],
+        Backend::Native => vec![],
+    };
 
     let mut missing_commands = Vec::new();
     for cmd in external_commands {
@@ -234,231 +586,263 @@ fn main() -> Result<()> {
 
     if args.check {
         info!("All dependencies are available");
+        // The database isn't required for --check, but if there is one make sure it is usable
+        match store.resolve(db_name, db_version) {
+            Ok(db_dir) => {
+                let db = KrakenDatabase::inspect(&db_dir)
+                    .with_context(|| format!("Database at {:?} is invalid", db_dir))?;
+                info!(
+                    "Database at {:?} is valid (k={}, l={}, {} taxa, {} minimizers)",
+                    db_dir,
+                    db.options.k,
+                    db.options.l,
+                    db.taxonomy.node_count() - 1,
+                    db.hash.size
+                );
+                if !db.options.has_expected_parameters() {
+                    warn!(
+                        "Database was built with k={}, l={} but nohuman's database uses k={}, l={}",
+                        db.options.k, db.options.l, EXPECTED_K, EXPECTED_L
+                    );
+                }
+            }
+            Err(e) => debug!("Not checking database: {}", e),
+        }
         return Ok(());
     }
 
-    // error out if input files are not provided, otherwise unwrap to a variable
-    let input = args.input.context("No input files provided")?;
+    if let Some(samplesheet) = &args.samplesheet {
+        let samples = read_samplesheet(samplesheet)
+            .with_context(|| format!("Failed to read sample sheet {}", samplesheet.display()))?;
+        info!("Processing {} samples from {}", samples.len(), samplesheet.display());
+        let database = store.resolve(db_name, db_version)?;
+        let results = run_batch(&build_pipeline(&args, Vec::new()).database(database), &samples, &args.outdir)?;
+
+        let summary = args.outdir.join(SUMMARY_FILE);
+        write_summary(&summary, &results)?;
+        info!("Summary written to {}", summary.display());
+        let failed = results.iter().filter(|result| result.report.is_err()).count();
+        if failed > 0 {
+            bail!("{} of {} samples failed", failed, results.len());
+        }
+        info!("Done.");
+        return Ok(());
+    }
 
-info!("Parsing input files...");
+    // error out if input files are not provided, otherwise unwrap to a variable
+    let pipeline = pipeline.context("No input files provided")?;
+    let database = store.resolve(db_name, db_version)?;
+    let pipeline = pipeline.database(database);
 
-// Early check: determine if the input files are gzip, bzip2 (direct use), or lzma, zstd (decompress first)
-let (mut files_to_decompress, mut output_paths): (Vec<PathBuf>, Vec<PathBuf>) = (Vec::new(), Vec::new());
+    info!("Parsing input files...");
+    pipeline.run()?;
 
-let kraken_input: Vec<PathBuf> = input
-    .iter()
-    .enumerate()
-    .map(|(i, input_file)| {
-        let ext = input_file.extension().unwrap_or_default().to_str().unwrap_or_default();
-        let file_size_mb = std::fs::metadata(input_file).unwrap().len() as f64 / 1_048_576.0;
+    info!("Done.");
 
-        let input_label = if i == 0 { "Input 1" } else { "Input 2" };
-        debug!("{}: Detected format: {}, File size: {:.2} MB", input_label, ext, file_size_mb);
+    Ok(())
+}
 
-        match ext {
-            "gz" | "bz2" | "bgz" => {
-                // Directly use gzip or bzip2 files
-                debug!("{}: Decompression will be handled by kraken2...", input_label);
-                input_file.to_path_buf()
+/// Run a `nohuman db` subcommand
+fn run_db_command(command: &DbCommand, store: &DatabaseStore) -> Result<()> {
+    match command {
+        DbCommand::List => {
+            let (installed, broken) = store.scan()?;
+            if installed.is_empty() && broken.is_empty() {
+                info!("No databases are installed in {:?}", store.root());
+                return Ok(());
             }
-            "xz" | "lzma" | "zst" | "zstd" => {
-                debug!("{}: Decompressing for kraken2 compatibility...", input_label);
-                let decompressed_file = tempfile::Builder::new().suffix(".fq").tempfile().unwrap();
-                let decompressed_path = decompressed_file.path().to_path_buf();
-
-                // Collect paths for decompression
-                files_to_decompress.push(input_file.clone());
-                output_paths.push(decompressed_path.clone());
-
-                decompressed_path // Return the decompressed path for Kraken2
+            let default = store.find_in(&installed, None, None).ok();
+            println!("name\tversion\tbuild_date\tinstalled\tsize\tdefault");
+            for db in &installed {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    db.entry.name,
+                    db.entry.version,
+                    db.entry.build_date.as_deref().unwrap_or("-"),
+                    db.installed.as_deref().unwrap_or("-"),
+                    db.disk_size().map_or("-".to_string(), format_size),
+                    if default.as_ref() == Some(db) { "*" } else { "" }
+                );
             }
-            _ => {
-                // Assume the file is uncompressed
-                debug!("{}: File stem not in {{.gz, .bgz, .bz2, .xz, .lzma, .zst, .zstd}} --> assuming uncompressed...", input_label);
-                input_file.to_path_buf()
+            // Databases whose metadata can't be read are listed so they can be removed
+            for db in &broken {
+                println!("{}\t{}\t-\t-\t-\t", db.name, db.version);
             }
         }
-    })
-    .collect();
-
-    // If there are files to decompress, use read_with_niffler
-    if !files_to_decompress.is_empty() {
-        let compression_threads = args.compression_threads.unwrap_or(1);
-
-        if compression_threads > 1 {
-            // Parallel decompression using 1 thread per file
-            read_with_niffler(files_to_decompress, output_paths, compression_threads)?;
-        } else {
-            // Sequential decompression using a single thread
-            read_with_niffler(files_to_decompress, output_paths, 1)?;
-        }
-    }
-
-    let temp_kraken_output =
-        tempfile::NamedTempFile::new().context("Failed to create temporary kraken output file")?;
-    let threads = args.threads.to_string();
-    let compression_threads = args.compression_threads.unwrap_or(args.threads);
-    let db = validate_db_directory(&args.database)
-        .map_err(|e| anyhow::anyhow!(e))?
-        .to_string_lossy()
-        .to_string();
-    let mut kraken_cmd = vec![
-        "--threads",
-        &threads,
-        "--db",
-        &db,
-        "--output",
-        temp_kraken_output.path().to_str().unwrap(),
-    ];
-    match input.len() {
-        2 => kraken_cmd.push("--paired"),
-        i if i > 2 => bail!("Only one or two input files are allowed"),
-        _ => {}
-    }
-
-    // create a temporary output directory in the current directory and don't delete it
-    let tmpdir = tempfile::Builder::new()
-        .prefix("nohuman")
-        .tempdir_in(std::env::current_dir().unwrap())
-        .context("Failed to create temporary directory")?;
-    let outfile = if input.len() == 2 {
-        tmpdir.path().join("kraken_out#.fq")
-    } else {
-        tmpdir.path().join("kraken_out.fq")
-    };
-    let outfile = outfile.to_string_lossy().to_string();
-    kraken_cmd.extend(&["--unclassified-out", &outfile]);
-
-    kraken_cmd.extend(kraken_input.iter().map(|p| p.to_str().unwrap()));
-    info!("Running kraken2...");
-    debug!("With arguments: {:?}", &kraken_cmd);
-
-    // Run the kraken2 command and capture stdout/stderr
-    let kraken_run = Command::new("kraken2")
-        .args(&kraken_cmd)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .context("Failed to run kraken2")?;
-
-    // Write stderr (= kraken2 logging info) to a log file
-    if let Some(log_path) = &args.kraken2_log {
-        let mut log_file = File::create(log_path).context("Failed to create log file")?;
-        log_file.write_all(&kraken_run.stderr).context("Failed to write `kraken2` stderr to log file")?;
-        debug!("Kraken2 log written to: {:?}", &log_path);
-    }
-
-    if let Some(stats_file) = &args.stats {
-        // capture kraken2 version
-        let kraken_version_run = Command::new("kraken2")
-            .args(["--version"])
-            .stdout(Stdio::piped())
-            .output()
-            .context("Failed to run kraken2")?;
-        
-        // Convert output to string
-        let kraken_version_output = String::from_utf8_lossy(&kraken_version_run.stdout);
-
-        // Extract the version number
-        let kraken_version = kraken_version_output
-            .lines()
-            .find(|line| line.contains("version"))
-            .and_then(|line| line.split_whitespace().nth(2))  // Get the third word (the version number)
-            .unwrap_or("Unknown version")
-            .to_string();
-        
-        let kraken_stderr = String::from_utf8_lossy(&kraken_run.stderr).to_string();
-        let mut stats = parse_kraken_stats(&kraken_stderr)?;
-        stats.kraken2_version = kraken_version;
-        stats.input1 = input[0].display().to_string();
-        stats.output1 = args.out1.clone().unwrap_or_else(|| PathBuf::from("output_1.fq")).display().to_string();
-        if input.len() == 2 {
-            stats.input2 = input[1].display().to_string();
-            stats.output2 = args.out2.clone().unwrap_or_else(|| PathBuf::from("output_2.fq")).display().to_string();
+        DbCommand::Info { name, version } => {
+            let db = store.find(name.as_deref(), version.as_deref())?;
+            println!("name: {}", db.entry.name);
+            println!("version: {}", db.entry.version);
+            if let Some(description) = &db.entry.description {
+                println!("description: {}", description);
+            }
+            println!("path: {}", db.path.display());
+            println!("source: {}", db.entry.url);
+            println!("md5: {}", db.entry.md5);
+            println!("build date: {}", db.entry.build_date.as_deref().unwrap_or("-"));
+            println!("installed: {}", db.installed.as_deref().unwrap_or("-"));
+            if let Some(size) = db.entry.size {
+                println!("download size: {}", format_size(size));
+            }
+            println!("size: {}", format_size(db.disk_size()?));
+            let db_dir = db.database_dir().map_err(|e| anyhow::anyhow!(e))?;
+            let kraken_db = KrakenDatabase::inspect(&db_dir)
+                .with_context(|| format!("Database at {:?} is invalid", db_dir))?;
+            println!("k-mer length (k): {}", kraken_db.options.k);
+            println!("minimizer length (l): {}", kraken_db.options.l);
+            println!("taxa: {}", kraken_db.taxonomy.node_count() - 1);
+            println!("minimizers: {}", kraken_db.hash.size);
         }
-        write_stats(stats_file, &stats)?;
-        debug!("Run stats written to: {:?}", &stats_file);
-    }
-
-    info!("Kraken2 finished. Organising output...");
-
-    if input.len() == 2 {
-        let out1 = args.out1.clone().unwrap_or_else(|| {
-            let parent = input[0].parent().unwrap();
-            let fname: PathBuf = match input[0].extension().unwrap_or_default().to_str() {
-                Some("gz") | Some("bz2") | Some("xz") | Some("lzma") | Some("zst") | Some("zstd") => {
-                    let no_ext = input[0].with_extension("");   // Strip compression extension
-                    let stem = no_ext.file_stem().unwrap();
-                    format!("{}.nohuman.fq.{}", stem.to_string_lossy(), input[0].extension().unwrap().to_string_lossy()).into() // Append correct extension
-                }
-                _ => format!("{}.nohuman.fq", input[0].file_stem().unwrap().to_string_lossy()).into(),  // Uncompressed file
+        DbCommand::Verify { name, version } => {
+            let (databases, broken) = match name {
+                Some(name) => (vec![store.find(Some(name), version.as_deref())?], Vec::new()),
+                None => store.scan()?,
             };
-            parent.join(fname)
-        });
-    
-        let out2 = args.out2.clone().unwrap_or_else(|| {
-            let parent = input[1].parent().unwrap();
-            let fname: PathBuf = match input[1].extension().unwrap_or_default().to_str() {
-                Some("gz") | Some("bgz") | Some("bz2") | Some("xz") | Some("lzma") | Some("zst") | Some("zstd") => {
-                    let no_ext = input[1].with_extension("");   // Strip compression extension
-                    let stem = no_ext.file_stem().unwrap();
-                    format!("{}.nohuman.fq.{}", stem.to_string_lossy(), input[1].extension().unwrap().to_string_lossy()).into() // Append correct extension
+            let mut failed = broken.len();
+            for db in &broken {
+                error!("{}: {}", db.id(), db.error);
+            }
+            for db in &databases {
+                info!("Verifying {}...", db.entry.id());
+                let problems = db.verify();
+                for problem in &problems {
+                    error!("{}: {}", db.entry.id(), problem);
                 }
-                _ => format!("{}.nohuman.fq", input[1].file_stem().unwrap().to_string_lossy()).into(),  // Uncompressed file
-            };
-            parent.join(fname)
-        });
-    
-        let tmpout1 = tmpdir.path().join("kraken_out_1.fq");
-        let tmpout2 = tmpdir.path().join("kraken_out_2.fq");
-    
-        // Write out the results with compression based on the extension
-        debug!("Writing output files...");
-        write_output(&tmpout1, Some(&tmpout2), &out1, Some(&out2), compression_threads)?;
-
-        // Log output format and file sizes
-        if args.verbose {
-            let output_format1 = out1.extension().unwrap_or_default().to_str().unwrap_or_default();
-            let output_format2 = out2.extension().unwrap_or_default().to_str().unwrap_or_default();
-            let out1_size_mb = std::fs::metadata(&out1).unwrap().len() as f64 / 1_048_576.0;
-            let out2_size_mb = std::fs::metadata(&out2).unwrap().len() as f64 / 1_048_576.0;
-            debug!("Output 1 ({} compression) written to: {} ({:.2} MB)", output_format1, out1.display(), out1_size_mb);
-            debug!("Output 2 ({} compression) written to: {} ({:.2} MB)", output_format2, out2.display(), out2_size_mb);
+                if problems.is_empty() {
+                    info!("{} is intact", db.entry.id());
+                } else {
+                    failed += 1;
+                }
+            }
+            if failed > 0 {
+                bail!(
+                    "{} of {} databases failed verification",
+                    failed,
+                    databases.len() + broken.len()
+                );
+            }
         }
-    } else {
-        let out1 = args.out1.clone().unwrap_or_else(|| {
-            let parent = input[0].parent().unwrap();
-            let fname: PathBuf = match input[0].extension().unwrap_or_default().to_str() {
-                Some("gz") | Some("bz2") | Some("xz") | Some("zst") => {
-                    let no_ext = input[0].with_extension("");
-                    let stem = no_ext.file_stem().unwrap();
-                    format!("{}.nohuman.fq.{}", stem.to_string_lossy(), input[0].extension().unwrap().to_string_lossy()).into()
+        DbCommand::Remove { name, version, old } => {
+            let versions: Vec<String> = match version {
+                Some(version) => vec![version.clone()],
+                None => {
+                    debug_assert!(*old);
+                    store.old_versions(name)?
                 }
-                _ => format!("{}.nohuman.fq", input[0].file_stem().unwrap().to_string_lossy()).into(),
             };
-            parent.join(fname)
-        });
-    
-        let tmpout1 = tmpdir.path().join("kraken_out.fq");
-    
-        // Write out the results for out1
-        debug!("Writing output file...");
-        write_output(&tmpout1, None, &out1, None, compression_threads)?;
-        
-        // Log output format and file size
-        if args.verbose {
-            let output_format = out1.extension().unwrap_or_default().to_str().unwrap_or_default();
-            let out1_size_mb = std::fs::metadata(&out1).unwrap().len() as f64 / 1_048_576.0;
-            debug!("Output ({} compression) written to: {} ({:.2} MB)", output_format, out1.display(), out1_size_mb);
+            if versions.is_empty() {
+                info!("No old versions of {} are installed", name);
+            }
+            for version in versions {
+                let path = store.remove(name, &version)?;
+                info!("Removed {}/{} from {:?}", name, version, path);
+            }
+        }
+        DbCommand::SetDefault { name, version } => {
+            store.set_default(name, version.as_deref())?;
+            let db = store.find(None, None)?;
+            info!("{} is now the default database", db.entry.id());
         }
     }
+    Ok(())
+}
 
-    // Cleanup the temporary directory, but only issue a warning if it fails
-    if let Err(e) = tmpdir.close() {
-        warn!("Failed to remove temporary output directory: {}", e);
+/// A size in bytes in human-readable units
+fn format_size(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
     }
-    
-    info!("Done.");
-    
-    Ok(())
-}
\ No newline at end of file
+    format!("{:.1} {}", size, units[unit])
+}
+
+/// Translate the command-line arguments into a pipeline for the given input files
+fn build_pipeline(args: &Args, input: Vec<PathBuf>) -> Pipeline {
+    let mut pipeline = Pipeline::new(input)
+        .keep(args.keep)
+        .database(&args.database)
+        .backend(args.backend)
+        .interleaved(args.interleaved)
+        .threads(args.threads)
+        .confidence(args.confidence)
+        .minimum_hit_groups(args.minimum_hit_groups)
+        .overwrite(args.overwrite);
+    if let Some(out1) = &args.out1 {
+        pipeline = pipeline.out1(out1);
+    }
+    if let Some(out2) = &args.out2 {
+        pipeline = pipeline.out2(out2);
+    }
+    if let Some(human_out1) = &args.human_out1 {
+        pipeline = pipeline.human_out1(human_out1);
+    }
+    if let Some(human_out2) = &args.human_out2 {
+        pipeline = pipeline.human_out2(human_out2);
+    }
+    if let Some(mode) = args.mask {
+        pipeline = pipeline.mask(mode);
+    }
+    if let Some(removed_ids) = &args.removed_ids {
+        pipeline = pipeline.removed_ids(removed_ids);
+    }
+    if let Some(report) = &args.report {
+        pipeline = pipeline.report(report).report_minimizer_data(args.report_minimizer_data);
+    }
+    if let Some(stats) = &args.stats {
+        pipeline = pipeline.stats(stats);
+    }
+    if let Some(multiqc) = &args.multiqc {
+        pipeline = pipeline.multiqc(multiqc);
+    }
+    if let Some(sample_name) = &args.sample_name {
+        pipeline = pipeline.sample_name(sample_name);
+    }
+    if let Some(kraken2_log) = &args.kraken2_log {
+        pipeline = pipeline.kraken2_log(kraken2_log);
+    }
+    if let Some(threads) = args.compression_threads {
+        pipeline = pipeline.compression_threads(threads);
+    }
+    if let Some(format) = args.output_format {
+        pipeline = pipeline.output_format(format);
+    }
+    if let Some(level) = args.compression_level {
+        pipeline = pipeline.compression_level(level);
+    }
+    pipeline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_installs_database() {
+        let tmp = tempfile::tempdir().unwrap();
+        let reads = tmp.path().join("reads.fq");
+        let sheet = tmp.path().join("samples.csv");
+        std::fs::write(&reads, "").unwrap();
+        std::fs::write(&sheet, "").unwrap();
+        let parse = |args: &[&str]| {
+            let path = |p: &PathBuf| p.to_str().unwrap().to_string();
+            let args = args.iter().map(|arg| match *arg {
+                "READS" => path(&reads),
+                "SHEET" => path(&sheet),
+                arg => arg.to_string(),
+            });
+            Args::try_parse_from(std::iter::once("nohuman".to_string()).chain(args)).unwrap()
+        };
+
+        assert!(parse(&["--download"]).only_installs_database());
+        assert!(!parse(&["--download", "READS"]).only_installs_database());
+        assert!(!parse(&["--download", "--samplesheet", "SHEET"]).only_installs_database());
+        assert!(!parse(&["--samplesheet", "SHEET"]).only_installs_database());
+
+        let registry = "file:///srv/nohuman/config.toml";
+        assert!(parse(&["--from-file", "READS", "--registry-url", registry]).only_installs_database());
+        assert!(Args::try_parse_from(["nohuman", "--registry-url", registry, "in.fq"]).is_err());
+    }
+}
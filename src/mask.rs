@@ -0,0 +1,290 @@
+use crate::classify::per_read::{KrakenRecord, PerReadError, PerReadReader};
+use crate::fastx::{FastxError, Record};
+use crate::interleave::{next_record, read_chunks, CHUNK_SIZE};
+use crate::MaskMode;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use thiserror::Error;
+
+/// The quality score given to masked bases: the lowest there is (Phred 0)
+pub const MASK_QUALITY: u8 = b'!';
+
+#[derive(Error, Debug)]
+pub enum MaskError {
+    #[error("The classifier's per-read output lists read {expected} where it wrote {found}")]
+    ReadMismatch { expected: String, found: String },
+
+    #[error("The classifier's per-read output lists more reads than it wrote")]
+    MissingRead,
+
+    #[error("The classifier wrote more reads than its per-read output lists")]
+    ExtraRead,
+
+    #[error(transparent)]
+    PerReadError(#[from] PerReadError),
+
+    #[error(transparent)]
+    FastxError(#[from] FastxError),
+
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// Mask a read in place, keeping its name
+pub fn mask_record(record: &mut Record, mode: MaskMode) {
+    match mode {
+        MaskMode::N => {
+            record.seq.fill(b'N');
+            if let Some(qual) = &mut record.qual {
+                qual.fill(MASK_QUALITY);
+            }
+        }
+        MaskMode::Stub => {
+            record.seq = b"N".to_vec();
+            if let Some(qual) = &mut record.qual {
+                *qual = vec![MASK_QUALITY];
+            }
+        }
+    }
+}
+
+/// Put the classifier's unclassified and classified reads back into their input order, as given
+/// by its per-read (kraken2 `--output`) output, masking the classified (human) reads. Returns the
+/// number of reads (or pairs) masked.
+///
+/// `unclassified` and `classified` hold one input per mate. Reads are written to the output for
+/// their mate or, if there is only one output, interleaved into it. Each input is read on its own
+/// thread, as the classifier may write a large block to one before writing to the others.
+pub fn mask_reads<R: Read + Send, W: Write>(
+    per_read: R,
+    unclassified: Vec<R>,
+    classified: Vec<R>,
+    outputs: Vec<W>,
+    mode: MaskMode,
+) -> Result<usize, MaskError> {
+    std::thread::scope(|scope| {
+        let (decision_sender, decisions) = mpsc::channel();
+        let decision_reader = scope.spawn(move || read_decisions(per_read, decision_sender));
+
+        let mut record_readers = Vec::new();
+        let mut streams = [Vec::new(), Vec::new()];
+        for (inputs, set) in [unclassified, classified]
+            .into_iter()
+            .zip(streams.iter_mut())
+        {
+            for input in inputs {
+                let (sender, receiver) = mpsc::channel();
+                set.push(receiver);
+                record_readers.push(scope.spawn(move || read_chunks(input, sender)));
+            }
+        }
+        let result = merge(decisions, streams, outputs, mode);
+
+        // An input that can't be read ends early, which would otherwise be reported as a missing read
+        decision_reader
+            .join()
+            .expect("Per-read output thread panicked")?;
+        for handle in record_readers {
+            handle.join().expect("Mask thread panicked")?;
+        }
+        result
+    })
+}
+
+/// Parse the per-read output in `input` and send it in chunks, stopping early if the receiver
+/// hangs up
+fn read_decisions<R: Read>(
+    input: R,
+    sender: Sender<Vec<KrakenRecord>>,
+) -> Result<(), PerReadError> {
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    for decision in PerReadReader::new(BufReader::new(input)) {
+        chunk.push(decision?);
+        if chunk.len() == CHUNK_SIZE && sender.send(std::mem::take(&mut chunk)).is_err() {
+            return Ok(());
+        }
+    }
+    if !chunk.is_empty() {
+        let _ = sender.send(chunk);
+    }
+    Ok(())
+}
+
+fn merge<W: Write>(
+    decisions: Receiver<Vec<KrakenRecord>>,
+    streams: [Vec<Receiver<Vec<Record>>>; 2],
+    outputs: Vec<W>,
+    mode: MaskMode,
+) -> Result<usize, MaskError> {
+    let mut outputs: Vec<BufWriter<W>> = outputs.into_iter().map(BufWriter::new).collect();
+    let mut buffers: [Vec<std::vec::IntoIter<Record>>; 2] = [
+        streams[0].iter().map(|_| Vec::new().into_iter()).collect(),
+        streams[1].iter().map(|_| Vec::new().into_iter()).collect(),
+    ];
+    let mut masked = 0;
+    for decision in decisions.iter().flatten() {
+        let human = decision.classified;
+        let set = usize::from(human);
+        for (mate, (buffer, stream)) in buffers[set].iter_mut().zip(&streams[set]).enumerate() {
+            let mut record = next_record(buffer, stream).ok_or(MaskError::MissingRead)?;
+            check_read_id(&decision, &record)?;
+            if human {
+                mask_record(&mut record, mode);
+            }
+            let output = mate.min(outputs.len() - 1);
+            record.write(&mut outputs[output])?;
+        }
+        masked += usize::from(human);
+    }
+
+    for (buffers, streams) in buffers.iter_mut().zip(&streams) {
+        for (buffer, stream) in buffers.iter_mut().zip(streams) {
+            if next_record(buffer, stream).is_some() {
+                return Err(MaskError::ExtraRead);
+            }
+        }
+    }
+    for output in &mut outputs {
+        output.flush()?;
+    }
+    Ok(masked)
+}
+
+/// Check that a read written by the classifier is the one its per-read output has next. Pairs are
+/// listed under the name of their first mate, without its `/1` suffix.
+fn check_read_id(decision: &KrakenRecord, record: &Record) -> Result<(), MaskError> {
+    let id = if decision.paired() {
+        record.pair_name()
+    } else {
+        record.id()
+    };
+    if id == decision.read_id.as_bytes() {
+        Ok(())
+    } else {
+        Err(MaskError::ReadMismatch {
+            expected: decision.read_id.clone(),
+            found: String::from_utf8_lossy(record.id()).to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fastq(reads: &[(&str, &str)]) -> String {
+        reads
+            .iter()
+            .map(|(id, seq)| format!("@{}\n{}\n+\n{}\n", id, seq, "I".repeat(seq.len())))
+            .collect()
+    }
+
+    #[test]
+    fn test_mask_record() {
+        let mut record = Record {
+            header: b"r1 kraken:taxid|9606".to_vec(),
+            seq: b"ACGT".to_vec(),
+            qual: Some(b"IIII".to_vec()),
+        };
+        mask_record(&mut record, MaskMode::N);
+        assert_eq!(record.seq, b"NNNN");
+        assert_eq!(record.qual.as_deref(), Some(&b"!!!!"[..]));
+
+        mask_record(&mut record, MaskMode::Stub);
+        assert_eq!(record.seq, b"N");
+        assert_eq!(record.qual.as_deref(), Some(&b"!"[..]));
+        assert_eq!(record.header, b"r1 kraken:taxid|9606");
+    }
+
+    #[test]
+    fn test_mask_reads_keeps_input_order() {
+        let per_read = "U\tr1\t0\t4\t\nC\tr2\t9606\t4\t\nU\tr3\t0\t2\t\n";
+        let unclassified = fastq(&[("r1", "ACGT"), ("r3", "GG")]);
+        let classified = fastq(&[("r2 kraken:taxid|9606", "TTTT")]);
+        let mut output = Vec::new();
+        let masked = mask_reads(
+            per_read.as_bytes(),
+            vec![unclassified.as_bytes()],
+            vec![classified.as_bytes()],
+            vec![&mut output],
+            MaskMode::N,
+        )
+        .unwrap();
+        assert_eq!(masked, 1);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "@r1\nACGT\n+\nIIII\n@r2 kraken:taxid|9606\nNNNN\n+\n!!!!\n@r3\nGG\n+\nII\n"
+        );
+    }
+
+    #[test]
+    fn test_mask_reads_paired_interleaved() {
+        let per_read = "C\tr1\t9606\t2|2\t\nU\tr2\t0\t2|2\t\n";
+        let (unclassified1, unclassified2) = (fastq(&[("r2/1", "AA")]), fastq(&[("r2/2", "CC")]));
+        let (classified1, classified2) = (fastq(&[("r1/1", "GG")]), fastq(&[("r1/2", "TT")]));
+        let mut output = Vec::new();
+        mask_reads(
+            per_read.as_bytes(),
+            vec![unclassified1.as_bytes(), unclassified2.as_bytes()],
+            vec![classified1.as_bytes(), classified2.as_bytes()],
+            vec![&mut output],
+            MaskMode::Stub,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "@r1/1\nN\n+\n!\n@r1/2\nN\n+\n!\n@r2/1\nAA\n+\nII\n@r2/2\nCC\n+\nII\n"
+        );
+    }
+
+    #[test]
+    fn test_mask_reads_inconsistent_outputs() {
+        let reads = fastq(&[("r1", "ACGT")]);
+        let per_read = "U\tr1\t0\t4\nU\tr2\t0\t4\n";
+        let err = mask_reads(
+            per_read.as_bytes(),
+            vec![reads.as_bytes()],
+            vec![&b""[..]],
+            vec![io::sink()],
+            MaskMode::N,
+        )
+        .unwrap_err();
+        assert!(matches!(err, MaskError::MissingRead));
+
+        let err = mask_reads(
+            "U\tr2\t0\t4\n".as_bytes(),
+            vec![reads.as_bytes()],
+            vec![&b""[..]],
+            vec![io::sink()],
+            MaskMode::N,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The classifier's per-read output lists read r2 where it wrote r1"
+        );
+
+        let err = mask_reads(
+            "".as_bytes(),
+            vec![reads.as_bytes()],
+            vec![&b""[..]],
+            vec![io::sink()],
+            MaskMode::N,
+        )
+        .unwrap_err();
+        assert!(matches!(err, MaskError::ExtraRead));
+
+        let err = mask_reads(
+            "X\tr1\t0\t4\n".as_bytes(),
+            vec![reads.as_bytes()],
+            vec![&b""[..]],
+            vec![io::sink()],
+            MaskMode::N,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            MaskError::PerReadError(PerReadError::InvalidLine { line: 1, .. })
+        ));
+    }
+}
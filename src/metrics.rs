@@ -0,0 +1,248 @@
+use crate::classify::per_read::{KrakenRecord, PerReadError, PerReadReader};
+use crate::removed::human_kmer_fraction;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, BufReader, Read};
+
+/// Read and base counts for one input file (or one mate of interleaved reads)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct InputCounts {
+    pub reads: u64,
+    pub bases: u64,
+    pub reads_removed: u64,
+    pub bases_removed: u64,
+}
+
+/// How many read pairs have k-mers hitting human taxa in both mates, one mate or neither. Pairs
+/// are classified as a unit, so this is the evidence behind each pair's classification.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PairCounts {
+    pub both_mates: u64,
+    pub one_mate: u64,
+    pub neither_mate: u64,
+}
+
+/// A summary of read lengths
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct LengthSummary {
+    pub reads: u64,
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+    pub n50: usize,
+}
+
+/// The number of reads of each length
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LengthHistogram(BTreeMap<usize, u64>);
+
+impl LengthHistogram {
+    pub fn add(&mut self, length: usize) {
+        *self.0.entry(length).or_insert(0) += 1;
+    }
+
+    pub fn summary(&self) -> LengthSummary {
+        let reads: u64 = self.0.values().sum();
+        if reads == 0 {
+            return LengthSummary::default();
+        }
+        let bases: u64 = self
+            .0
+            .iter()
+            .map(|(&length, &count)| length as u64 * count)
+            .sum();
+        // The length at which the reads at least as long hold half of the bases
+        let mut cumulative = 0;
+        let n50 = self
+            .0
+            .iter()
+            .rev()
+            .find(|(&length, &count)| {
+                cumulative += length as u64 * count;
+                cumulative * 2 >= bases
+            })
+            .map_or(0, |(&length, _)| length);
+        LengthSummary {
+            reads,
+            min: self.0.keys().next().copied().unwrap_or(0),
+            max: self.0.keys().next_back().copied().unwrap_or(0),
+            mean: bases as f64 / reads as f64,
+            n50,
+        }
+    }
+}
+
+/// Statistics of the reads gathered from the classifier's per-read output
+#[derive(Debug, Clone, Default)]
+pub struct ReadMetrics {
+    /// Reads (or pairs) seen
+    pub records: u64,
+    /// Counts for each input file (or mate)
+    pub inputs: Vec<InputCounts>,
+    /// Counts for read pairs, if the reads are paired
+    pub pairs: Option<PairCounts>,
+    /// Lengths of all reads
+    pub lengths_before: LengthHistogram,
+    /// Lengths of the reads not classified as human
+    pub lengths_after: LengthHistogram,
+}
+
+impl ReadMetrics {
+    /// Gather the statistics from the classifier's per-read output. All of `per_read` is read,
+    /// even if it can't be parsed, so the classifier is never left unable to write it.
+    pub fn collect<R: Read>(per_read: R) -> Result<Self, PerReadError> {
+        let mut reader = BufReader::new(per_read);
+        let mut metrics = Self::default();
+        let result = PerReadReader::new(&mut reader).try_for_each(|record| {
+            metrics.add(&record?);
+            Ok(())
+        });
+        if result.is_err() {
+            io::copy(&mut reader, &mut io::sink())?;
+        }
+        result.map(|_| metrics)
+    }
+
+    /// Count a read or pair
+    pub fn add(&mut self, record: &KrakenRecord) {
+        self.records += 1;
+        if self.inputs.len() < record.lengths.len() {
+            self.inputs
+                .resize(record.lengths.len(), InputCounts::default());
+        }
+        for (counts, &length) in self.inputs.iter_mut().zip(&record.lengths) {
+            counts.reads += 1;
+            counts.bases += length as u64;
+            if record.classified {
+                counts.reads_removed += 1;
+                counts.bases_removed += length as u64;
+            }
+            self.lengths_before.add(length);
+            if !record.classified {
+                self.lengths_after.add(length);
+            }
+        }
+        if record.paired() {
+            let human_mates = record
+                .hits
+                .iter()
+                .filter(|hits| human_kmer_fraction(hits) > 0.0)
+                .count();
+            let pairs = self.pairs.get_or_insert_with(PairCounts::default);
+            match human_mates {
+                2 => pairs.both_mates += 1,
+                1 => pairs.one_mate += 1,
+                _ => pairs.neither_mate += 1,
+            }
+        }
+    }
+
+    pub fn total_bases(&self) -> u64 {
+        self.inputs.iter().map(|counts| counts.bases).sum()
+    }
+
+    pub fn bases_removed(&self) -> u64 {
+        self.inputs.iter().map(|counts| counts.bases_removed).sum()
+    }
+}
+
+/// Wall-clock time spent in each phase of a run. Outputs are compressed while the reads are being
+/// classified, so `compression_seconds` is only the time taken to finish them afterwards.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Timings {
+    /// Decompressing inputs the classifier can't read itself
+    pub decompression_seconds: f64,
+    /// Running the classifier, including loading the database if it loads it itself
+    pub classification_seconds: f64,
+    /// Finishing the compression of the outputs once classification was done
+    pub compression_seconds: f64,
+    pub total_seconds: f64,
+}
+
+/// The peak resident memory, in megabytes, reported in `usage`
+pub fn maxrss_mb(usage: &libc::rusage) -> f64 {
+    // Linux reports kilobytes, macOS bytes
+    let bytes = if cfg!(target_os = "macos") {
+        usage.ru_maxrss as f64
+    } else {
+        usage.ru_maxrss as f64 * 1024.0
+    };
+    bytes / 1_048_576.0
+}
+
+/// The peak resident memory, in megabytes, of the nohuman process so far. This is a high-water
+/// mark for the whole process, so after several runs in one process (e.g., a batch) it is the
+/// largest of them.
+pub fn process_peak_memory_mb() -> f64 {
+    // SAFETY: `usage` is a valid rusage struct for `getrusage` to fill in
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return 0.0;
+    }
+    maxrss_mb(&usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length_summary() {
+        let mut histogram = LengthHistogram::default();
+        for length in [2, 2, 2, 3, 8] {
+            histogram.add(length);
+        }
+        assert_eq!(
+            histogram.summary(),
+            LengthSummary {
+                reads: 5,
+                min: 2,
+                max: 8,
+                mean: 3.4,
+                n50: 3,
+            }
+        );
+        assert_eq!(
+            LengthHistogram::default().summary(),
+            LengthSummary::default()
+        );
+    }
+
+    #[test]
+    fn test_collect_paired() {
+        let per_read = "U\tr1\t0\t100|90\t0:70 |:| 9606:1 0:59\n\
+                        C\tr2\t9606\t100|100\t9606:70 |:| 9606:70\n\
+                        U\tr3\t0\t50|50\t0:20 |:| 0:20\n";
+        let metrics = ReadMetrics::collect(per_read.as_bytes()).unwrap();
+        assert_eq!(metrics.records, 3);
+        assert_eq!((metrics.total_bases(), metrics.bases_removed()), (490, 200));
+        assert_eq!(
+            metrics.inputs[1],
+            InputCounts {
+                reads: 3,
+                bases: 240,
+                reads_removed: 1,
+                bases_removed: 100,
+            }
+        );
+        assert_eq!(
+            metrics.pairs,
+            Some(PairCounts {
+                both_mates: 1,
+                one_mate: 1,
+                neither_mate: 1,
+            })
+        );
+        assert_eq!(metrics.lengths_before.summary().max, 100);
+        assert_eq!(metrics.lengths_after.summary().reads, 4);
+    }
+
+    #[test]
+    fn test_collect_reads_invalid_input_to_the_end() {
+        let mut per_read = "U\tr1\n".to_string();
+        per_read.push_str(&"U\tr2\t0\t4\t\n".repeat(10_000));
+        let mut input = per_read.as_bytes();
+        assert!(ReadMetrics::collect(&mut input).is_err());
+        assert!(input.is_empty());
+    }
+}
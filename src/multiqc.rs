@@ -0,0 +1,190 @@
+use crate::Stats;
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+/// The id of nohuman's section in MultiQC reports. Files with the same id are merged into one
+/// table, so a batch of samples gets a single host depletion section.
+pub const MULTIQC_ID: &str = "nohuman";
+
+/// The columns of nohuman's MultiQC table, after the sample name
+const COLUMNS: [&str; 4] = ["reads_in", "reads_out", "percent_human", "database_version"];
+
+/// Whether `path` has a name MultiQC picks up as custom content without any configuration
+pub fn is_multiqc_file_name(path: &Path) -> bool {
+    let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+    name.ends_with("_mqc.json") || name.ends_with("_mqc.tsv")
+}
+
+/// Write a MultiQC custom content file with a row for the sample: its reads (or pairs) in and out,
+/// the percentage classified as human and the database version.
+///
+/// The file is tab-separated, with the section's settings in comment lines, if its extension is
+/// `.tsv` and JSON otherwise.
+pub fn write_multiqc(path: &Path, stats: &Stats) -> Result<()> {
+    let content = if path.extension() == Some(OsStr::new("tsv")) {
+        multiqc_tsv(stats)
+    } else {
+        format!("{}\n", serde_json::to_string_pretty(&multiqc_json(stats))?)
+    };
+    std::fs::write(path, content)
+        .with_context(|| format!("Failed to write MultiQC file {}", path.display()))
+}
+
+fn multiqc_json(stats: &Stats) -> serde_json::Value {
+    json!({
+        "id": MULTIQC_ID,
+        "section_name": "nohuman",
+        "description": "Host (human) read removal by nohuman. Paired reads are counted as pairs.",
+        "plot_type": "table",
+        "pconfig": {
+            "id": "nohuman_table",
+            "title": "nohuman: host depletion",
+        },
+        "headers": {
+            "reads_in": {
+                "title": "Reads in",
+                "description": "Reads (or pairs) classified",
+                "format": "{:,.0f}",
+            },
+            "reads_out": {
+                "title": "Reads out",
+                "description": "Reads (or pairs) not classified as human",
+                "format": "{:,.0f}",
+            },
+            "percent_human": {
+                "title": "% Human",
+                "description": "Percentage of reads (or pairs) classified as human",
+                "min": 0,
+                "max": 100,
+                "suffix": "%",
+                "scale": "OrRd",
+            },
+            "database_version": {
+                "title": "Database",
+                "description": "Version of the nohuman database",
+            },
+        },
+        "data": {
+            stats.sample.as_str(): {
+                "reads_in": stats.total_sequences,
+                "reads_out": stats.sequences_remaining,
+                "percent_human": percent_human(stats),
+                "database_version": stats.database_version,
+            },
+        },
+    })
+}
+
+fn multiqc_tsv(stats: &Stats) -> String {
+    format!(
+        "# id: '{}'\n\
+         # section_name: 'nohuman'\n\
+         # description: 'Host (human) read removal by nohuman. Paired reads are counted as pairs.'\n\
+         # plot_type: 'table'\n\
+         Sample\t{}\n\
+         {}\t{}\t{}\t{:.4}\t{}\n",
+        MULTIQC_ID,
+        COLUMNS.join("\t"),
+        stats.sample,
+        stats.total_sequences,
+        stats.sequences_remaining,
+        percent_human(stats),
+        stats.database_version
+    )
+}
+
+fn percent_human(stats: &Stats) -> f64 {
+    stats.proportion_removed * 100.0
+}
+
+/// The sample name for a run's inputs: the name of the first input without its extensions (and,
+/// for paired inputs, without a trailing `_1`/`_R1`), or `stdin`
+pub fn sample_name(inputs: &[PathBuf]) -> String {
+    let Some(input) = inputs.first().filter(|input| !crate::is_stdio(input)) else {
+        return "stdin".to_string();
+    };
+    let mut name = input
+        .file_name()
+        .and_then(OsStr::to_str)
+        .unwrap_or_default();
+    for extension in [".gz", ".bgz", ".bz2", ".xz", ".lzma", ".zst", ".zstd"] {
+        name = name.strip_suffix(extension).unwrap_or(name);
+    }
+    for extension in [".fastq", ".fq", ".fasta", ".fa", ".fna"] {
+        name = name.strip_suffix(extension).unwrap_or(name);
+    }
+    if inputs.len() > 1 {
+        for suffix in ["_R1", "_1"] {
+            if let Some(stripped) = name
+                .strip_suffix(suffix)
+                .filter(|stripped| !stripped.is_empty())
+            {
+                name = stripped;
+                break;
+            }
+        }
+    }
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn stats() -> Stats {
+        let mut stats = Stats::from_counts(200, 50, 150);
+        stats.sample = "sample1".to_string();
+        stats.database_version = "HPRC.r1".to_string();
+        stats
+    }
+
+    #[test]
+    fn test_write_multiqc_json() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sample1_mqc.json");
+        write_multiqc(&path, &stats()).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["id"], MULTIQC_ID);
+        assert_eq!(
+            json["data"]["sample1"],
+            json!({"reads_in": 200, "reads_out": 150, "percent_human": 25.0, "database_version": "HPRC.r1"})
+        );
+        for column in COLUMNS {
+            assert!(json["headers"][column]["title"].is_string());
+        }
+    }
+
+    #[test]
+    fn test_write_multiqc_tsv() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sample1_mqc.tsv");
+        write_multiqc(&path, &stats()).unwrap();
+        let tsv = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = tsv.lines().filter(|line| !line.starts_with('#')).collect();
+        assert_eq!(
+            lines,
+            [
+                "Sample\treads_in\treads_out\tpercent_human\tdatabase_version",
+                "sample1\t200\t150\t25.0000\tHPRC.r1"
+            ]
+        );
+        assert!(tsv.starts_with("# id: 'nohuman'\n"));
+    }
+
+    #[test]
+    fn test_sample_name() {
+        let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(sample_name(&paths(&["data/s1.fastq.gz"])), "s1");
+        assert_eq!(sample_name(&paths(&["s1_1.fq"])), "s1_1");
+        assert_eq!(sample_name(&paths(&["s1_R1.fq.zst", "s1_R2.fq.zst"])), "s1");
+        assert_eq!(sample_name(&paths(&["s1_1.fq", "s1_2.fq"])), "s1");
+        assert_eq!(sample_name(&paths(&["_1.fq", "_2.fq"])), "_1");
+        assert_eq!(sample_name(&paths(&["-"])), "stdin");
+        assert!(is_multiqc_file_name(Path::new("out/s1_mqc.tsv")));
+        assert!(!is_multiqc_file_name(Path::new("out/s1.json")));
+    }
+}
@@ -0,0 +1,1648 @@
+use crate::classify::kraken2::Kraken2Classifier;
+use crate::classify::native::NativeClassifier;
+use crate::classify::{Classifier, ClassifierOptions, ClassifyError, ClassifyJob, ClassifySummary};
+use crate::fastx::{detect_compression, FastxError, Reader};
+use crate::fifo::{self, FeedPipe, Interruptible, NamedPipe};
+use crate::interleave::{deinterleave, interleave, InterleaveError};
+use crate::mask::mask_reads;
+use crate::metrics::{process_peak_memory_mb, ReadMetrics, Timings};
+use crate::multiqc::{is_multiqc_file_name, sample_name, write_multiqc};
+use crate::removed::write_removed_ids;
+use crate::{
+    create_output, database_version, default_output_path, is_stdio, remove_partial_outputs,
+    validate_db_directory, with_compression_extension, write_output, write_stats, Backend,
+    CommandError, Keep, MaskMode, OutputFormat, OutputSettings, Stats, STDIO,
+};
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// A host removal run: classify the input reads and write the non-human (and/or human) reads.
+///
+/// Only the inputs are required; everything else has the same defaults as the `nohuman` CLI.
+/// A database (or a ready-made classifier) must be given before calling [`Pipeline::run`].
+///
+/// ```no_run
+/// use nohuman::{Keep, Pipeline};
+///
+/// let report = Pipeline::new(vec!["in_1.fq.gz".into(), "in_2.fq.gz".into()])
+///     .database("/data/nohuman/db")
+///     .keep(Keep::Both)
+///     .threads(8)
+///     .stats("stats.json")
+///     .run()?;
+/// println!("Removed {} read pairs", report.stats.sequences_removed);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct Pipeline {
+    inputs: Vec<PathBuf>,
+    interleaved: bool,
+    out: [Option<PathBuf>; 2],
+    human_out: [Option<PathBuf>; 2],
+    keep: Keep,
+    mask: Option<MaskMode>,
+    database: Option<PathBuf>,
+    backend: Backend,
+    classifier: Option<Arc<dyn Classifier>>,
+    memory_mapping: bool,
+    threads: usize,
+    compression_threads: Option<usize>,
+    output_format: Option<OutputFormat>,
+    compression_level: Option<u32>,
+    confidence: f64,
+    minimum_hit_groups: u32,
+    overwrite: bool,
+    stats: Option<PathBuf>,
+    kraken2_log: Option<PathBuf>,
+    removed_ids: Option<PathBuf>,
+    report: Option<PathBuf>,
+    report_minimizer_data: bool,
+    sample_name: Option<String>,
+    multiqc: Option<PathBuf>,
+}
+
+/// The outcome of a successful [`Pipeline::run`]
+#[derive(Debug)]
+pub struct RunReport {
+    /// Where the non-human reads were written (empty if they were not kept)
+    pub nonhuman_outputs: Vec<PathBuf>,
+    /// Where the human reads were written (empty if they were not kept)
+    pub human_outputs: Vec<PathBuf>,
+    /// The counts reported by the classifier
+    pub summary: ClassifySummary,
+    /// The run's statistics, as written to the stats file
+    pub stats: Stats,
+}
+
+impl Pipeline {
+    /// A pipeline for one (single-end or interleaved) or two (paired-end) input files. An input of
+    /// `-` is read from stdin.
+    pub fn new(inputs: Vec<PathBuf>) -> Self {
+        Self {
+            inputs,
+            interleaved: false,
+            out: [None, None],
+            human_out: [None, None],
+            keep: Keep::Nonhuman,
+            mask: None,
+            database: None,
+            backend: Backend::Kraken2,
+            classifier: None,
+            memory_mapping: false,
+            threads: 1,
+            compression_threads: None,
+            output_format: None,
+            compression_level: None,
+            confidence: 0.0,
+            minimum_hit_groups: 2,
+            overwrite: false,
+            stats: None,
+            kraken2_log: None,
+            removed_ids: None,
+            report: None,
+            report_minimizer_data: false,
+            sample_name: None,
+            multiqc: None,
+        }
+    }
+
+    /// Replace the input files
+    pub fn inputs(mut self, inputs: Vec<PathBuf>) -> Self {
+        self.inputs = inputs;
+        self
+    }
+
+    /// The single input holds paired reads, interleaved (mate 1, mate 2, mate 1, ...). They are
+    /// classified as pairs and each set of reads is written interleaved to one output.
+    pub fn interleaved(mut self, interleaved: bool) -> Self {
+        self.interleaved = interleaved;
+        self
+    }
+
+    /// First output file for non-human reads (defaults to the first input with the tag "nohuman",
+    /// or stdout when reading from stdin). `-` writes to stdout.
+    pub fn out1(mut self, path: impl Into<PathBuf>) -> Self {
+        self.out[0] = Some(path.into());
+        self
+    }
+
+    /// Second output file for non-human reads (defaults to the second input with the tag "nohuman")
+    pub fn out2(mut self, path: impl Into<PathBuf>) -> Self {
+        self.out[1] = Some(path.into());
+        self
+    }
+
+    /// First output file for human reads (defaults to the first input with the tag "human")
+    pub fn human_out1(mut self, path: impl Into<PathBuf>) -> Self {
+        self.human_out[0] = Some(path.into());
+        self
+    }
+
+    /// Second output file for human reads (defaults to the second input with the tag "human")
+    pub fn human_out2(mut self, path: impl Into<PathBuf>) -> Self {
+        self.human_out[1] = Some(path.into());
+        self
+    }
+
+    /// Which reads to write out
+    pub fn keep(mut self, keep: Keep) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Which reads are written out
+    pub fn keeps(&self) -> Keep {
+        self.keep
+    }
+
+    /// Write every read, in its original order, to `--out1`/`--out2` (which default to the inputs
+    /// with the tag "masked"), masking the reads classified as human rather than removing them
+    pub fn mask(mut self, mode: MaskMode) -> Self {
+        self.mask = Some(mode);
+        self
+    }
+
+    /// The kraken2 database directory
+    pub fn database(mut self, path: impl Into<PathBuf>) -> Self {
+        self.database = Some(path.into());
+        self
+    }
+
+    /// The classifier backend to load the database with
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Use an already loaded classifier rather than loading one from the database, e.g., to
+    /// classify several samples with one database load
+    pub fn classifier(mut self, classifier: Arc<dyn Classifier>) -> Self {
+        self.classifier = Some(classifier);
+        self
+    }
+
+    /// Have kraken2 read the database with memory mapping rather than loading it into memory,
+    /// so it stays in the page cache between runs
+    pub fn memory_mapping(mut self, memory_mapping: bool) -> Self {
+        self.memory_mapping = memory_mapping;
+        self
+    }
+
+    /// Number of threads used for classification
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Number of threads used for compression (defaults to the classification threads)
+    pub fn compression_threads(mut self, threads: usize) -> Self {
+        self.compression_threads = Some(threads);
+        self
+    }
+
+    /// Compress every output in this format, rather than the one implied by its extension (or,
+    /// for stdout, uncompressed). Outputs that aren't named explicitly get its extension.
+    pub fn output_format(mut self, format: OutputFormat) -> Self {
+        self.output_format = Some(format);
+        self
+    }
+
+    /// Compression level for the outputs, which must suit the format of each compressed output
+    pub fn compression_level(mut self, level: u32) -> Self {
+        self.compression_level = Some(level);
+        self
+    }
+
+    fn output_settings(&self) -> OutputSettings {
+        OutputSettings {
+            threads: self.compression_threads.unwrap_or(self.threads),
+            format: self.output_format,
+            level: self.compression_level,
+        }
+    }
+
+    /// kraken2's `--confidence` threshold
+    pub fn confidence(mut self, confidence: f64) -> Self {
+        self.confidence = confidence;
+        self
+    }
+
+    /// kraken2's `--minimum-hit-groups` threshold
+    pub fn minimum_hit_groups(mut self, minimum_hit_groups: u32) -> Self {
+        self.minimum_hit_groups = minimum_hit_groups;
+        self
+    }
+
+    /// Allow existing output files to be overwritten
+    pub fn overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Write the run's statistics (JSON) to this file
+    pub fn stats(mut self, path: impl Into<PathBuf>) -> Self {
+        self.stats = Some(path.into());
+        self
+    }
+
+    /// Write kraken2's log (stderr) to this file
+    pub fn kraken2_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.kraken2_log = Some(path.into());
+        self
+    }
+
+    /// Write a table of the reads classified as human, with the evidence for each, to this file
+    /// (compressed according to its extension). `-` writes to stdout.
+    pub fn removed_ids(mut self, path: impl Into<PathBuf>) -> Self {
+        self.removed_ids = Some(path.into());
+        self
+    }
+
+    /// Write a taxonomic report of the classification, in kraken2's `--report` format, to this
+    /// file, and add a summary of it to the stats. `-` writes to stdout.
+    pub fn report(mut self, path: impl Into<PathBuf>) -> Self {
+        self.report = Some(path.into());
+        self
+    }
+
+    /// Include kraken2's minimizer counts for each taxon in the report
+    pub fn report_minimizer_data(mut self, report_minimizer_data: bool) -> Self {
+        self.report_minimizer_data = report_minimizer_data;
+        self
+    }
+
+    /// The sample's name in the stats and the MultiQC file (defaults to the name of the first
+    /// input, without its extensions)
+    pub fn sample_name(mut self, name: impl Into<String>) -> Self {
+        self.sample_name = Some(name.into());
+        self
+    }
+
+    /// Write a MultiQC custom content file (`<name>_mqc.json` or `<name>_mqc.tsv`) with the
+    /// sample's host depletion stats to this file
+    pub fn multiqc(mut self, path: impl Into<PathBuf>) -> Self {
+        self.multiqc = Some(path.into());
+        self
+    }
+
+    fn thresholds(&self) -> ClassifierOptions {
+        ClassifierOptions {
+            confidence: self.confidence,
+            minimum_hit_groups: self.minimum_hit_groups,
+        }
+    }
+
+    /// Check the settings are consistent, without touching the inputs or the database
+    pub fn validate(&self) -> Result<()> {
+        if self.inputs.is_empty() || self.inputs.len() > 2 {
+            bail!("Only one or two input files are allowed");
+        }
+        if self.inputs.len() > 1 && self.inputs.iter().any(|input| is_stdio(input)) {
+            bail!(
+                "Only a single input can be read from stdin. Use '--interleaved' for paired reads."
+            );
+        }
+        if self.interleaved {
+            if self.inputs.len() > 1 {
+                bail!("Interleaved reads must be given as a single input");
+            }
+            if self.out[1].is_some() || self.human_out[1].is_some() {
+                bail!("Interleaved reads are written to a single output, so --out2/--human-out2 are not used");
+            }
+        }
+
+        if self.report_minimizer_data && self.report.is_none() {
+            bail!("Minimizer data is only written in the report. Use '--report' to write one.");
+        }
+        if self.mask.is_some() && self.keep != Keep::Nonhuman {
+            bail!("Masking writes every read to --out1/--out2, so it can't be used with '--keep'");
+        }
+
+        // Explicitly named outputs must be consistent with the reads being kept
+        if !self.keep.nonhuman() && self.out.iter().any(Option::is_some) {
+            bail!("--out1/--out2 are only used when keeping non-human reads. Use '--keep both' to write both sets of reads.");
+        }
+        if !self.keep.human() && self.human_out.iter().any(Option::is_some) {
+            bail!("--human-out1/--human-out2 require '--keep human' or '--keep both'.");
+        }
+
+        // All output files must be distinct
+        let explicit_outputs: Vec<&PathBuf> = self
+            .out
+            .iter()
+            .chain(&self.human_out)
+            .chain([&self.removed_ids, &self.report])
+            .flatten()
+            .collect();
+        for (i, out) in explicit_outputs.iter().enumerate() {
+            if explicit_outputs[..i].contains(out) {
+                bail!("Output file '{}' is given more than once. Please provide distinct output file names.", out.display());
+            }
+        }
+
+        let [nonhuman_outputs, human_outputs] = self.resolve_outputs();
+        let stdout_outputs = nonhuman_outputs
+            .iter()
+            .chain(&human_outputs)
+            .chain(&self.removed_ids)
+            .chain(&self.report)
+            .filter(|out| is_stdio(out))
+            .count();
+        if stdout_outputs > 1 {
+            bail!("Only one output can be written to stdout. Please provide an output file for the others.");
+        }
+
+        if let Some(level) = self.compression_level {
+            let settings = self.output_settings();
+            let formats: Vec<OutputFormat> = nonhuman_outputs
+                .iter()
+                .chain(&human_outputs)
+                .map(|out| settings.format_for(out))
+                .filter(|format| *format != OutputFormat::None)
+                .collect();
+            if formats.is_empty() {
+                bail!("--compression-level has no effect as none of the outputs are compressed");
+            }
+            for format in formats {
+                format.check_level(level).map_err(|e| anyhow::anyhow!(e))?;
+            }
+        }
+
+        // Existing output files won't be overwritten unless `overwrite` is set
+        for out in explicit_outputs.iter().filter(|out| !is_stdio(out)) {
+            if out.exists() && !self.overwrite {
+                bail!("Output file '{}' already exists. Use '--overwrite' to allow overwriting existing files.", out.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// The classifier given with [`Pipeline::classifier`], or else the configured backend loaded
+    /// with the database
+    pub fn load_classifier(&self) -> Result<Arc<dyn Classifier>> {
+        if let Some(classifier) = &self.classifier {
+            return Ok(classifier.clone());
+        }
+        let database = self.database.as_ref().context("No database given")?;
+        let db = validate_db_directory(database).map_err(|e| anyhow::anyhow!(e))?;
+        Ok(match self.backend {
+            Backend::Kraken2 => Arc::new(
+                Kraken2Classifier::new(&db, self.thresholds()).memory_mapping(self.memory_mapping),
+            ),
+            Backend::Native => {
+                info!("Loading database...");
+                let classifier = NativeClassifier::load(&db, self.thresholds())
+                    .with_context(|| format!("Failed to load database from {}", db.display()))?;
+                Arc::new(classifier)
+            }
+        })
+    }
+
+    /// Where the non-human and human reads are written: the explicitly provided output paths,
+    /// falling back to names derived from the inputs (or stdout for reads from stdin)
+    fn resolve_outputs(&self) -> [Vec<PathBuf>; 2] {
+        let resolve = |keep: bool, explicit: &[Option<PathBuf>; 2], tag: &str| -> Vec<PathBuf> {
+            if !keep {
+                return Vec::new();
+            }
+            self.inputs
+                .iter()
+                .zip(explicit)
+                .map(|(input_file, out)| {
+                    out.clone()
+                        .unwrap_or_else(|| self.default_output(input_file, tag))
+                })
+                .collect()
+        };
+        [
+            resolve(
+                self.keep.nonhuman(),
+                &self.out,
+                if self.mask.is_some() {
+                    "masked"
+                } else {
+                    "nohuman"
+                },
+            ),
+            resolve(self.keep.human(), &self.human_out, "human"),
+        ]
+    }
+
+    /// The output for `input` when none is given: its name with `tag` inserted, and the extension
+    /// of the output format if one was given, or stdout for stdin
+    pub(crate) fn default_output(&self, input: &Path, tag: &str) -> PathBuf {
+        if is_stdio(input) {
+            return PathBuf::from(STDIO);
+        }
+        let path = default_output_path(input, tag);
+        match self.output_format {
+            Some(format) => with_compression_extension(&path, format),
+            None => path,
+        }
+    }
+
+    /// Read the input from `path`, detecting its compression from its first bytes, and write the
+    /// reads into `feeds` for the classifier: split into one pipe per mate if the input is
+    /// interleaved.
+    ///
+    /// Every pipe is opened for writing, even if the input can't be read, so the classifier never
+    /// waits for a writer that won't come.
+    fn feed_input(
+        path: &Path,
+        input: Interruptible,
+        interleaved: bool,
+        feeds: &[FeedPipe],
+    ) -> Result<()> {
+        let result = (|| -> Result<()> {
+            let (mut reader, format) = niffler::send::get_reader(Box::new(input))?;
+            debug!("Input: Detected format: {:?}", format);
+            if interleaved {
+                let pairs = deinterleave(
+                    Reader::new(BufReader::new(reader)),
+                    [0, 1].map(|i| move || feeds[i].open_writer()),
+                )?;
+                debug!("Input: Read {} interleaved read pairs", pairs);
+            } else {
+                io::copy(&mut reader, &mut feeds[0].open_writer()?)?;
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            for feed in feeds {
+                let _ = feed.open_writer();
+            }
+        }
+        let input_name = if is_stdio(path) {
+            "stdin".to_string()
+        } else {
+            path.display().to_string()
+        };
+        result.with_context(|| format!("Failed to read input from {}", input_name))
+    }
+
+    /// Classify the reads and write the outputs.
+    ///
+    /// If classification or writing fails, any partially written outputs are removed.
+    pub fn run(&self) -> Result<RunReport> {
+        let started = Instant::now();
+        self.validate()?;
+        for (i, input_file) in self
+            .inputs
+            .iter()
+            .enumerate()
+            .filter(|(_, input)| !is_stdio(input))
+        {
+            let file_size_mb = std::fs::metadata(input_file)
+                .with_context(|| format!("Failed to read input file {}", input_file.display()))?
+                .len() as f64
+                / 1_048_576.0;
+            debug!(
+                "Input {}: {} ({:.2} MB)",
+                i + 1,
+                input_file.display(),
+                file_size_mb
+            );
+            // Catch misnamed or unsupported files before the database is loaded
+            if input_file.is_file() {
+                detect_compression(input_file).with_context(|| {
+                    format!("Failed to read input file {}", input_file.display())
+                })?;
+            }
+        }
+
+        if let [input] = self.inputs.as_slice() {
+            if !self.interleaved && input.is_file() && looks_interleaved(input) {
+                warn!(
+                    "The first two reads of {} look like mates of a pair. Use '--interleaved' to classify them as pairs.",
+                    input.display()
+                );
+            }
+        }
+
+        let classifier = self.load_classifier()?;
+        if self.kraken2_log.is_some() && !classifier.writes_log() {
+            warn!(
+                "The kraken2 log is not written with the {} backend",
+                classifier.name()
+            );
+        }
+        let settings = self.output_settings();
+
+        let [nonhuman_outputs, human_outputs] = self.resolve_outputs();
+        if let Some(format) = self.output_format {
+            for out in nonhuman_outputs
+                .iter()
+                .chain(&human_outputs)
+                .filter(|out| !is_stdio(out))
+            {
+                if OutputFormat::from_path(out) != format {
+                    warn!(
+                        "{} will be written as {} despite its extension",
+                        out.display(),
+                        format
+                    );
+                }
+            }
+        }
+
+        // create a temporary directory to hold the named pipes the classifier reads from and writes to
+        let tmpdir = tempfile::Builder::new()
+            .prefix("nohuman")
+            .tempdir()
+            .context("Failed to create temporary directory")?;
+
+        // Reads from stdin, or interleaved reads that must be split into mates, are fed to the
+        // classifier through named pipes. The input is read so that it can be interrupted, as the
+        // classifier may fail while the feeder is waiting for reads on stdin.
+        let feed_input = if self.interleaved || is_stdio(&self.inputs[0]) {
+            let input = &self.inputs[0];
+            let file = if is_stdio(input) {
+                // SAFETY: `dup` has no preconditions, and the descriptor it returns is owned by
+                // nothing else
+                match unsafe { libc::dup(libc::STDIN_FILENO) } {
+                    -1 => Err(io::Error::last_os_error()),
+                    fd => Ok(unsafe { File::from_raw_fd(fd) }),
+                }
+            } else {
+                File::open(input)
+            };
+            let file = file.with_context(|| format!("Failed to open {}", input.display()))?;
+            Some(fifo::interruptible(file).context("Failed to create a pipe")?)
+        } else {
+            None
+        };
+        let feeds = if feed_input.is_some() {
+            let names: &[&str] = if self.interleaved {
+                &["input_1.fq", "input_2.fq"]
+            } else {
+                &["input.fq"]
+            };
+            names
+                .iter()
+                .map(|name| {
+                    let path = tmpdir.path().join(name);
+                    FeedPipe::create(&path)
+                        .with_context(|| format!("Failed to create named pipe {}", path.display()))
+                })
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+        let classifier_inputs: Vec<PathBuf> = if feeds.is_empty() {
+            self.inputs.clone()
+        } else {
+            feeds.iter().map(|feed| feed.path().to_path_buf()).collect()
+        };
+
+        // The classifier's outputs are named pipes that are read and compressed while it is
+        // running, so the uncompressed reads never touch the disk
+        let mut pipes = Vec::new();
+        let create_pipe = |name: &str, pipes: &mut Vec<NamedPipe>| -> Result<(PathBuf, File)> {
+            let path = tmpdir.path().join(name);
+            let mut pipe = NamedPipe::create(&path)
+                .with_context(|| format!("Failed to create named pipe {}", path.display()))?;
+            let reader = pipe.take_reader().context("Named pipe has no reader")?;
+            pipes.push(pipe);
+            Ok((path, reader))
+        };
+        let mut pipe_paths = [Vec::new(), Vec::new()];
+        let mut set_readers = [Vec::new(), Vec::new()];
+        for (((prefix, outputs), paths), readers) in
+            [("nonhuman", &nonhuman_outputs), ("human", &human_outputs)]
+                .into_iter()
+                .zip(pipe_paths.iter_mut())
+                .zip(set_readers.iter_mut())
+        {
+            // Masking needs both sets of reads
+            if outputs.is_empty() && self.mask.is_none() {
+                continue;
+            }
+            for i in 1..=classifier_inputs.len() {
+                let (path, reader) = create_pipe(&format!("{}_{}.fq", prefix, i), &mut pipes)?;
+                paths.push(path);
+                readers.push(reader);
+            }
+        }
+        let [unclassified_pipes, classified_pipes] = pipe_paths;
+        let read_outputs: Vec<&Path> = nonhuman_outputs
+            .iter()
+            .chain(&human_outputs)
+            .map(PathBuf::as_path)
+            .collect();
+        let all_outputs: Vec<&Path> = read_outputs
+            .iter()
+            .copied()
+            .chain(self.removed_ids.as_deref())
+            .collect();
+
+        // The classifier's per-read output is used for the stats, to mask reads and to list the
+        // removed reads, and is copied to each that is wanted
+        let mut output_jobs = Vec::new();
+        let per_read_consumers =
+            1 + usize::from(self.mask.is_some()) + usize::from(self.removed_ids.is_some());
+        let (per_read_pipe, per_read) = create_pipe("per_read.txt", &mut pipes)?;
+        let (mut per_read_readers, tee_job) = if per_read_consumers == 1 {
+            (vec![per_read], None)
+        } else {
+            let (readers, writers): (Vec<File>, Vec<File>) = (0..per_read_consumers)
+                .map(|_| fifo::pipe())
+                .collect::<io::Result<Vec<_>>>()
+                .context("Failed to create pipe")?
+                .into_iter()
+                .unzip();
+            let tee = OutputJob::Tee {
+                input: per_read,
+                outputs: writers,
+            };
+            (readers, Some(tee))
+        };
+        if let Some(output) = &self.removed_ids {
+            output_jobs.push(OutputJob::RemovedIds {
+                per_read: per_read_readers
+                    .pop()
+                    .expect("per-read output for the removed reads"),
+                output,
+            });
+        }
+
+        // Masking puts the reads back in order with the classifier's per-read output
+        match self.mask {
+            Some(mode) => {
+                let [unclassified, classified] = set_readers;
+                output_jobs.push(OutputJob::Mask {
+                    per_read: per_read_readers.pop().expect("per-read output for masking"),
+                    unclassified,
+                    classified,
+                    outputs: &nonhuman_outputs,
+                    mode,
+                });
+            }
+            None => output_jobs.extend(
+                set_readers
+                    .into_iter()
+                    .zip([&nonhuman_outputs, &human_outputs])
+                    .filter(|(_, outputs)| !outputs.is_empty())
+                    .map(|(readers, outputs)| OutputJob::Write { readers, outputs }),
+            ),
+        }
+        // The copy fails if another job does, so it goes last for that job's error to be reported
+        output_jobs.extend(tee_job);
+        let metrics_reader = per_read_readers
+            .pop()
+            .expect("per-read output for the stats");
+
+        let job = ClassifyJob {
+            inputs: &classifier_inputs,
+            unclassified: &unclassified_pipes,
+            classified: &classified_pipes,
+            per_read: Some(&per_read_pipe),
+            report: self.report.is_some(),
+            report_minimizer_data: self.report_minimizer_data,
+            threads: self.threads,
+        };
+        info!("Classifying reads with {}...", classifier.name());
+
+        // The input is fed on its own thread, which is stopped once the classifier has finished
+        let feeder = feed_input.map(|(input, interrupter)| {
+            let (path, interleaved, feeds) =
+                (self.inputs[0].clone(), self.interleaved, feeds.clone());
+            let handle =
+                std::thread::spawn(move || Self::feed_input(&path, input, interleaved, &feeds));
+            (handle, interrupter)
+        });
+
+        // Run the classifier, writing its output as it is produced
+        let mut timings = Timings::default();
+        let (classify_result, write_result, metrics) = std::thread::scope(|scope| {
+            let interleaved = self.interleaved;
+            let writers: Vec<_> = output_jobs
+                .into_iter()
+                .map(|job| scope.spawn(move || job.run(interleaved, settings)))
+                .collect();
+            let collector = scope.spawn(move || ReadMetrics::collect(metrics_reader));
+
+            let classify_started = Instant::now();
+            let classify_result = classifier.classify(&job);
+            timings.classification_seconds = classify_started.elapsed().as_secs_f64();
+
+            // The classifier has finished, so the writers can finish once they have drained the pipes
+            let finish_started = Instant::now();
+            pipes.iter_mut().for_each(NamedPipe::close_writer);
+            let write_results: Vec<Result<()>> = writers
+                .into_iter()
+                .map(|handle| handle.join().expect("Output thread panicked"))
+                .collect();
+            timings.compression_seconds = finish_started.elapsed().as_secs_f64();
+            let metrics = collector.join().expect("Stats thread panicked");
+            (
+                classify_result,
+                write_results.into_iter().collect::<Result<()>>(),
+                metrics,
+            )
+        });
+
+        // Stop the feeder if it is still going, i.e. the classifier failed: a read of the input is
+        // interrupted, a pipe the classifier never opened is closed, and a write fails with a
+        // broken pipe now that the classifier is no longer reading
+        let feed_result = feeder.map(|(handle, interrupter)| {
+            interrupter.interrupt();
+            for feed in &feeds {
+                if let Err(e) = feed.close() {
+                    warn!(
+                        "Failed to close named pipe {}: {}",
+                        feed.path().display(),
+                        e
+                    );
+                }
+            }
+            handle.join().expect("Input thread panicked")
+        });
+
+        // Write stderr (= kraken2 logging info) to a log file, even if kraken2 failed
+        let mut log_result = Ok(());
+        if let Some(log_path) = self
+            .kraken2_log
+            .as_ref()
+            .filter(|_| classifier.writes_log())
+        {
+            let kraken_stderr: &[u8] = match &classify_result {
+                Ok(summary) => &summary.log,
+                Err(ClassifyError::CommandError(CommandError::Failed { stderr, .. })) => {
+                    stderr.as_bytes()
+                }
+                Err(_) => &[],
+            };
+            log_result = File::create(log_path)
+                .context("Failed to create log file")
+                .and_then(|mut log_file| {
+                    log_file
+                        .write_all(kraken_stderr)
+                        .context("Failed to write `kraken2` stderr to log file")
+                });
+            if log_result.is_ok() {
+                debug!("Kraken2 log written to: {:?}", &log_path);
+            }
+        }
+        // If the run failed, its error is the one to report, and the outputs must still be removed
+        let failed = write_result.is_err()
+            || classify_result.is_err()
+            || matches!(feed_result, Some(Err(_)));
+        if let (true, Err(e)) = (failed, &log_result) {
+            warn!("{:#}", e);
+        }
+
+        // A failed writer causes the classifier to fail when it can no longer write to the pipe,
+        // so report the writer's error as the root cause
+        if let Err(e) = write_result {
+            remove_partial_outputs(&all_outputs);
+            return Err(e);
+        }
+
+        let summary = match classify_result {
+            Ok(summary) => summary,
+            Err(e) => {
+                // Don't leave behind any partial output that could be mistaken for clean reads
+                remove_partial_outputs(&all_outputs);
+                // An input that could not be read is the root cause of the classifier failing,
+                // unless it failed only because the classifier stopped reading
+                if let Some(Err(feed_error)) = feed_result {
+                    if !is_broken_pipe(&feed_error) {
+                        return Err(feed_error);
+                    }
+                }
+                return Err(e).with_context(|| {
+                    format!("{} did not complete successfully", classifier.name())
+                });
+            }
+        };
+
+        // The classifier read the input to the end, but that may be because it could not be read
+        if let Some(Err(e)) = feed_result {
+            remove_partial_outputs(&all_outputs);
+            return Err(e);
+        }
+        log_result?;
+
+        let mut stats = Stats::from_counts(
+            summary.total_sequences,
+            summary.classified_sequences,
+            summary.unclassified_sequences,
+        );
+        stats.backend = classifier.name().to_string();
+        stats.sample = self
+            .sample_name
+            .clone()
+            .unwrap_or_else(|| sample_name(&self.inputs));
+        if let Some(database) = &self.database {
+            let db = validate_db_directory(database).unwrap_or_else(|_| database.clone());
+            stats.database = db.display().to_string();
+            stats.database_version = database_version(&db);
+        }
+        stats.kraken2_version = classifier.version().unwrap_or_default();
+        stats.confidence = self.confidence;
+        stats.minimum_hit_groups = self.minimum_hit_groups;
+        let display = |paths: &[PathBuf], i: usize| {
+            paths
+                .get(i)
+                .map(|p| p.display().to_string())
+                .unwrap_or_default()
+        };
+        stats.input1 = display(&self.inputs, 0);
+        stats.input2 = display(&self.inputs, 1);
+        stats.output1 = display(&nonhuman_outputs, 0);
+        stats.output2 = display(&nonhuman_outputs, 1);
+        stats.human_output1 = display(&human_outputs, 0);
+        stats.human_output2 = display(&human_outputs, 1);
+        if self.mask.is_some() {
+            stats.nonhuman_sequences_written = stats.total_sequences;
+            stats.sequences_masked = stats.sequences_removed;
+        } else if self.keep.nonhuman() {
+            stats.nonhuman_sequences_written = stats.sequences_remaining;
+        }
+        if self.keep.human() {
+            stats.human_sequences_written = stats.sequences_removed;
+        }
+        match metrics {
+            Ok(metrics) if metrics.records == summary.total_sequences as u64 => stats.add_read_metrics(&metrics),
+            Ok(metrics) => warn!(
+                "The per-read output lists {} of the {} sequences classified, so the base and length stats are left out",
+                metrics.records, summary.total_sequences
+            ),
+            Err(e) => warn!("Failed to read the per-read output, so the base and length stats are left out: {}", e),
+        }
+        timings.decompression_seconds = summary.decompression_time.as_secs_f64();
+        timings.classification_seconds -= timings.decompression_seconds;
+        timings.total_seconds = started.elapsed().as_secs_f64();
+        stats.timings = timings;
+        stats.process_peak_memory_mb = process_peak_memory_mb();
+        stats.classifier_peak_memory_mb = summary.peak_memory_mb.unwrap_or(0.0);
+
+        if let Some(report_path) = &self.report {
+            match &summary.report {
+                Some(report) => {
+                    if self.report_minimizer_data && !report.has_minimizer_data() {
+                        warn!(
+                            "Minimizer data is not reported by the {} backend",
+                            classifier.name()
+                        );
+                    }
+                    let mut output = create_output(report_path)?;
+                    report
+                        .write(&mut output)
+                        .and_then(|_| output.flush())
+                        .with_context(|| {
+                            format!("Failed to write report to {}", report_path.display())
+                        })?;
+                    debug!("Report written to: {:?}", report_path);
+                    stats.report = Some(report.summary());
+                }
+                None => warn!(
+                    "The {} backend does not produce a report",
+                    classifier.name()
+                ),
+            }
+        }
+        if let Some(stats_file) = &self.stats {
+            write_stats(stats_file, &stats)?;
+            debug!("Run stats written to: {:?}", &stats_file);
+        }
+        if let Some(multiqc_file) = &self.multiqc {
+            if !is_multiqc_file_name(multiqc_file) {
+                warn!(
+                    "MultiQC only finds {} by default if its name ends in '_mqc.json' or '_mqc.tsv'",
+                    multiqc_file.display()
+                );
+            }
+            write_multiqc(multiqc_file, &stats)?;
+            debug!("MultiQC stats written to: {:?}", &multiqc_file);
+        }
+
+        info!(
+            "Classification finished: {} of {} sequences classified as human",
+            summary.classified_sequences, summary.total_sequences
+        );
+
+        // Log output format and file sizes
+        for out in read_outputs.iter().filter(|out| !is_stdio(out)) {
+            let output_format = settings.format_for(out);
+            let out_size_mb = std::fs::metadata(out)?.len() as f64 / 1_048_576.0;
+            debug!(
+                "Output ({} compression) written to: {} ({:.2} MB)",
+                output_format,
+                out.display(),
+                out_size_mb
+            );
+        }
+
+        // Cleanup the temporary directory, but only issue a warning if it fails
+        if let Err(e) = tmpdir.close() {
+            warn!("Failed to remove temporary output directory: {}", e);
+        }
+
+        Ok(RunReport {
+            nonhuman_outputs,
+            human_outputs,
+            summary,
+            stats,
+        })
+    }
+}
+
+/// How the classifier's output reads are written out
+enum OutputJob<'a> {
+    /// Compress one set of reads into its outputs
+    Write {
+        readers: Vec<File>,
+        outputs: &'a [PathBuf],
+    },
+    /// Merge both sets of reads back into their input order, masking the human reads
+    Mask {
+        per_read: File,
+        unclassified: Vec<File>,
+        classified: Vec<File>,
+        outputs: &'a [PathBuf],
+        mode: MaskMode,
+    },
+    /// List the reads classified as human from the classifier's per-read output
+    RemovedIds { per_read: File, output: &'a Path },
+    /// Copy the classifier's per-read output to more than one of the other jobs
+    Tee { input: File, outputs: Vec<File> },
+}
+
+impl OutputJob<'_> {
+    fn run(self, interleaved: bool, settings: OutputSettings) -> Result<()> {
+        match self {
+            OutputJob::Write { readers, outputs } if interleaved => {
+                write_interleaved(readers, &outputs[0], settings)
+            }
+            OutputJob::Write { readers, outputs } => write_output(readers, outputs, settings),
+            OutputJob::Mask {
+                per_read,
+                unclassified,
+                classified,
+                outputs,
+                mode,
+            } => {
+                let (readers, writers): (Vec<File>, Vec<File>) = outputs
+                    .iter()
+                    .map(|_| fifo::pipe())
+                    .collect::<io::Result<Vec<_>>>()
+                    .context("Failed to create pipe")?
+                    .into_iter()
+                    .unzip();
+                std::thread::scope(|scope| {
+                    let masker = scope.spawn(move || {
+                        mask_reads(per_read, unclassified, classified, writers, mode)
+                    });
+                    let write_result = write_output(readers, outputs, settings);
+                    let mask_result = masker.join().expect("Mask thread panicked");
+                    // Masking fails with a broken pipe if the output can't be written
+                    write_result?;
+                    let masked = mask_result.context("Failed to mask human reads")?;
+                    debug!("Masked {} human reads", masked);
+                    Ok(())
+                })
+            }
+            OutputJob::RemovedIds { per_read, output } => {
+                // The table's compression is always implied by its extension
+                let settings = OutputSettings {
+                    format: None,
+                    level: None,
+                    ..settings
+                };
+                let (pipe_reader, pipe_writer) = fifo::pipe().context("Failed to create pipe")?;
+                std::thread::scope(|scope| {
+                    let lister = scope.spawn(move || write_removed_ids(per_read, pipe_writer));
+                    let write_result =
+                        write_output(vec![pipe_reader], &[output.to_path_buf()], settings);
+                    let list_result = lister.join().expect("Removed reads thread panicked");
+                    write_result?;
+                    let removed = list_result.context("Failed to list the removed reads")?;
+                    debug!("Listed {} removed reads in {}", removed, output.display());
+                    Ok(())
+                })
+            }
+            OutputJob::Tee { input, outputs } => {
+                tee(input, outputs).context("Failed to copy the per-read output")
+            }
+        }
+    }
+}
+
+/// Interleave the two mates' reads from `readers` into `output`
+fn write_interleaved(readers: Vec<File>, output: &PathBuf, settings: OutputSettings) -> Result<()> {
+    let readers: [File; 2] = readers.try_into().map_err(|readers: Vec<File>| {
+        anyhow::anyhow!("Expected 2 inputs to interleave, got {}", readers.len())
+    })?;
+    let (pipe_reader, pipe_writer) = fifo::pipe().context("Failed to create pipe")?;
+    std::thread::scope(|scope| {
+        let merger = scope.spawn(move || interleave(readers, pipe_writer));
+        let write_result = write_output(vec![pipe_reader], std::slice::from_ref(output), settings);
+        let merge_result = merger.join().expect("Interleave thread panicked");
+        // The merge fails with a broken pipe if the output can't be written
+        write_result?;
+        merge_result.context("Failed to interleave output reads")?;
+        Ok(())
+    })
+}
+
+/// Copy `input` to every one of `outputs`
+fn tee<R: Read>(mut input: R, mut outputs: Vec<File>) -> io::Result<()> {
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = match input.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for output in outputs.iter_mut() {
+            output.write_all(&buffer[..n])?;
+        }
+    }
+}
+
+/// Whether the first two reads of `path` share a name, as the mates of interleaved pairs do
+fn looks_interleaved(path: &Path) -> bool {
+    let Ok(mut reader) = Reader::from_path(path) else {
+        return false;
+    };
+    match (reader.next(), reader.next()) {
+        (Some(Ok(first)), Some(Ok(second))) => first.pair_name() == second.pair_name(),
+        _ => false,
+    }
+}
+
+fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    let is_broken = |e: &io::Error| e.kind() == io::ErrorKind::BrokenPipe;
+    error
+        .chain()
+        .any(|cause| match cause.downcast_ref::<InterleaveError>() {
+            Some(InterleaveError::IoError(e))
+            | Some(InterleaveError::FastxError(FastxError::IoError(e))) => is_broken(e),
+            _ => cause.downcast_ref::<io::Error>().is_some_and(is_broken),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classify::mock::MockClassifier;
+    use std::fs::OpenOptions;
+    use std::io::Read;
+
+    fn write_reads(dir: &Path, name: &str, ids: &[&str]) -> PathBuf {
+        let path = dir.join(name);
+        let contents: String = ids
+            .iter()
+            .map(|id| format!("@{}\nACGT\n+\nIIII\n", id))
+            .collect();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_run_paired_keep_both() {
+        let tmp = tempfile::tempdir().unwrap();
+        let in1 = write_reads(tmp.path(), "in_1.fq", &["r1/1", "r2/1", "r3/1"]);
+        let in2 = write_reads(tmp.path(), "in_2.fq", &["r1/2", "r2/2", "r3/2"]);
+        let stats_file = tmp.path().join("stats.json");
+
+        let report = Pipeline::new(vec![in1, in2])
+            .classifier(Arc::new(MockClassifier::new(["r2/1"])))
+            .keep(Keep::Both)
+            .out1(tmp.path().join("clean_1.fq.gz"))
+            .stats(&stats_file)
+            .run()
+            .unwrap();
+
+        assert_eq!(
+            report.nonhuman_outputs,
+            vec![
+                tmp.path().join("clean_1.fq.gz"),
+                tmp.path().join("in_2.nohuman.fq")
+            ]
+        );
+        assert_eq!(
+            report.human_outputs,
+            vec![
+                tmp.path().join("in_1.human.fq"),
+                tmp.path().join("in_2.human.fq")
+            ]
+        );
+        assert_eq!(report.stats.total_sequences, 3);
+        assert_eq!(report.stats.nonhuman_sequences_written, 2);
+        assert_eq!(report.stats.human_sequences_written, 1);
+        assert_eq!(report.stats.backend, "mock");
+
+        let (mut reader, _) = niffler::from_path(&report.nonhuman_outputs[0]).unwrap();
+        let mut clean = String::new();
+        reader.read_to_string(&mut clean).unwrap();
+        assert_eq!(clean, "@r1/1\nACGT\n+\nIIII\n@r3/1\nACGT\n+\nIIII\n");
+        assert_eq!(
+            std::fs::read_to_string(&report.human_outputs[1]).unwrap(),
+            "@r2/2 kraken:taxid|9606\nACGT\n+\nIIII\n"
+        );
+        let stats: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(stats_file).unwrap()).unwrap();
+        assert_eq!(stats["sequences_removed"], 1);
+        assert_eq!(
+            (
+                stats["total_bases"].as_u64(),
+                stats["bases_removed"].as_u64()
+            ),
+            (Some(24), Some(8))
+        );
+        assert_eq!(stats["inputs"][1]["reads_removed"], 1);
+        assert_eq!(stats["pairs"]["both_mates"], 1);
+        assert_eq!(stats["pairs"]["neither_mate"], 2);
+        assert_eq!(stats["read_lengths_after"]["reads"], 4);
+        assert_eq!(stats["read_lengths_before"]["n50"], 4);
+        assert!(stats["timings"]["total_seconds"].as_f64().unwrap() > 0.0);
+        assert!(report.stats.process_peak_memory_mb > 0.0);
+    }
+
+    #[test]
+    fn test_run_removes_partial_outputs_on_failure() {
+        let tmp = tempfile::tempdir().unwrap();
+        let in1 = write_reads(tmp.path(), "in_1.fq", &["r1", "r2"]);
+        let in2 = write_reads(tmp.path(), "in_2.fq", &["r1"]);
+
+        let err = Pipeline::new(vec![in1, in2])
+            .classifier(Arc::new(MockClassifier::new(Vec::<String>::new())))
+            .run()
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("mock did not complete successfully"));
+        assert!(!tmp.path().join("in_1.nohuman.fq").exists());
+        assert!(!tmp.path().join("in_2.nohuman.fq").exists());
+    }
+
+    /// A classifier that runs an "executable" with a version and a log, as kraken2 does
+    struct LoggingClassifier(MockClassifier);
+
+    impl Classifier for LoggingClassifier {
+        fn name(&self) -> &str {
+            "logging"
+        }
+
+        fn version(&self) -> Option<String> {
+            Some("1.2.3".to_string())
+        }
+
+        fn writes_log(&self) -> bool {
+            true
+        }
+
+        fn classify(&self, job: &ClassifyJob) -> Result<ClassifySummary, ClassifyError> {
+            let mut summary = self.0.classify(job)?;
+            summary.log = b"classified\n".to_vec();
+            Ok(summary)
+        }
+    }
+
+    #[test]
+    fn test_run_kraken2_log() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = write_reads(tmp.path(), "in.fq", &["r1", "r2"]);
+        let log = tmp.path().join("kraken2.log");
+
+        let report = Pipeline::new(vec![input.clone()])
+            .classifier(Arc::new(LoggingClassifier(MockClassifier::new(["r1"]))))
+            .kraken2_log(&log)
+            .run()
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "classified\n");
+        assert_eq!(report.stats.kraken2_version, "1.2.3");
+
+        std::fs::remove_file(&log).unwrap();
+        let report = Pipeline::new(vec![input])
+            .classifier(Arc::new(MockClassifier::new(["r1"])))
+            .kraken2_log(&log)
+            .run()
+            .unwrap();
+        assert!(!log.exists());
+        assert_eq!(report.stats.kraken2_version, "");
+    }
+
+    #[test]
+    fn test_run_mask() {
+        let tmp = tempfile::tempdir().unwrap();
+        let in1 = write_reads(tmp.path(), "in_1.fq", &["r1/1", "r2/1", "r3/1"]);
+        let in2 = write_reads(tmp.path(), "in_2.fq", &["r1/2", "r2/2", "r3/2"]);
+
+        let report = Pipeline::new(vec![in1, in2])
+            .classifier(Arc::new(MockClassifier::new(["r2/1"])))
+            .mask(MaskMode::N)
+            .run()
+            .unwrap();
+
+        assert_eq!(
+            report.nonhuman_outputs,
+            vec![
+                tmp.path().join("in_1.masked.fq"),
+                tmp.path().join("in_2.masked.fq")
+            ]
+        );
+        assert_eq!(report.stats.nonhuman_sequences_written, 3);
+        assert_eq!(report.stats.sequences_masked, 1);
+        assert_eq!(
+            std::fs::read_to_string(&report.nonhuman_outputs[1]).unwrap(),
+            "@r1/2\nACGT\n+\nIIII\n@r2/2 kraken:taxid|9606\nNNNN\n+\n!!!!\n@r3/2\nACGT\n+\nIIII\n"
+        );
+    }
+
+    #[test]
+    fn test_run_removed_ids() {
+        let tmp = tempfile::tempdir().unwrap();
+        let in1 = write_reads(tmp.path(), "in_1.fq", &["r1/1", "r2/1", "r3/1"]);
+        let in2 = write_reads(tmp.path(), "in_2.fq", &["r1/2", "r2/2", "r3/2"]);
+        let removed_ids = tmp.path().join("removed.tsv.gz");
+        let pipeline = Pipeline::new(vec![in1, in2])
+            .classifier(Arc::new(MockClassifier::new(["r2/1"])))
+            .removed_ids(&removed_ids);
+
+        // With masking, the per-read output is used twice
+        for pipeline in [pipeline.clone(), pipeline.mask(MaskMode::Stub)] {
+            let report = pipeline.overwrite(true).run().unwrap();
+            assert_eq!(report.stats.sequences_removed, 1);
+            let (mut reader, format) = niffler::from_path(&removed_ids).unwrap();
+            assert_eq!(format, niffler::compression::Format::Gzip);
+            let mut table = String::new();
+            reader.read_to_string(&mut table).unwrap();
+            assert_eq!(
+                table,
+                "read_id\tmate\ttaxid\tlength\thuman_kmer_fraction\nr2\t1\t9606\t4\t1.0000\nr2\t2\t9606\t4\t1.0000\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_report() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = write_reads(tmp.path(), "in.fq", &["r1", "r2", "r3", "r4"]);
+        let report_file = tmp.path().join("report.txt");
+        let stats_file = tmp.path().join("stats.json");
+
+        Pipeline::new(vec![input])
+            .classifier(Arc::new(MockClassifier::new(["r2"])))
+            .report(&report_file)
+            .stats(&stats_file)
+            .run()
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&report_file).unwrap(),
+            " 75.00\t3\t3\tU\t0\tunclassified\n 25.00\t1\t0\tR\t1\troot\n 25.00\t1\t1\tS\t9606\t  Homo sapiens\n"
+        );
+        let stats: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(stats_file).unwrap()).unwrap();
+        assert_eq!(stats["report"]["unclassified_reads"], 3);
+        assert_eq!(stats["report"]["taxa"][0]["taxonomy_id"], 9606);
+        assert_eq!(stats["report"]["taxa"][0]["fraction_total_reads"], 0.25);
+    }
+
+    #[test]
+    fn test_run_multiqc() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = write_reads(tmp.path(), "in.fq", &["r1", "r2", "r3", "r4"]);
+        let multiqc_file = tmp.path().join("s1_mqc.tsv");
+
+        let report = Pipeline::new(vec![input])
+            .classifier(Arc::new(MockClassifier::new(["r2"])))
+            .database(tmp.path().join("HPRC.r1"))
+            .multiqc(&multiqc_file)
+            .run()
+            .unwrap();
+
+        assert_eq!(report.stats.sample, "in");
+        assert_eq!(
+            std::fs::read_to_string(&multiqc_file)
+                .unwrap()
+                .lines()
+                .last(),
+            Some("in\t4\t3\t25.0000\tHPRC.r1")
+        );
+    }
+
+    #[test]
+    fn test_run_interleaved() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = write_reads(
+            tmp.path(),
+            "in.fq",
+            &["r1/1", "r1/2", "r2/1", "r2/2", "r3/1", "r3/2"],
+        );
+
+        let report = Pipeline::new(vec![input])
+            .interleaved(true)
+            .classifier(Arc::new(MockClassifier::new(["r2/1"])))
+            .keep(Keep::Both)
+            .run()
+            .unwrap();
+
+        assert_eq!(
+            report.nonhuman_outputs,
+            vec![tmp.path().join("in.nohuman.fq")]
+        );
+        assert_eq!(report.summary.total_sequences, 3);
+        assert_eq!(
+            std::fs::read_to_string(&report.nonhuman_outputs[0]).unwrap(),
+            "@r1/1\nACGT\n+\nIIII\n@r1/2\nACGT\n+\nIIII\n@r3/1\nACGT\n+\nIIII\n@r3/2\nACGT\n+\nIIII\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&report.human_outputs[0]).unwrap(),
+            "@r2/1 kraken:taxid|9606\nACGT\n+\nIIII\n@r2/2 kraken:taxid|9606\nACGT\n+\nIIII\n"
+        );
+    }
+
+    #[test]
+    fn test_looks_interleaved() {
+        let tmp = tempfile::tempdir().unwrap();
+        assert!(looks_interleaved(&write_reads(
+            tmp.path(),
+            "il.fq",
+            &["r1/1", "r1/2", "r2/1"]
+        )));
+        assert!(!looks_interleaved(&write_reads(
+            tmp.path(),
+            "se.fq",
+            &["r1/1", "r2/1"]
+        )));
+        assert!(!looks_interleaved(&write_reads(
+            tmp.path(),
+            "one.fq",
+            &["r1/1"]
+        )));
+    }
+
+    /// A classifier that reads the first few bytes of its first input, if any, then fails, possibly
+    /// part of the way through writing a read as a crashing kraken2 would. It writes a log, as
+    /// kraken2 does.
+    struct GivingUpClassifier {
+        bytes: usize,
+        partial: bool,
+    }
+
+    impl Classifier for GivingUpClassifier {
+        fn name(&self) -> &str {
+            "giving-up"
+        }
+
+        fn writes_log(&self) -> bool {
+            true
+        }
+
+        fn classify(&self, job: &ClassifyJob) -> Result<ClassifySummary, ClassifyError> {
+            if self.bytes > 0 {
+                let mut buf = vec![0; self.bytes];
+                File::open(&job.inputs[0])?.read_exact(&mut buf)?;
+            }
+            if let Some(output) = job.unclassified.first().filter(|_| self.partial) {
+                OpenOptions::new()
+                    .write(true)
+                    .open(output)?
+                    .write_all(b"@r1\nAC")?;
+            }
+            Err(io::Error::new(io::ErrorKind::Other, "gave up").into())
+        }
+    }
+
+    #[test]
+    fn test_run_interleaved_classifier_gives_up() {
+        let tmp = tempfile::tempdir().unwrap();
+        let ids: Vec<String> = (0..20_000)
+            .flat_map(|i| [format!("r{}/1", i), format!("r{}/2", i)])
+            .collect();
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let input = write_reads(tmp.path(), "in.fq", &ids);
+
+        // The feeder is stopped whether it is waiting for the classifier to open a pipe, or to
+        // read more from one
+        for bytes in [0, 10] {
+            let err = Pipeline::new(vec![input.clone()])
+                .interleaved(true)
+                .classifier(Arc::new(GivingUpClassifier {
+                    bytes,
+                    partial: false,
+                }))
+                .run()
+                .unwrap_err();
+            assert_eq!(
+                format!("{:#}", err),
+                "giving-up did not complete successfully: gave up"
+            );
+            assert!(!tmp.path().join("in.nohuman.fq").exists());
+        }
+    }
+
+    #[test]
+    fn test_run_removes_partial_outputs_when_log_fails() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = write_reads(tmp.path(), "in.fq", &["r1", "r2"]);
+
+        let err = Pipeline::new(vec![input])
+            .classifier(Arc::new(GivingUpClassifier {
+                bytes: 0,
+                partial: true,
+            }))
+            .kraken2_log(tmp.path().join("missing").join("kraken2.log"))
+            .run()
+            .unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "giving-up did not complete successfully: gave up"
+        );
+        assert!(!tmp.path().join("in.nohuman.fq").exists());
+    }
+
+    #[test]
+    fn test_run_interleaved_mismatched_mates() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = write_reads(tmp.path(), "in.fq", &["r1/1", "r1/2", "r2/1", "r3/1"]);
+
+        let err = Pipeline::new(vec![input])
+            .interleaved(true)
+            .classifier(Arc::new(MockClassifier::new(Vec::<String>::new())))
+            .run()
+            .unwrap_err();
+        assert!(format!("{:#}", err)
+            .contains("The mates of pair 2 have different names (r2/1 and r3/1)"));
+        assert!(!tmp.path().join("in.nohuman.fq").exists());
+    }
+
+    #[test]
+    fn test_run_interleaved_unpaired_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = write_reads(tmp.path(), "in.fq", &["r1/1", "r1/2", "r2/1"]);
+
+        let err = Pipeline::new(vec![input])
+            .interleaved(true)
+            .classifier(Arc::new(MockClassifier::new(Vec::<String>::new())))
+            .run()
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("unpaired read: r2/1"));
+        assert!(!tmp.path().join("in.nohuman.fq").exists());
+    }
+
+    #[test]
+    fn test_run_with_output_format() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = write_reads(tmp.path(), "in.fq", &["r1", "r2"]);
+        let gzipped = tmp.path().join("reads.fq.gz");
+        let mut writer = niffler::to_path(
+            &gzipped,
+            niffler::compression::Format::Gzip,
+            niffler::Level::One,
+        )
+        .unwrap();
+        writer.write_all(&std::fs::read(&input).unwrap()).unwrap();
+        drop(writer);
+
+        let report = Pipeline::new(vec![gzipped])
+            .classifier(Arc::new(MockClassifier::new(["r2"])))
+            .output_format(OutputFormat::Xz)
+            .compression_level(9)
+            .run()
+            .unwrap();
+        assert_eq!(
+            report.nonhuman_outputs,
+            vec![tmp.path().join("reads.nohuman.fq.xz")]
+        );
+        let (mut reader, format) = niffler::from_path(&report.nonhuman_outputs[0]).unwrap();
+        assert_eq!(format, niffler::compression::Format::Lzma);
+        let mut clean = String::new();
+        reader.read_to_string(&mut clean).unwrap();
+        assert_eq!(clean, "@r1\nACGT\n+\nIIII\n");
+    }
+
+    #[test]
+    fn test_run_mislabelled_input() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = write_reads(tmp.path(), "in.fq.zst", &["r1"]);
+        let err = Pipeline::new(vec![input])
+            .classifier(Arc::new(MockClassifier::new(Vec::<String>::new())))
+            .run()
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("has the extension .zst but is uncompressed"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = write_reads(tmp.path(), "in.fq", &["r1"]);
+        let pipeline = Pipeline::new(vec![input.clone()]);
+        assert!(pipeline.validate().is_ok());
+
+        assert!(Pipeline::new(vec![]).validate().is_err());
+        assert!(pipeline
+            .clone()
+            .keep(Keep::Human)
+            .out1("out.fq")
+            .validate()
+            .is_err());
+        assert!(pipeline.clone().human_out1("human.fq").validate().is_err());
+        assert!(pipeline.clone().mask(MaskMode::Stub).validate().is_ok());
+        assert!(pipeline
+            .clone()
+            .mask(MaskMode::Stub)
+            .keep(Keep::Both)
+            .validate()
+            .is_err());
+        assert!(pipeline
+            .clone()
+            .keep(Keep::Both)
+            .out1("same.fq")
+            .human_out1("same.fq")
+            .validate()
+            .is_err());
+        // Only one input or set of reads can use stdin/stdout
+        assert!(Pipeline::new(vec!["-".into(), input.clone()])
+            .validate()
+            .is_err());
+        assert!(Pipeline::new(vec!["-".into()]).validate().is_ok());
+        assert!(Pipeline::new(vec!["-".into()])
+            .keep(Keep::Both)
+            .validate()
+            .is_err());
+        assert!(Pipeline::new(vec!["-".into()])
+            .keep(Keep::Both)
+            .human_out1("human.fq")
+            .validate()
+            .is_ok());
+        assert!(Pipeline::new(vec!["-".into()])
+            .removed_ids("-")
+            .validate()
+            .is_err());
+        assert!(Pipeline::new(vec!["-".into()])
+            .removed_ids("removed.tsv")
+            .validate()
+            .is_ok());
+        assert!(pipeline
+            .clone()
+            .report_minimizer_data(true)
+            .validate()
+            .is_err());
+        assert!(pipeline
+            .clone()
+            .report("report.txt")
+            .report_minimizer_data(true)
+            .validate()
+            .is_ok());
+        // Interleaved reads come from one input and go to one output per set
+        assert!(pipeline.clone().interleaved(true).validate().is_ok());
+        assert!(pipeline
+            .clone()
+            .interleaved(true)
+            .out2("out_2.fq")
+            .validate()
+            .is_err());
+        assert!(Pipeline::new(vec![input.clone(), input.clone()])
+            .interleaved(true)
+            .validate()
+            .is_err());
+        // Compression levels must suit the compressed outputs
+        assert!(pipeline.clone().compression_level(5).validate().is_err());
+        assert!(pipeline
+            .clone()
+            .out1("out.fq.zst")
+            .compression_level(19)
+            .validate()
+            .is_ok());
+        assert!(pipeline
+            .clone()
+            .out1("out.fq.gz")
+            .compression_level(19)
+            .validate()
+            .is_err());
+        assert!(pipeline
+            .clone()
+            .output_format(OutputFormat::Zst)
+            .compression_level(19)
+            .validate()
+            .is_ok());
+        // Existing outputs are only overwritten when allowed
+        assert!(pipeline.clone().out1(&input).validate().is_err());
+        assert!(pipeline
+            .clone()
+            .out1(&input)
+            .overwrite(true)
+            .validate()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_run_without_database() {
+        let tmp = tempfile::tempdir().unwrap();
+        let input = write_reads(tmp.path(), "in.fq", &["r1"]);
+        let err = Pipeline::new(vec![input]).run().unwrap_err();
+        assert_eq!(err.to_string(), "No database given");
+    }
+}
@@ -0,0 +1,886 @@
+use crate::db::KrakenDatabase;
+use crate::validate_db_directory;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Name of the file, in an installed database's directory, recording the registry entry it was
+/// installed from
+pub const METADATA_FILE: &str = "nohuman-db.toml";
+
+/// Name of the file, in the root of a [`DatabaseStore`], naming the default database
+pub const DEFAULT_FILE: &str = "default.toml";
+
+/// The registry nohuman was built with, for installing databases without a network
+pub const BUNDLED_REGISTRY: &str = include_str!("../config.toml");
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("Failed to parse the database registry: {0}")]
+    ParseFailed(#[from] toml::de::Error),
+
+    #[error("The database registry lists no databases")]
+    Empty,
+
+    #[error("Invalid database registry entry: {0}")]
+    InvalidEntry(String),
+
+    #[error("No database named '{name}' (available: {available})")]
+    UnknownDatabase { name: String, available: String },
+
+    #[error("No version '{version}' of database '{name}' (available: {available})")]
+    UnknownVersion {
+        name: String,
+        version: String,
+        available: String,
+    },
+
+    #[error("No database is installed in {0:?}. Use --download to download one")]
+    NotInstalled(PathBuf),
+
+    #[error("Several databases are installed in {root:?} ({names}). Choose one with --db-name")]
+    Ambiguous { root: PathBuf, names: String },
+
+    #[error("Failed to read database metadata from {path:?}: {reason}")]
+    InvalidMetadata { path: PathBuf, reason: String },
+
+    #[error("Database {0} is not installed")]
+    NotFound(String),
+
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// A version of a database that can be downloaded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatabaseEntry {
+    pub name: String,
+    pub version: String,
+    /// Where the database's tarball (`.tar.gz`) is downloaded from
+    pub url: String,
+    /// The MD5 checksum of the tarball
+    pub md5: String,
+    /// The size of the tarball, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// When the database was built (`YYYY-MM-DD`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_date: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+impl DatabaseEntry {
+    /// The `name/version` of the database, as laid out in a [`DatabaseStore`]
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.name, self.version)
+    }
+
+    /// Databases are ordered by when they were built, then by version
+    fn age_key(&self) -> (Option<&str>, &str) {
+        (self.build_date.as_deref(), &self.version)
+    }
+
+    fn check(&self) -> Result<(), RegistryError> {
+        for (field, value) in [("name", &self.name), ("version", &self.version)] {
+            // Names and versions become directory names
+            if value.is_empty() || value == "." || value == ".." || value.contains(['/', '\\']) {
+                return Err(RegistryError::InvalidEntry(format!(
+                    "{} '{}' must be non-empty and cannot contain path separators",
+                    field, value
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The list of databases that can be downloaded, in TOML: an array of `[[database]]` tables, each
+/// a [`DatabaseEntry`]. The first database listed is the default.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Registry {
+    #[serde(rename = "database", default)]
+    pub databases: Vec<DatabaseEntry>,
+}
+
+impl Registry {
+    pub fn parse(text: &str) -> Result<Self, RegistryError> {
+        let registry: Registry = toml::from_str(text)?;
+        if registry.databases.is_empty() {
+            return Err(RegistryError::Empty);
+        }
+        for entry in &registry.databases {
+            entry.check()?;
+            if registry
+                .databases
+                .iter()
+                .filter(|other| other.id() == entry.id())
+                .count()
+                > 1
+            {
+                return Err(RegistryError::InvalidEntry(format!(
+                    "{} is listed more than once",
+                    entry.id()
+                )));
+            }
+        }
+        Ok(registry)
+    }
+
+    /// The database named `name` (or the default database), at `version` (or its latest version)
+    pub fn select(
+        &self,
+        name: Option<&str>,
+        version: Option<&str>,
+    ) -> Result<&DatabaseEntry, RegistryError> {
+        let name = name.unwrap_or(&self.databases[0].name);
+        select(&self.databases.iter().collect::<Vec<_>>(), name, version)
+    }
+}
+
+/// The entry named `name` at `version`, or the latest version if no version is given
+fn select<'a>(
+    entries: &[&'a DatabaseEntry],
+    name: &str,
+    version: Option<&str>,
+) -> Result<&'a DatabaseEntry, RegistryError> {
+    let versions: Vec<&DatabaseEntry> = entries
+        .iter()
+        .copied()
+        .filter(|entry| entry.name == name)
+        .collect();
+    if versions.is_empty() {
+        let mut names: Vec<&str> = Vec::new();
+        for entry in entries {
+            if !names.contains(&entry.name.as_str()) {
+                names.push(&entry.name);
+            }
+        }
+        return Err(RegistryError::UnknownDatabase {
+            name: name.to_string(),
+            available: names.join(", "),
+        });
+    }
+    match version {
+        Some(version) => versions
+            .iter()
+            .find(|entry| entry.version == version)
+            .copied()
+            .ok_or_else(|| RegistryError::UnknownVersion {
+                name: name.to_string(),
+                version: version.to_string(),
+                available: versions
+                    .iter()
+                    .map(|entry| entry.version.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            }),
+        None => Ok(versions
+            .into_iter()
+            .max_by(|a, b| a.age_key().cmp(&b.age_key()))
+            .expect("not empty")),
+    }
+}
+
+/// A database installed in a [`DatabaseStore`], as recorded in its metadata file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstalledDatabase {
+    /// The directory the database was installed into
+    #[serde(skip)]
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub entry: DatabaseEntry,
+    /// When the database was installed (UTC, RFC 3339)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub installed: Option<String>,
+    /// The MD5 checksum of each file of the database, by its path in the directory
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+}
+
+impl InstalledDatabase {
+    /// Read the metadata of the database installed in `path`
+    pub fn open(path: &Path) -> Result<Self, RegistryError> {
+        let metadata = path.join(METADATA_FILE);
+        let invalid = |reason: String| RegistryError::InvalidMetadata {
+            path: metadata.clone(),
+            reason,
+        };
+        let text = fs::read_to_string(&metadata).map_err(|e| invalid(e.to_string()))?;
+        let installed: InstalledDatabase =
+            toml::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            ..installed
+        })
+    }
+
+    /// The directory holding the database files, which may be a `db` subdirectory
+    pub fn database_dir(&self) -> Result<PathBuf, String> {
+        validate_db_directory(&self.path)
+    }
+
+    /// The total size of the database's files, in bytes
+    pub fn disk_size(&self) -> io::Result<u64> {
+        database_files(&self.path)?
+            .iter()
+            .map(|file| Ok(self.path.join(file).metadata()?.len()))
+            .sum()
+    }
+
+    /// Check the database's files against the checksums recorded when it was installed, and that
+    /// the kraken2 files can be read. Returns the problems found, if any.
+    pub fn verify(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.files.is_empty() {
+            problems.push(
+                "no file checksums were recorded when the database was installed".to_string(),
+            );
+        }
+        for (file, md5) in &self.files {
+            match md5_file(&self.path.join(file)) {
+                Ok(actual) if actual == *md5 => {}
+                Ok(actual) => problems.push(format!(
+                    "{} has MD5 {} but {} was recorded",
+                    file, actual, md5
+                )),
+                Err(e) => problems.push(format!("{} can't be read: {}", file, e)),
+            }
+        }
+        match self.database_dir() {
+            Ok(dir) => {
+                if let Err(e) = KrakenDatabase::inspect(&dir) {
+                    problems.push(e.to_string());
+                }
+            }
+            Err(e) => problems.push(e),
+        }
+        problems
+    }
+}
+
+/// A database in a [`DatabaseStore`] whose metadata can't be read, e.g., because it was corrupted
+#[derive(Debug)]
+pub struct BrokenDatabase {
+    /// The name of the database's directory
+    pub name: String,
+    /// The name of the version's directory
+    pub version: String,
+    pub path: PathBuf,
+    /// Why the metadata can't be read
+    pub error: RegistryError,
+}
+
+impl BrokenDatabase {
+    /// The `name/version` of the database's directory
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.name, self.version)
+    }
+}
+
+/// The database named in a [`DatabaseStore`]'s [`DEFAULT_FILE`], and optionally the version to
+/// use rather than its latest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DefaultDatabase {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+/// A directory of installed databases, laid out as `<root>/<name>/<version>` so several databases,
+/// and several versions of each, can be installed side by side
+#[derive(Debug, Clone)]
+pub struct DatabaseStore {
+    root: PathBuf,
+}
+
+impl DatabaseStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The directory `entry` is installed into
+    pub fn install_dir(&self, entry: &DatabaseEntry) -> PathBuf {
+        self.root.join(&entry.name).join(&entry.version)
+    }
+
+    /// Record that `entry` has been installed into its directory, with the time and the checksum
+    /// of each of its files
+    pub fn record(&self, entry: &DatabaseEntry) -> Result<InstalledDatabase, RegistryError> {
+        let path = self.install_dir(entry);
+        let files = database_files(&path)?
+            .into_iter()
+            .map(|file| Ok((file.clone(), md5_file(&path.join(file))?)))
+            .collect::<io::Result<_>>()?;
+        let installed = InstalledDatabase {
+            path,
+            entry: entry.clone(),
+            installed: Some(utc_timestamp(SystemTime::now())),
+            files,
+        };
+        let metadata =
+            toml::to_string(&installed).expect("Database metadata is always serialisable");
+        fs::write(installed.path.join(METADATA_FILE), metadata)?;
+        Ok(installed)
+    }
+
+    /// The installed databases, ordered by name and version. Databases whose metadata can't be
+    /// read are skipped with a warning; see [`DatabaseStore::broken`].
+    pub fn installed(&self) -> Result<Vec<InstalledDatabase>, RegistryError> {
+        let (installed, broken) = self.scan()?;
+        for db in &broken {
+            warn!("Skipping database {}: {}", db.id(), db.error);
+        }
+        Ok(installed)
+    }
+
+    /// The installed databases whose metadata can't be read, ordered by name and version
+    pub fn broken(&self) -> Result<Vec<BrokenDatabase>, RegistryError> {
+        self.scan().map(|(_, broken)| broken)
+    }
+
+    /// The installed databases, and those whose metadata can't be read, each ordered by name and
+    /// version. Unlike [`DatabaseStore::installed`], nothing is logged for the broken databases.
+    pub fn scan(&self) -> Result<(Vec<InstalledDatabase>, Vec<BrokenDatabase>), RegistryError> {
+        let (mut installed, mut broken) = (Vec::new(), Vec::new());
+        if !self.root.is_dir() {
+            return Ok((installed, broken));
+        }
+        for name_dir in fs::read_dir(&self.root)? {
+            let name_dir = name_dir?.path();
+            if !name_dir.is_dir() {
+                continue;
+            }
+            for version_dir in fs::read_dir(&name_dir)? {
+                let version_dir = version_dir?.path();
+                if !version_dir.join(METADATA_FILE).is_file() {
+                    continue;
+                }
+                match InstalledDatabase::open(&version_dir) {
+                    Ok(db) => installed.push(db),
+                    Err(error) => broken.push(BrokenDatabase {
+                        name: file_name(&name_dir),
+                        version: file_name(&version_dir),
+                        path: version_dir,
+                        error,
+                    }),
+                }
+            }
+        }
+        installed.sort_by(|a, b| {
+            (&a.entry.name, &a.entry.version).cmp(&(&b.entry.name, &b.entry.version))
+        });
+        broken.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+        Ok((installed, broken))
+    }
+
+    /// The installed database named `name` at `version` (or its latest version). With no name,
+    /// the default database is used, if one has been set, or else there must be only one
+    /// database (in any number of versions) installed.
+    pub fn find(
+        &self,
+        name: Option<&str>,
+        version: Option<&str>,
+    ) -> Result<InstalledDatabase, RegistryError> {
+        self.find_in(&self.installed()?, name, version)
+    }
+
+    /// Like [`DatabaseStore::find`], among `installed`, the databases listed by
+    /// [`DatabaseStore::scan`]
+    pub fn find_in(
+        &self,
+        installed: &[InstalledDatabase],
+        name: Option<&str>,
+        version: Option<&str>,
+    ) -> Result<InstalledDatabase, RegistryError> {
+        if installed.is_empty() {
+            return Err(RegistryError::NotInstalled(self.root.clone()));
+        }
+        let mut names: Vec<&str> = installed.iter().map(|db| db.entry.name.as_str()).collect();
+        names.dedup();
+        let default = self.default_database()?;
+        let (name, version) = match (name, &default) {
+            (Some(name), _) => (name, version),
+            (None, Some(default)) => (
+                default.name.as_str(),
+                version.or(default.version.as_deref()),
+            ),
+            (None, None) if names.len() == 1 => (names[0], version),
+            (None, None) => {
+                return Err(RegistryError::Ambiguous {
+                    root: self.root.clone(),
+                    names: names.join(", "),
+                })
+            }
+        };
+        let entries: Vec<&DatabaseEntry> = installed.iter().map(|db| &db.entry).collect();
+        let entry = select(&entries, name, version)?;
+        Ok(installed
+            .iter()
+            .find(|db| &db.entry == entry)
+            .cloned()
+            .expect("selected from the installed databases"))
+    }
+
+    /// The installed database named `name` at exactly `version`
+    pub fn get(&self, name: &str, version: &str) -> Result<InstalledDatabase, RegistryError> {
+        self.installed()?
+            .into_iter()
+            .find(|db| db.entry.name == name && db.entry.version == version)
+            .ok_or_else(|| RegistryError::NotFound(format!("{}/{}", name, version)))
+    }
+
+    /// The database set with [`DatabaseStore::set_default`], if any
+    pub fn default_database(&self) -> Result<Option<DefaultDatabase>, RegistryError> {
+        let path = self.root.join(DEFAULT_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(&path)?;
+        toml::from_str(&text)
+            .map(Some)
+            .map_err(|e| RegistryError::InvalidMetadata {
+                path,
+                reason: e.to_string(),
+            })
+    }
+
+    /// Use the installed database `name` when none is named, at `version` or else its latest
+    /// version
+    pub fn set_default(&self, name: &str, version: Option<&str>) -> Result<(), RegistryError> {
+        match version {
+            Some(version) => self.get(name, version).map(|_| ())?,
+            None if self.installed()?.iter().any(|db| db.entry.name == name) => {}
+            None => return Err(RegistryError::NotFound(name.to_string())),
+        }
+        let default = DefaultDatabase {
+            name: name.to_string(),
+            version: version.map(str::to_string),
+        };
+        let text = toml::to_string(&default).expect("The default database is always serialisable");
+        fs::write(self.root.join(DEFAULT_FILE), text)?;
+        Ok(())
+    }
+
+    /// The installed versions of `name` other than its latest version and the version pinned as
+    /// the default, if any, ordered by version
+    pub fn old_versions(&self, name: &str) -> Result<Vec<String>, RegistryError> {
+        let installed = self.installed()?;
+        let latest = self.find_in(&installed, Some(name), None)?;
+        let pinned = self
+            .default_database()?
+            .filter(|default| default.name == name)
+            .and_then(|default| default.version);
+        Ok(installed
+            .into_iter()
+            .filter(|db| db.entry.name == name && *db != latest)
+            .map(|db| db.entry.version)
+            .filter(|version| Some(version) != pinned.as_ref())
+            .collect())
+    }
+
+    /// Delete the installed database `name` at `version`, even if its metadata can't be read, and
+    /// return the directory it was in. The default is cleared, with a warning, if it no longer
+    /// names an installed database.
+    pub fn remove(&self, name: &str, version: &str) -> Result<PathBuf, RegistryError> {
+        let path = match self.get(name, version) {
+            Ok(installed) => installed.path,
+            Err(RegistryError::NotFound(id)) => self
+                .broken()?
+                .into_iter()
+                .find(|db| db.name == name && db.version == version)
+                .map(|db| db.path)
+                .ok_or(RegistryError::NotFound(id))?,
+            Err(e) => return Err(e),
+        };
+        fs::remove_dir_all(&path)?;
+        let name_dir = self.root.join(name);
+        if fs::read_dir(&name_dir)?.next().is_none() {
+            fs::remove_dir(&name_dir)?;
+        }
+        if let Some(default) = self.default_database()? {
+            let remaining = self.installed()?;
+            let still_installed = remaining.iter().any(|db| {
+                db.entry.name == default.name
+                    && default
+                        .version
+                        .as_ref()
+                        .map_or(true, |v| *v == db.entry.version)
+            });
+            if !still_installed {
+                warn!(
+                    "{}/{} was the default database, so no default is set now",
+                    name, version
+                );
+                fs::remove_file(self.root.join(DEFAULT_FILE))?;
+            }
+        }
+        Ok(path)
+    }
+
+    /// The database directory to classify with: the root itself if it holds a database (as
+    /// downloaded by nohuman versions before the store) and no name or version is asked for,
+    /// otherwise the installed database selected by [`DatabaseStore::find`]
+    pub fn resolve(
+        &self,
+        name: Option<&str>,
+        version: Option<&str>,
+    ) -> Result<PathBuf, RegistryError> {
+        if name.is_none() && version.is_none() {
+            if let Ok(db) = validate_db_directory(&self.root) {
+                return Ok(db);
+            }
+        }
+        let installed = self.find(name, version)?;
+        installed
+            .database_dir()
+            .map_err(|reason| RegistryError::InvalidMetadata {
+                path: installed.path.join(METADATA_FILE),
+                reason: format!("the database files are missing: {}", reason),
+            })
+    }
+}
+
+/// The last component of `path`, as a string
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// The paths, relative to `dir`, of the files of the database installed in it (all files but
+/// its metadata), sorted
+fn database_files(dir: &Path) -> io::Result<Vec<String>> {
+    fn walk(dir: &Path, prefix: &str, files: &mut Vec<String>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                walk(&entry.path(), &format!("{}/", name), files)?;
+            } else if name != METADATA_FILE {
+                files.push(name);
+            }
+        }
+        Ok(())
+    }
+    let mut files = Vec::new();
+    walk(dir, "", &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+/// The MD5 checksum of a file, read in blocks
+pub(crate) fn md5_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = md5::Context::new();
+    let mut buffer = vec![0; 1 << 20];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.consume(&buffer[..n]);
+    }
+    Ok(format!("{:x}", hasher.compute()))
+}
+
+/// Format a time as UTC, to the second, in RFC 3339 (e.g., `2024-07-22T09:30:00Z`)
+fn utc_timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+    // Civil date from days since 1970-01-01 (http://howardhinnant.github.io/date_algorithms.html)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_utils::*;
+    use tempfile::TempDir;
+
+    const REGISTRY: &str = r#"
+[[database]]
+name = "HPRC"
+version = "r1"
+url = "https://example.com/hprc_r1.tar.gz"
+md5 = "abc"
+build_date = "2023-08-10"
+
+[[database]]
+name = "HPRC"
+version = "r2"
+url = "https://example.com/hprc_r2.tar.gz"
+md5 = "def"
+size = 1024
+build_date = "2025-01-31"
+description = "Second release"
+
+[[database]]
+name = "T2T"
+version = "1.0"
+url = "https://example.com/t2t.tar.gz"
+md5 = "123"
+"#;
+
+    fn install(store: &DatabaseStore, entry: &DatabaseEntry) {
+        let dir = store.install_dir(entry);
+        fs::create_dir_all(&dir).unwrap();
+        for file in ["hash.k2d", "opts.k2d", "taxo.k2d"] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+        store.record(entry).unwrap();
+    }
+
+    #[test]
+    fn test_registry_select() {
+        let registry = Registry::parse(REGISTRY).unwrap();
+        assert_eq!(registry.select(None, None).unwrap().version, "r2");
+        assert_eq!(registry.select(None, Some("r1")).unwrap().md5, "abc");
+        assert_eq!(registry.select(Some("T2T"), None).unwrap().id(), "T2T/1.0");
+        assert_eq!(
+            registry.select(Some("HPRC"), None).unwrap().size,
+            Some(1024)
+        );
+        assert_eq!(
+            registry
+                .select(Some("HPRC"), Some("r3"))
+                .unwrap_err()
+                .to_string(),
+            "No version 'r3' of database 'HPRC' (available: r1, r2)"
+        );
+        assert!(matches!(
+            registry.select(Some("GRCh38"), None),
+            Err(RegistryError::UnknownDatabase { .. })
+        ));
+    }
+
+    #[test]
+    fn test_registry_parse_errors() {
+        assert!(matches!(Registry::parse(""), Err(RegistryError::Empty)));
+        assert!(matches!(
+            Registry::parse("database = 1"),
+            Err(RegistryError::ParseFailed(_))
+        ));
+        let bad_version =
+            "[[database]]\nname = \"HPRC\"\nversion = \"../r1\"\nurl = \"u\"\nmd5 = \"m\"\n";
+        assert!(matches!(
+            Registry::parse(bad_version),
+            Err(RegistryError::InvalidEntry(_))
+        ));
+        let duplicate = format!("{}{}", bad_version, bad_version).replace("../r1", "r1");
+        assert!(matches!(
+            Registry::parse(&duplicate),
+            Err(RegistryError::InvalidEntry(_))
+        ));
+    }
+
+    #[test]
+    fn test_bundled_registry() {
+        let registry = Registry::parse(BUNDLED_REGISTRY).unwrap();
+        assert!(registry.select(None, None).is_ok());
+    }
+
+    #[test]
+    fn test_store_find_and_resolve() {
+        let tmp = TempDir::new().unwrap();
+        let store = DatabaseStore::new(tmp.path());
+        assert!(matches!(
+            store.resolve(None, None),
+            Err(RegistryError::NotInstalled(_))
+        ));
+
+        let registry = Registry::parse(REGISTRY).unwrap();
+        for entry in &registry.databases[..2] {
+            install(&store, entry);
+        }
+        assert_eq!(store.installed().unwrap().len(), 2);
+        assert_eq!(store.find(None, None).unwrap().entry.version, "r2");
+        assert_eq!(
+            store.resolve(None, Some("r1")).unwrap(),
+            tmp.path().join("HPRC").join("r1")
+        );
+        assert_eq!(
+            crate::database_version(&store.resolve(None, None).unwrap()),
+            "HPRC/r2"
+        );
+
+        install(&store, &registry.databases[2]);
+        assert!(matches!(
+            store.find(None, None),
+            Err(RegistryError::Ambiguous { .. })
+        ));
+        assert_eq!(
+            store.find(Some("T2T"), None).unwrap().path,
+            tmp.path().join("T2T").join("1.0")
+        );
+        assert_eq!(
+            store.find(Some("GRCh38"), None).unwrap_err().to_string(),
+            "No database named 'GRCh38' (available: HPRC, T2T)"
+        );
+    }
+
+    #[test]
+    fn test_store_record_and_verify() {
+        let tmp = TempDir::new().unwrap();
+        let store = DatabaseStore::new(tmp.path());
+        let entry = Registry::parse(REGISTRY).unwrap().databases[0].clone();
+        let dir = store.install_dir(&entry).join("db");
+        fs::create_dir_all(&dir).unwrap();
+        write_database(
+            &dir,
+            &options_bytes(35, 31),
+            &hash_bytes(&[0; 8], 0, 22),
+            &human_taxonomy_bytes(),
+        );
+        store.record(&entry).unwrap();
+
+        let installed = store.get("HPRC", "r1").unwrap();
+        assert_eq!(installed.entry, entry);
+        assert!(installed.installed.is_some());
+        assert_eq!(
+            installed.files.keys().collect::<Vec<_>>(),
+            ["db/hash.k2d", "db/opts.k2d", "db/taxo.k2d"]
+        );
+        assert!(installed.verify().is_empty());
+        assert!(installed.disk_size().unwrap() > 0);
+
+        fs::write(dir.join("opts.k2d"), options_bytes(31, 15)).unwrap();
+        let problems = installed.verify();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("db/opts.k2d has MD5"));
+        fs::remove_file(dir.join("hash.k2d")).unwrap();
+        assert_eq!(installed.verify().len(), 3);
+    }
+
+    #[test]
+    fn test_store_default_and_remove() {
+        let tmp = TempDir::new().unwrap();
+        let store = DatabaseStore::new(tmp.path());
+        let registry = Registry::parse(REGISTRY).unwrap();
+        for entry in &registry.databases {
+            install(&store, entry);
+        }
+        assert!(matches!(
+            store.set_default("GRCh38", None),
+            Err(RegistryError::NotFound(_))
+        ));
+        assert!(matches!(
+            store.set_default("HPRC", Some("r3")),
+            Err(RegistryError::NotFound(_))
+        ));
+
+        store.set_default("HPRC", Some("r1")).unwrap();
+        assert_eq!(store.find(None, None).unwrap().entry.id(), "HPRC/r1");
+        assert_eq!(store.find(None, Some("r2")).unwrap().entry.id(), "HPRC/r2");
+        // A pinned default isn't old
+        assert!(store.old_versions("HPRC").unwrap().is_empty());
+        store.set_default("HPRC", None).unwrap();
+        assert_eq!(store.find(None, None).unwrap().entry.id(), "HPRC/r2");
+        assert_eq!(store.old_versions("HPRC").unwrap(), ["r1"]);
+
+        store.remove("HPRC", "r2").unwrap();
+        assert_eq!(store.find(None, None).unwrap().entry.id(), "HPRC/r1");
+        store.remove("HPRC", "r1").unwrap();
+        assert!(!tmp.path().join("HPRC").exists());
+        assert_eq!(store.default_database().unwrap(), None);
+        assert_eq!(store.find(None, None).unwrap().entry.id(), "T2T/1.0");
+        assert!(matches!(
+            store.remove("HPRC", "r1"),
+            Err(RegistryError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_store_skips_broken_metadata() {
+        let tmp = TempDir::new().unwrap();
+        let store = DatabaseStore::new(tmp.path());
+        let registry = Registry::parse(REGISTRY).unwrap();
+        for entry in &registry.databases {
+            install(&store, entry);
+        }
+        let path = tmp.path().join("HPRC").join("r1");
+        fs::write(path.join(METADATA_FILE), "name = \"HPRC\"\nversion = ").unwrap();
+
+        let installed: Vec<String> = store
+            .installed()
+            .unwrap()
+            .iter()
+            .map(|db| db.entry.id())
+            .collect();
+        assert_eq!(installed, ["HPRC/r2", "T2T/1.0"]);
+        let broken = store.broken().unwrap();
+        assert_eq!(broken.len(), 1);
+        assert_eq!(
+            (broken[0].id(), &broken[0].path),
+            ("HPRC/r1".to_string(), &path)
+        );
+        assert!(matches!(
+            broken[0].error,
+            RegistryError::InvalidMetadata { .. }
+        ));
+        let (installed, broken) = store.scan().unwrap();
+        assert_eq!((installed.len(), broken.len()), (2, 1));
+        assert_eq!(
+            store
+                .find_in(&installed, Some("HPRC"), None)
+                .unwrap()
+                .entry
+                .id(),
+            "HPRC/r2"
+        );
+
+        assert_eq!(store.remove("HPRC", "r1").unwrap(), path);
+        assert!(!path.exists());
+        assert!(store.broken().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(1_721_640_600);
+        assert_eq!(utc_timestamp(time), "2024-07-22T09:30:00Z");
+        let leap_day = UNIX_EPOCH + std::time::Duration::from_secs(951_825_599);
+        assert_eq!(utc_timestamp(leap_day), "2000-02-29T11:59:59Z");
+    }
+
+    #[test]
+    fn test_store_resolve_database_in_root() {
+        let tmp = TempDir::new().unwrap();
+        for file in ["hash.k2d", "opts.k2d", "taxo.k2d"] {
+            fs::write(tmp.path().join(file), "").unwrap();
+        }
+        let store = DatabaseStore::new(tmp.path());
+        assert_eq!(store.resolve(None, None).unwrap(), tmp.path());
+        assert!(matches!(
+            store.resolve(Some("HPRC"), None),
+            Err(RegistryError::NotInstalled(_))
+        ));
+    }
+}
@@ -0,0 +1,83 @@
+use crate::classify::per_read::{Hit, PerReadError, PerReadReader};
+use std::io::{BufReader, BufWriter, Read, Write};
+
+/// The header line of the removed reads table
+pub const REMOVED_IDS_HEADER: &str = "read_id\tmate\ttaxid\tlength\thuman_kmer_fraction";
+
+/// Write a tab-separated table of the reads classified as human, from the classifier's per-read
+/// output, and return the number of reads (or pairs) listed.
+///
+/// There is a row per mate (numbered 1 and 2; single-end reads are mate 1) with the taxon the read
+/// (or pair) was assigned to, the mate's length and the fraction of its k-mers that hit a human
+/// taxon, which is the evidence the classification was based on.
+pub fn write_removed_ids<R: Read, W: Write>(per_read: R, output: W) -> Result<usize, PerReadError> {
+    let mut writer = BufWriter::new(output);
+    writeln!(writer, "{}", REMOVED_IDS_HEADER)?;
+    let mut removed = 0;
+    for record in PerReadReader::new(BufReader::new(per_read)) {
+        let record = record?;
+        if !record.classified {
+            continue;
+        }
+        removed += 1;
+        for (mate, (length, hits)) in record.lengths.iter().zip(&record.hits).enumerate() {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}\t{:.4}",
+                record.read_id,
+                mate + 1,
+                record.taxid,
+                length,
+                human_kmer_fraction(hits)
+            )?;
+        }
+    }
+    writer.flush()?;
+    Ok(removed)
+}
+
+/// The fraction of a mate's k-mers that hit a taxon in the database, all of which are human.
+/// K-mers with ambiguous bases count as misses.
+pub fn human_kmer_fraction(hits: &[Hit]) -> f64 {
+    let total: usize = hits.iter().map(|hit| hit.kmers).sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let human: usize = hits
+        .iter()
+        .filter(|hit| hit.taxid.is_some_and(|taxid| taxid != 0))
+        .map(|hit| hit.kmers)
+        .sum();
+    human as f64 / total as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_removed_ids() {
+        let per_read = "U\tr1\t0\t150\t0:116\n\
+                        C\tr2\t9606\t150|148\t9606:30 0:80 A:10 |:| 9606:114\n\
+                        C\tr3\t9606\t4|4\t\n";
+        let mut output = Vec::new();
+        assert_eq!(
+            write_removed_ids(per_read.as_bytes(), &mut output).unwrap(),
+            2
+        );
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "read_id\tmate\ttaxid\tlength\thuman_kmer_fraction\n\
+             r2\t1\t9606\t150\t0.2500\n\
+             r2\t2\t9606\t148\t1.0000\n\
+             r3\t1\t9606\t4\t0.0000\n\
+             r3\t2\t9606\t4\t0.0000\n"
+        );
+    }
+
+    #[test]
+    fn test_write_removed_ids_invalid_input() {
+        let err = write_removed_ids("C\tr1\n".as_bytes(), Vec::new()).unwrap_err();
+        assert!(matches!(err, PerReadError::InvalidLine { line: 1, .. }));
+    }
+}
@@ -0,0 +1,401 @@
+use crate::db::Taxonomy;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ReportError {
+    #[error("Invalid line in the classification report (line {line}): {reason}")]
+    InvalidLine { line: usize, reason: String },
+
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+/// A taxonomic report of a classification, in the form of kraken2's `--report` output: the taxa
+/// reads were assigned to, in depth-first order, with the number of reads in each clade
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TaxonReport {
+    pub rows: Vec<ReportRow>,
+}
+
+/// A taxon in a [`TaxonReport`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportRow {
+    /// Reads (or pairs) assigned to the taxon or any taxon below it
+    pub clade_reads: u64,
+    /// Reads (or pairs) assigned to the taxon itself
+    pub direct_reads: u64,
+    /// Minimizers in the reads that map to the taxon (with `--report-minimizer-data`)
+    pub minimizers: Option<u64>,
+    /// Estimated number of distinct minimizers in the reads that map to the taxon (with
+    /// `--report-minimizer-data`)
+    pub distinct_minimizers: Option<u64>,
+    /// kraken2's rank code, e.g., "S" for species or "G1" for a clade between genus and species
+    pub rank: String,
+    pub taxid: u64,
+    pub name: String,
+    /// Depth in the report's tree, from 0 for the root (and for the unclassified reads)
+    pub depth: usize,
+}
+
+/// A Bracken-style summary of a [`TaxonReport`], as embedded in the run's stats
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportSummary {
+    pub unclassified_reads: u64,
+    pub classified_reads: u64,
+    /// Taxa with reads assigned directly to them, with the most reads first
+    pub taxa: Vec<TaxonSummary>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaxonSummary {
+    pub name: String,
+    pub taxonomy_id: u64,
+    pub taxonomy_lvl: String,
+    pub reads: u64,
+    pub fraction_total_reads: f64,
+}
+
+impl TaxonReport {
+    pub fn from_path(path: &Path) -> Result<Self, ReportError> {
+        Self::parse(BufReader::new(File::open(path)?))
+    }
+
+    /// Parse a report in kraken2's format, with or without minimizer data
+    pub fn parse<R: BufRead>(reader: R) -> Result<Self, ReportError> {
+        let mut rows = Vec::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let row = parse_row(&line).map_err(|reason| ReportError::InvalidLine {
+                line: i + 1,
+                reason,
+            })?;
+            rows.push(row);
+        }
+        Ok(Self { rows })
+    }
+
+    /// Build a report from the number of reads (or pairs) assigned to each taxon, by (NCBI)
+    /// taxonomy ID with 0 for unclassified reads, as kraken2 builds its report. Taxa without reads
+    /// in their clade are left out, and the children of each taxon are ordered by clade size.
+    pub fn from_taxonomy(taxonomy: &Taxonomy, counts: &HashMap<u64, u64>) -> Self {
+        let mut internal_ids = HashMap::new();
+        let mut children = vec![Vec::new(); taxonomy.node_count()];
+        for (id, node) in taxonomy.nodes.iter().enumerate().skip(1) {
+            internal_ids.insert(node.external_id, id);
+            if id > 1 {
+                children[node.parent_id as usize].push(id);
+            }
+        }
+        let mut direct = vec![0; taxonomy.node_count()];
+        for (taxid, &reads) in counts {
+            if let Some(&id) = internal_ids.get(taxid) {
+                direct[id] += reads;
+            }
+        }
+        // Parents have smaller IDs than their children, so one pass in reverse sums the clades
+        let mut clade = direct.clone();
+        for id in (2..taxonomy.node_count()).rev() {
+            clade[taxonomy.nodes[id].parent_id as usize] += clade[id];
+        }
+
+        let mut rows = Vec::new();
+        let unclassified = counts.get(&0).copied().unwrap_or(0);
+        if unclassified > 0 {
+            rows.push(ReportRow {
+                clade_reads: unclassified,
+                direct_reads: unclassified,
+                minimizers: None,
+                distinct_minimizers: None,
+                rank: "U".to_string(),
+                taxid: 0,
+                name: "unclassified".to_string(),
+                depth: 0,
+            });
+        }
+        if taxonomy.node_count() < 2 || clade[1] == 0 {
+            return Self { rows };
+        }
+
+        // Depth-first from the root, keeping the rank code and how far below a ranked taxon we are
+        let mut stack = vec![(1, 0, "R", 0)];
+        while let Some((id, depth, code, offset)) = stack.pop() {
+            let rank = match offset {
+                0 => code.to_string(),
+                _ => format!("{}{}", code, offset),
+            };
+            rows.push(ReportRow {
+                clade_reads: clade[id],
+                direct_reads: direct[id],
+                minimizers: None,
+                distinct_minimizers: None,
+                rank,
+                taxid: taxonomy.external_id(id as u64),
+                name: taxonomy.name(id as u64).unwrap_or_default().to_string(),
+                depth,
+            });
+            let mut kids: Vec<usize> = children[id]
+                .iter()
+                .copied()
+                .filter(|&kid| clade[kid] > 0)
+                .collect();
+            kids.sort_by_key(|&kid| {
+                (
+                    std::cmp::Reverse(clade[kid]),
+                    taxonomy.external_id(kid as u64),
+                )
+            });
+            // Pushed in reverse so the largest clade is reported first
+            for kid in kids.into_iter().rev() {
+                let (kid_code, kid_offset) = match taxonomy.rank(kid as u64).and_then(rank_code) {
+                    Some(kid_code) => (kid_code, 0),
+                    None => (code, offset + 1),
+                };
+                stack.push((kid, depth + 1, kid_code, kid_offset));
+            }
+        }
+        Self { rows }
+    }
+
+    /// Reads (or pairs) not assigned to any taxon
+    pub fn unclassified_reads(&self) -> u64 {
+        self.rows
+            .iter()
+            .filter(|row| row.taxid == 0 && row.depth == 0)
+            .map(|row| row.clade_reads)
+            .sum()
+    }
+
+    /// Reads (or pairs) assigned to a taxon
+    pub fn classified_reads(&self) -> u64 {
+        self.rows
+            .iter()
+            .filter(|row| row.taxid != 0 && row.depth == 0)
+            .map(|row| row.clade_reads)
+            .sum()
+    }
+
+    pub fn total_reads(&self) -> u64 {
+        self.unclassified_reads() + self.classified_reads()
+    }
+
+    /// Whether the report has kraken2's minimizer columns
+    pub fn has_minimizer_data(&self) -> bool {
+        !self.rows.is_empty() && self.rows.iter().all(|row| row.minimizers.is_some())
+    }
+
+    /// Write the report in kraken2's format
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let total = self.total_reads();
+        for row in &self.rows {
+            let percentage = match total {
+                0 => 0.0,
+                _ => 100.0 * row.clade_reads as f64 / total as f64,
+            };
+            write!(
+                writer,
+                "{:6.2}\t{}\t{}\t",
+                percentage, row.clade_reads, row.direct_reads
+            )?;
+            if let (Some(minimizers), Some(distinct)) = (row.minimizers, row.distinct_minimizers) {
+                write!(writer, "{}\t{}\t", minimizers, distinct)?;
+            }
+            writeln!(
+                writer,
+                "{}\t{}\t{}{}",
+                row.rank,
+                row.taxid,
+                "  ".repeat(row.depth),
+                row.name
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The taxa reads were assigned to directly, with the fraction of all reads assigned to each
+    pub fn summary(&self) -> ReportSummary {
+        let total = self.total_reads().max(1) as f64;
+        let mut taxa: Vec<TaxonSummary> = self
+            .rows
+            .iter()
+            .filter(|row| row.taxid != 0 && row.direct_reads > 0)
+            .map(|row| TaxonSummary {
+                name: row.name.clone(),
+                taxonomy_id: row.taxid,
+                taxonomy_lvl: row.rank.clone(),
+                reads: row.direct_reads,
+                fraction_total_reads: row.direct_reads as f64 / total,
+            })
+            .collect();
+        taxa.sort_by_key(|taxon| std::cmp::Reverse(taxon.reads));
+        ReportSummary {
+            unclassified_reads: self.unclassified_reads(),
+            classified_reads: self.classified_reads(),
+            taxa,
+        }
+    }
+}
+
+/// kraken2's code for a taxonomic rank, if it has one
+fn rank_code(rank: &str) -> Option<&'static str> {
+    match rank {
+        "superkingdom" | "domain" => Some("D"),
+        "kingdom" => Some("K"),
+        "phylum" => Some("P"),
+        "class" => Some("C"),
+        "order" => Some("O"),
+        "family" => Some("F"),
+        "genus" => Some("G"),
+        "species" => Some("S"),
+        _ => None,
+    }
+}
+
+/// Parse a line of the form `<%> <clade> <direct> [<minimizers> <distinct>] <rank> <taxid> <name>`,
+/// separated by tabs, where the name is indented by two spaces per level
+fn parse_row(line: &str) -> Result<ReportRow, String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let (counts, rank, taxid, name) = match fields.as_slice() {
+        [_, clade, direct, rank, taxid, name] => (vec![*clade, *direct], rank, taxid, name),
+        [_, clade, direct, minimizers, distinct, rank, taxid, name] => (
+            vec![*clade, *direct, *minimizers, *distinct],
+            rank,
+            taxid,
+            name,
+        ),
+        _ => {
+            return Err(format!(
+                "expected 6 or 8 tab-separated fields, got {}",
+                fields.len()
+            ))
+        }
+    };
+    let counts = counts
+        .iter()
+        .map(|count| {
+            count
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid count '{}'", count))
+        })
+        .collect::<Result<Vec<u64>, _>>()?;
+    let taxid = taxid
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid taxonomy ID '{}'", taxid))?;
+    let indent = name.len() - name.trim_start_matches(' ').len();
+    Ok(ReportRow {
+        clade_reads: counts[0],
+        direct_reads: counts[1],
+        minimizers: counts.get(2).copied(),
+        distinct_minimizers: counts.get(3).copied(),
+        rank: rank.trim().to_string(),
+        taxid,
+        name: name.trim().to_string(),
+        depth: indent / 2,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_utils::taxonomy_bytes;
+
+    const REPORT: &str = " 25.00\t1\t1\tU\t0\tunclassified
+ 75.00\t3\t0\tR\t1\troot
+ 75.00\t3\t1\tG\t9605\t  Homo
+ 50.00\t2\t2\tS\t9606\t    Homo sapiens
+";
+
+    #[test]
+    fn test_parse_and_write_round_trip() {
+        let report = TaxonReport::parse(REPORT.as_bytes()).unwrap();
+        assert_eq!(report.rows.len(), 4);
+        assert_eq!(report.rows[3].name, "Homo sapiens");
+        assert_eq!((report.rows[3].depth, report.rows[3].taxid), (2, 9606));
+        assert_eq!(
+            (report.unclassified_reads(), report.classified_reads()),
+            (1, 3)
+        );
+        assert!(!report.has_minimizer_data());
+
+        let mut written = Vec::new();
+        report.write(&mut written).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), REPORT);
+    }
+
+    #[test]
+    fn test_parse_minimizer_data() {
+        let report =
+            TaxonReport::parse("100.00\t5\t5\t120\t80\tS\t9606\t    Homo sapiens\n".as_bytes())
+                .unwrap();
+        assert!(report.has_minimizer_data());
+        assert_eq!(
+            (
+                report.rows[0].minimizers,
+                report.rows[0].distinct_minimizers
+            ),
+            (Some(120), Some(80))
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_line() {
+        let err =
+            TaxonReport::parse(format!("{}100.00\t5\tS\t9606\tHomo sapiens\n", REPORT).as_bytes())
+                .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid line in the classification report (line 5): expected 6 or 8 tab-separated fields, got 5"
+        );
+    }
+
+    #[test]
+    fn test_from_taxonomy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("taxo.k2d");
+        std::fs::write(
+            &path,
+            taxonomy_bytes(&[
+                (0, 1, "root", "no rank"),
+                (1, 9605, "Homo", "genus"),
+                (1, 1234, "Other", "genus"),
+                (2, 9606, "Homo sapiens", "species"),
+                (4, 63221, "Homo sapiens neanderthalensis", "subspecies"),
+            ]),
+        )
+        .unwrap();
+        let taxonomy = Taxonomy::from_path(&path).unwrap();
+        let counts = HashMap::from([(0, 1), (9605, 1), (9606, 2), (63221, 1)]);
+
+        let report = TaxonReport::from_taxonomy(&taxonomy, &counts);
+        let mut written = Vec::new();
+        report.write(&mut written).unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            " 20.00\t1\t1\tU\t0\tunclassified
+ 80.00\t4\t0\tR\t1\troot
+ 80.00\t4\t1\tG\t9605\t  Homo
+ 60.00\t3\t2\tS\t9606\t    Homo sapiens
+ 20.00\t1\t1\tS1\t63221\t      Homo sapiens neanderthalensis
+"
+        );
+
+        let summary = report.summary();
+        assert_eq!(summary.classified_reads, 4);
+        let taxa: Vec<(u64, u64)> = summary
+            .taxa
+            .iter()
+            .map(|taxon| (taxon.taxonomy_id, taxon.reads))
+            .collect();
+        assert_eq!(taxa, vec![(9606, 2), (9605, 1), (63221, 1)]);
+        assert_eq!(summary.taxa[0].fraction_total_reads, 0.4);
+    }
+}
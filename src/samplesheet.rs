@@ -0,0 +1,456 @@
+use crate::{Pipeline, RunReport};
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Name of the table summarising every sample in a batch, written to the output directory
+pub const SUMMARY_FILE: &str = "nohuman_summary.tsv";
+
+#[derive(Error, Debug)]
+pub enum SampleSheetError {
+    #[error("Sample sheet has no samples")]
+    Empty,
+
+    #[error("Sample '{0}' appears more than once in the sample sheet")]
+    DuplicateSample(String),
+
+    #[error("Invalid sample id '{0}': ids must be non-empty and cannot contain path separators")]
+    InvalidSampleId(String),
+
+    #[error(transparent)]
+    CsvError(#[from] csv::Error),
+}
+
+/// A row of a sample sheet.
+///
+/// The sheet has a header with the columns `sample` and `r1`, and optionally `r2` (for paired
+/// reads), `out1` and `out2` (to name the non-human outputs). Relative paths are relative to the
+/// directory holding the sheet.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Sample {
+    pub sample: String,
+    pub r1: PathBuf,
+    #[serde(default)]
+    pub r2: Option<PathBuf>,
+    #[serde(default)]
+    pub out1: Option<PathBuf>,
+    #[serde(default)]
+    pub out2: Option<PathBuf>,
+}
+
+impl Sample {
+    pub fn inputs(&self) -> Vec<PathBuf> {
+        std::iter::once(self.r1.clone())
+            .chain(self.r2.clone())
+            .collect()
+    }
+
+    /// Make the sample's relative paths relative to `dir` instead
+    fn relative_to(mut self, dir: &Path) -> Self {
+        for path in [
+            Some(&mut self.r1),
+            self.r2.as_mut(),
+            self.out1.as_mut(),
+            self.out2.as_mut(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = dir.join(&*path);
+        }
+        self
+    }
+}
+
+/// Read a sample sheet, which is tab-separated if its extension is `.tsv` or `.txt` and
+/// comma-separated otherwise. The samples' relative paths are resolved against the sheet's
+/// directory.
+pub fn read_samplesheet(path: &Path) -> Result<Vec<Sample>, SampleSheetError> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let delimiter = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("tsv") | Some("txt") => b'\t',
+        _ => b',',
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .from_path(path)?;
+
+    let mut samples = Vec::new();
+    let mut seen = HashSet::new();
+    for row in reader.deserialize() {
+        let sample: Sample = row?;
+        if sample.sample.is_empty() || sample.sample.contains(['/', '\\']) {
+            return Err(SampleSheetError::InvalidSampleId(sample.sample));
+        }
+        if !seen.insert(sample.sample.clone()) {
+            return Err(SampleSheetError::DuplicateSample(sample.sample));
+        }
+        samples.push(sample.relative_to(dir));
+    }
+    if samples.is_empty() {
+        return Err(SampleSheetError::Empty);
+    }
+    Ok(samples)
+}
+
+/// The outcome of running one sample of a batch
+pub struct SampleResult {
+    pub sample: String,
+    pub report: Result<RunReport>,
+}
+
+/// Run every sample in a sample sheet with the settings of `template`, loading the database once.
+///
+/// Outputs that aren't named in the sheet, each sample's stats (`<sample>.stats.json`) and its
+/// MultiQC file (`<sample>_mqc.json`) are written to `outdir`. All samples are checked before any
+/// are run; after that, a failed sample doesn't stop the rest of the batch.
+pub fn run_batch(
+    template: &Pipeline,
+    samples: &[Sample],
+    outdir: &Path,
+) -> Result<Vec<SampleResult>> {
+    std::fs::create_dir_all(outdir)
+        .with_context(|| format!("Failed to create output directory {}", outdir.display()))?;
+
+    let mut pipelines = Vec::with_capacity(samples.len());
+    let mut outputs = HashSet::new();
+    for sample in samples {
+        let (pipeline, sample_outputs) = sample_pipeline(template, sample, outdir)?;
+        for output in sample_outputs {
+            if !outputs.insert(output.clone()) {
+                bail!(
+                    "Output file {} of sample {} is also written by another sample. Please name the outputs in the sample sheet.",
+                    output.display(),
+                    sample.sample
+                );
+            }
+        }
+        pipelines.push(pipeline);
+    }
+
+    // Load the database once and share it between the samples
+    let classifier = template.clone().memory_mapping(true).load_classifier()?;
+
+    let mut results = Vec::with_capacity(samples.len());
+    for (i, (sample, pipeline)) in samples.iter().zip(pipelines).enumerate() {
+        info!("Sample {} ({}/{})", sample.sample, i + 1, samples.len());
+        let report = pipeline.classifier(classifier.clone()).run();
+        if let Err(e) = &report {
+            error!("Sample {} failed: {:#}", sample.sample, e);
+        }
+        results.push(SampleResult {
+            sample: sample.sample.clone(),
+            report,
+        });
+    }
+    Ok(results)
+}
+
+/// The pipeline for one sample, with every output path resolved, and the paths it writes to
+fn sample_pipeline(
+    template: &Pipeline,
+    sample: &Sample,
+    outdir: &Path,
+) -> Result<(Pipeline, Vec<PathBuf>)> {
+    let inputs = sample.inputs();
+    for input in &inputs {
+        if !input.exists() {
+            bail!(
+                "Input file {} for sample {} does not exist",
+                input.display(),
+                sample.sample
+            );
+        }
+    }
+    let default_output = |input: &Path, tag: &str| {
+        outdir.join(
+            template
+                .default_output(input, tag)
+                .file_name()
+                .unwrap_or_default(),
+        )
+    };
+
+    let stats = outdir.join(format!("{}.stats.json", sample.sample));
+    let multiqc = outdir.join(format!("{}_mqc.json", sample.sample));
+    let mut outputs = vec![stats.clone(), multiqc.clone()];
+    let mut pipeline = template
+        .clone()
+        .inputs(inputs.clone())
+        .sample_name(&sample.sample)
+        .stats(stats)
+        .multiqc(multiqc);
+    if template.keeps().nonhuman() {
+        let out1 = sample
+            .out1
+            .clone()
+            .unwrap_or_else(|| default_output(&inputs[0], "nohuman"));
+        outputs.push(out1.clone());
+        pipeline = pipeline.out1(out1);
+        if let Some(r2) = &sample.r2 {
+            let out2 = sample
+                .out2
+                .clone()
+                .unwrap_or_else(|| default_output(r2, "nohuman"));
+            outputs.push(out2.clone());
+            pipeline = pipeline.out2(out2);
+        }
+    }
+    if template.keeps().human() {
+        let human_out1 = default_output(&inputs[0], "human");
+        outputs.push(human_out1.clone());
+        pipeline = pipeline.human_out1(human_out1);
+        if let Some(r2) = &sample.r2 {
+            let human_out2 = default_output(r2, "human");
+            outputs.push(human_out2.clone());
+            pipeline = pipeline.human_out2(human_out2);
+        }
+    }
+    pipeline
+        .validate()
+        .with_context(|| format!("Invalid settings for sample {}", sample.sample))?;
+    Ok((pipeline, outputs))
+}
+
+/// Write a tab-separated table with a row of counts (or the error) for each sample
+pub fn write_summary(path: &Path, results: &[SampleResult]) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(path)
+        .with_context(|| format!("Failed to create summary file {}", path.display()))?;
+    writer.write_record([
+        "sample",
+        "status",
+        "total_sequences",
+        "sequences_removed",
+        "sequences_remaining",
+        "proportion_removed",
+        "output1",
+        "output2",
+        "error",
+    ])?;
+    for result in results {
+        match &result.report {
+            Ok(report) => {
+                let stats = &report.stats;
+                writer.write_record([
+                    result.sample.as_str(),
+                    "ok",
+                    &stats.total_sequences.to_string(),
+                    &stats.sequences_removed.to_string(),
+                    &stats.sequences_remaining.to_string(),
+                    &format!("{:.6}", stats.proportion_removed),
+                    &stats.output1,
+                    &stats.output2,
+                    "",
+                ])?;
+            }
+            Err(e) => {
+                writer.write_record([
+                    result.sample.as_str(),
+                    "failed",
+                    "",
+                    "",
+                    "",
+                    "",
+                    "",
+                    "",
+                    &format!("{:#}", e),
+                ])?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classify::mock::MockClassifier;
+    use crate::Keep;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_read_samplesheet_csv() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("samples.csv");
+        std::fs::write(
+            &path,
+            "sample,r1,r2,out1,out2\ns1, a_1.fq.gz ,a_2.fq.gz,,\n# a comment\ns2,b.fq,,/data/clean.fq,\n",
+        )
+        .unwrap();
+        let samples = read_samplesheet(&path).unwrap();
+        assert_eq!(
+            samples,
+            vec![
+                Sample {
+                    sample: "s1".to_string(),
+                    r1: tmp.path().join("a_1.fq.gz"),
+                    r2: Some(tmp.path().join("a_2.fq.gz")),
+                    out1: None,
+                    out2: None,
+                },
+                Sample {
+                    sample: "s2".to_string(),
+                    r1: tmp.path().join("b.fq"),
+                    r2: None,
+                    out1: Some(PathBuf::from("/data/clean.fq")),
+                    out2: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_samplesheet_tsv_optional_columns() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("samples.tsv");
+        std::fs::write(&path, "sample\tr1\ns1\ta.fq\n").unwrap();
+        let samples = read_samplesheet(&path).unwrap();
+        assert_eq!(samples[0].inputs(), vec![tmp.path().join("a.fq")]);
+    }
+
+    #[test]
+    fn test_read_samplesheet_errors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("samples.csv");
+
+        std::fs::write(&path, "sample,r1\ns1,a.fq\ns1,b.fq\n").unwrap();
+        assert!(
+            matches!(read_samplesheet(&path), Err(SampleSheetError::DuplicateSample(s)) if s == "s1")
+        );
+
+        std::fs::write(&path, "sample,r1\n../s1,a.fq\n").unwrap();
+        assert!(matches!(
+            read_samplesheet(&path),
+            Err(SampleSheetError::InvalidSampleId(_))
+        ));
+
+        std::fs::write(&path, "sample,r1\n").unwrap();
+        assert!(matches!(
+            read_samplesheet(&path),
+            Err(SampleSheetError::Empty)
+        ));
+
+        std::fs::write(&path, "sample,r2\ns1,a.fq\n").unwrap();
+        assert!(matches!(
+            read_samplesheet(&path),
+            Err(SampleSheetError::CsvError(_))
+        ));
+    }
+
+    #[test]
+    fn test_run_batch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let reads = |name: &str, ids: &[&str]| {
+            let path = tmp.path().join(name);
+            let contents: String = ids
+                .iter()
+                .map(|id| format!("@{}\nACGT\n+\nIIII\n", id))
+                .collect();
+            std::fs::write(&path, contents).unwrap();
+            path
+        };
+        let samples = vec![
+            Sample {
+                sample: "paired".to_string(),
+                r1: reads("p_1.fq", &["h1", "m1"]),
+                r2: Some(reads("p_2.fq", &["h1", "m1"])),
+                out1: None,
+                out2: None,
+            },
+            Sample {
+                sample: "single".to_string(),
+                r1: reads("s.fq", &["h2", "m2", "m3"]),
+                r2: None,
+                out1: Some(tmp.path().join("clean.fq")),
+                out2: None,
+            },
+        ];
+        let outdir = tmp.path().join("out");
+        let template = Pipeline::new(Vec::new())
+            .classifier(Arc::new(MockClassifier::new(["h1", "h2"])))
+            .keep(Keep::Both);
+
+        let results = run_batch(&template, &samples, &outdir).unwrap();
+        let reports: Vec<&RunReport> = results.iter().map(|r| r.report.as_ref().unwrap()).collect();
+        assert_eq!(
+            reports[0].nonhuman_outputs,
+            vec![outdir.join("p_1.nohuman.fq"), outdir.join("p_2.nohuman.fq")]
+        );
+        assert_eq!(reports[0].human_outputs[1], outdir.join("p_2.human.fq"));
+        assert_eq!(reports[0].stats.sample, "paired");
+        assert_eq!(
+            reports[1].nonhuman_outputs,
+            vec![tmp.path().join("clean.fq")]
+        );
+        assert_eq!(
+            std::fs::read_to_string(tmp.path().join("clean.fq"))
+                .unwrap()
+                .lines()
+                .count(),
+            8
+        );
+        assert!(outdir.join("single.stats.json").exists());
+        assert!(outdir.join("single_mqc.json").exists());
+
+        let summary = outdir.join(SUMMARY_FILE);
+        write_summary(&summary, &results).unwrap();
+        let summary = std::fs::read_to_string(summary).unwrap();
+        let lines: Vec<&str> = summary.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].starts_with("single\tok\t3\t1\t2\t0.333333\t"));
+    }
+
+    #[test]
+    fn test_run_batch_checks_samples_first() {
+        let tmp = tempfile::tempdir().unwrap();
+        let samples = vec![Sample {
+            sample: "missing".to_string(),
+            r1: tmp.path().join("missing.fq"),
+            r2: None,
+            out1: None,
+            out2: None,
+        }];
+        let template = Pipeline::new(Vec::new()).classifier(Arc::new(MockClassifier::new(["h1"])));
+        let err = run_batch(&template, &samples, tmp.path()).err().unwrap();
+        assert!(err.to_string().contains("does not exist"));
+    }
+
+    #[test]
+    fn test_run_batch_output_collision() {
+        let tmp = tempfile::tempdir().unwrap();
+        for dir in ["a", "b"] {
+            std::fs::create_dir(tmp.path().join(dir)).unwrap();
+            std::fs::write(
+                tmp.path().join(dir).join("reads.fq"),
+                "@r1\nACGT\n+\nIIII\n",
+            )
+            .unwrap();
+        }
+        let sample = |name: &str| Sample {
+            sample: name.to_string(),
+            r1: tmp.path().join(name).join("reads.fq"),
+            r2: None,
+            out1: None,
+            out2: None,
+        };
+        let template = Pipeline::new(Vec::new()).classifier(Arc::new(MockClassifier::new(["r1"])));
+        let err = run_batch(
+            &template,
+            &[sample("a"), sample("b")],
+            &tmp.path().join("out"),
+        )
+        .err()
+        .unwrap();
+        assert!(err
+            .to_string()
+            .contains("is also written by another sample"));
+    }
+}
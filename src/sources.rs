@@ -0,0 +1,306 @@
+use crate::registry::DatabaseEntry;
+use reqwest::Url;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Where the registry of databases is downloaded from, unless overridden
+pub const DEFAULT_REGISTRY_URL: &str =
+    "https://raw.githubusercontent.com/mbhall88/nohuman/main/config.toml";
+
+/// The environment variable naming the user config file, instead of [`user_config_path`]'s default
+pub const CONFIG_ENV: &str = "NOHUMAN_CONFIG";
+
+/// The environment variable listing the URLs of the registry, separated by whitespace
+pub const REGISTRY_URL_ENV: &str = "NOHUMAN_REGISTRY_URL";
+
+/// The environment variable listing the URLs of the database tarball, separated by whitespace
+pub const DB_URL_ENV: &str = "NOHUMAN_DB_URL";
+
+#[derive(Error, Debug)]
+pub enum SourcesError {
+    #[error("Failed to read the config file {path:?}: {source}")]
+    ReadFailed { path: PathBuf, source: io::Error },
+
+    #[error("Failed to parse the config file {path:?}: {source}")]
+    ParseFailed {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("Invalid URL '{url}' in {origin}: {reason}")]
+    InvalidUrl {
+        url: String,
+        origin: String,
+        reason: String,
+    },
+}
+
+/// The settings of the user config file
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub registry_urls: Option<Vec<String>>,
+    pub db_urls: Option<Vec<String>>,
+}
+
+impl UserConfig {
+    /// Read the config file at `path`, or the default settings if there is no file there
+    pub fn read(path: &Path) -> Result<Self, SourcesError> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(source) => {
+                return Err(SourcesError::ReadFailed {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+        };
+        toml::from_str(&content).map_err(|source| SourcesError::ParseFailed {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+/// The user config file: the file named by `$NOHUMAN_CONFIG`, or `nohuman/config.toml` in the
+/// user's config directory (e.g., `~/.config` on Linux)
+pub fn user_config_path() -> Option<PathBuf> {
+    match std::env::var_os(CONFIG_ENV) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => dirs::config_dir().map(|dir| dir.join("nohuman").join("config.toml")),
+    }
+}
+
+/// Where databases are downloaded from. Each list of URLs is a set of mirrors, tried in order
+/// until one succeeds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sources {
+    /// The URLs of the registry of databases. A `file://` URL is a local copy of the registry.
+    pub registry_urls: Vec<String>,
+    /// The URLs of the database tarball, in place of the one in the registry. A URL ending in `/`
+    /// is a mirror of a directory, to which the name of the registry's tarball is added.
+    pub db_urls: Vec<String>,
+}
+
+impl Default for Sources {
+    fn default() -> Self {
+        Self {
+            registry_urls: vec![DEFAULT_REGISTRY_URL.to_string()],
+            db_urls: Vec::new(),
+        }
+    }
+}
+
+impl Sources {
+    /// The sources given on the command line, or else in the environment, or else in the user
+    /// config file, or else the defaults. Each list is taken whole from the first place that sets
+    /// it.
+    pub fn load(registry_urls: Vec<String>, db_urls: Vec<String>) -> Result<Self, SourcesError> {
+        let config = match user_config_path() {
+            Some(path) => Some((UserConfig::read(&path)?, path)),
+            None => None,
+        };
+        Self::layered(
+            registry_urls,
+            db_urls,
+            |name| std::env::var(name).ok(),
+            config,
+        )
+    }
+
+    fn layered(
+        registry_urls: Vec<String>,
+        db_urls: Vec<String>,
+        env: impl Fn(&str) -> Option<String>,
+        config: Option<(UserConfig, PathBuf)>,
+    ) -> Result<Self, SourcesError> {
+        let (config, config_path) = config.unwrap_or_default();
+        let config_origin = format!("{:?}", config_path);
+        let pick = |cli: Vec<String>, variable: &str, configured: Option<Vec<String>>| {
+            if !cli.is_empty() {
+                return Some((cli, "the command line".to_string()));
+            }
+            if let Some(value) = env(variable).filter(|value| !value.trim().is_empty()) {
+                let urls = value.split_whitespace().map(String::from).collect();
+                return Some((urls, format!("${}", variable)));
+            }
+            configured.map(|urls| (urls, config_origin.clone()))
+        };
+
+        let mut sources = Self::default();
+        if let Some((urls, origin)) = pick(registry_urls, REGISTRY_URL_ENV, config.registry_urls) {
+            check_urls(&urls, &origin, &["http", "https", "file"])?;
+            if !urls.is_empty() {
+                sources.registry_urls = urls;
+            }
+        }
+        if let Some((urls, origin)) = pick(db_urls, DB_URL_ENV, config.db_urls) {
+            check_urls(&urls, &origin, &["http", "https"])?;
+            sources.db_urls = urls;
+        }
+        Ok(sources)
+    }
+
+    /// The URLs to download `entry`'s tarball from, in the order to try them
+    pub fn db_urls_for(&self, entry: &DatabaseEntry) -> Vec<String> {
+        if self.db_urls.is_empty() {
+            return vec![entry.url.clone()];
+        }
+        let file_name = entry.url.rsplit('/').next().unwrap_or_default();
+        self.db_urls
+            .iter()
+            .map(|url| match url.ends_with('/') {
+                true => format!("{}{}", url, file_name),
+                false => url.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Check that every URL is a URL with one of `schemes`
+fn check_urls(urls: &[String], origin: &str, schemes: &[&str]) -> Result<(), SourcesError> {
+    for url in urls {
+        let reason = match Url::parse(url) {
+            Ok(parsed) if schemes.contains(&parsed.scheme()) => continue,
+            Ok(parsed) => format!("unsupported scheme '{}'", parsed.scheme()),
+            Err(e) => e.to_string(),
+        };
+        return Err(SourcesError::InvalidUrl {
+            url: url.clone(),
+            origin: origin.to_string(),
+            reason,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+
+    fn urls(urls: &[&str]) -> Vec<String> {
+        urls.iter().map(|url| url.to_string()).collect()
+    }
+
+    #[test]
+    fn test_layered_sources() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("config.toml");
+        fs::write(
+            &path,
+            "registry_urls = [\"https://a.example/config.toml\", \"https://b.example/config.toml\"]\n\
+             db_urls = [\"https://a.example/dbs/\"]\n",
+        )
+        .unwrap();
+        let config = || Some((UserConfig::read(&path).unwrap(), path.clone()));
+        let no_env = |_: &str| None;
+
+        let sources = Sources::layered(vec![], vec![], no_env, None).unwrap();
+        assert_eq!(sources, Sources::default());
+
+        let sources = Sources::layered(vec![], vec![], no_env, config()).unwrap();
+        assert_eq!(
+            sources.registry_urls,
+            urls(&[
+                "https://a.example/config.toml",
+                "https://b.example/config.toml"
+            ])
+        );
+        assert_eq!(sources.db_urls, urls(&["https://a.example/dbs/"]));
+
+        let env: HashMap<&str, &str> = HashMap::from([(
+            DB_URL_ENV,
+            " https://c.example/db.tar.gz\thttp://d.example/ ",
+        )]);
+        let sources = Sources::layered(
+            vec![],
+            vec![],
+            |name| env.get(name).map(|v| v.to_string()),
+            config(),
+        )
+        .unwrap();
+        assert_eq!(sources.registry_urls.len(), 2);
+        assert_eq!(
+            sources.db_urls,
+            urls(&["https://c.example/db.tar.gz", "http://d.example/"])
+        );
+
+        let cli = urls(&["https://e.example/config.toml"]);
+        let sources = Sources::layered(
+            cli.clone(),
+            vec![],
+            |name| env.get(name).map(|v| v.to_string()),
+            config(),
+        )
+        .unwrap();
+        assert_eq!(sources.registry_urls, cli);
+        assert_eq!(sources.db_urls.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_sources() {
+        let err = Sources::layered(
+            urls(&["ftp://a.example/config.toml"]),
+            vec![],
+            |_| None,
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(err, SourcesError::InvalidUrl { .. }));
+        assert!(err.to_string().contains("the command line"));
+        let env = |_: &str| Some("not-a-url".to_string());
+        assert!(Sources::layered(vec![], vec![], env, None).is_err());
+
+        // Only the registry can be a local file
+        let local = urls(&["file:///srv/nohuman/config.toml"]);
+        let sources = Sources::layered(local.clone(), vec![], |_| None, None).unwrap();
+        assert_eq!(sources.registry_urls, local);
+        assert!(Sources::layered(vec![], local, |_| None, None).is_err());
+
+        let tmp = TempDir::new().unwrap();
+        assert_eq!(
+            UserConfig::read(&tmp.path().join("missing.toml")).unwrap(),
+            UserConfig::default()
+        );
+        let path = tmp.path().join("config.toml");
+        fs::write(&path, "registry_url = \"https://a.example/config.toml\"\n").unwrap();
+        assert!(matches!(
+            UserConfig::read(&path),
+            Err(SourcesError::ParseFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_db_urls_for() {
+        let entry = DatabaseEntry {
+            name: "HPRC".to_string(),
+            version: "r1".to_string(),
+            url: "https://zenodo.org/records/1/files/k2_HPRC.tar.gz".to_string(),
+            md5: "foo".to_string(),
+            size: None,
+            build_date: None,
+            description: None,
+        };
+        assert_eq!(Sources::default().db_urls_for(&entry), urls(&[&entry.url]));
+        let sources = Sources {
+            db_urls: urls(&[
+                "https://mirror.example/nohuman/",
+                "https://other.example/hprc.tar.gz",
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            sources.db_urls_for(&entry),
+            urls(&[
+                "https://mirror.example/nohuman/k2_HPRC.tar.gz",
+                "https://other.example/hprc.tar.gz"
+            ])
+        );
+    }
+}